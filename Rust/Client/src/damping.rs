@@ -0,0 +1,121 @@
+//
+// Smorzamento dei flap e quarantena dei path
+//
+// Un modem che si disconnette e riconnette ogni pochi secondi farebbe creare e
+// rimuovere di continuo le routine. Con healthPolicy:
+//
+//   minUpTime       un path viene creato solo dopo che la sua interfaccia ha
+//                   un indirizzo da almeno minUpTime millisecondi
+//   removeDelay     un path viene rimosso solo se la sua interfaccia (o il suo
+//                   indirizzo) manca da almeno removeDelay millisecondi
+//   quarantineGap   un path che non riceve nulla da quarantineGap millisecondi,
+//   quarantineLoss  o che perde più di quarantineLoss% dei probe, va in
+//                   quarantena: resta aperto ma viene usato solo se nessun
+//                   altro path è utilizzabile
+//   quarantineTime  la quarantena finisce quarantineTime millisecondi dopo
+//                   l'ultimo controllo andato male
+//
+// Ogni stato è indicizzato con la chiave del path (path_key).
+//
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct HealthPolicy {
+    #[serde(rename = "minUpTime")]
+    pub min_up_time: Option<u64>,
+    #[serde(rename = "removeDelay")]
+    pub remove_delay: Option<u64>,
+    #[serde(rename = "quarantineGap")]
+    pub quarantine_gap: Option<u64>,
+    #[serde(rename = "quarantineLoss")]
+    pub quarantine_loss: Option<f64>,
+    #[serde(rename = "quarantineTime")]
+    pub quarantine_time: Option<u64>,
+}
+
+// Quarantena di default se quarantineTime non è impostato
+const DEFAULT_QUARANTINE_TIME: u64 = 10000;
+
+impl HealthPolicy {
+    pub fn min_up_time(&self) -> Duration {
+        Duration::from_millis(self.min_up_time.unwrap_or(0))
+    }
+
+    pub fn remove_delay(&self) -> Duration {
+        Duration::from_millis(self.remove_delay.unwrap_or(0))
+    }
+
+    pub fn quarantine_time(&self) -> Duration {
+        Duration::from_millis(self.quarantine_time.unwrap_or(DEFAULT_QUARANTINE_TIME))
+    }
+
+    // Il motivo per cui il path andrebbe in quarantena, se c'è
+    pub fn quarantine_reason(&self, gap: Duration, probe_loss: Option<f64>) -> Option<String> {
+        if let Some(max_gap) = self.quarantine_gap {
+            if gap > Duration::from_millis(max_gap) {
+                return Some(format!("no packets for {:.1}s", gap.as_secs_f64()));
+            }
+        }
+        match (self.quarantine_loss, probe_loss) {
+            (Some(max_loss), Some(loss)) if loss > max_loss => {
+                Some(format!("probe loss {}%", loss))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub struct Quarantine {
+    // Ultimo controllo andato male
+    pub last_bad: Instant,
+    pub reason: String,
+}
+
+// Da quando i path sono disponibili (non ancora creati) o non più disponibili
+// (non ancora rimossi)
+#[derive(Default)]
+pub struct Damping {
+    up_since: HashMap<String, Instant>,
+    down_since: HashMap<String, Instant>,
+}
+
+impl Damping {
+    // Il path è disponibile: true se lo è da almeno min_up
+    pub fn up(&mut self, key: &str, now: Instant, min_up: Duration) -> bool {
+        let since = *self.up_since.entry(key.to_string()).or_insert(now);
+        now.duration_since(since) >= min_up
+    }
+
+    // Da quanto il path aspetta di essere creato
+    pub fn pending(&self, key: &str, now: Instant) -> Option<Duration> {
+        self.up_since
+            .get(key)
+            .map(|since| now.duration_since(*since))
+    }
+
+    // Dimentica i path in attesa che non sono più disponibili o sono stati creati
+    pub fn retain_pending(&mut self, keep: impl Fn(&str) -> bool) {
+        self.up_since.retain(|key, _| keep(key));
+    }
+
+    // Il path creato non è disponibile: true se non lo è da almeno remove_delay
+    pub fn down(&mut self, key: &str, now: Instant, remove_delay: Duration) -> bool {
+        let since = *self.down_since.entry(key.to_string()).or_insert(now);
+        now.duration_since(since) >= remove_delay
+    }
+
+    // Da quanto il path creato non è disponibile
+    pub fn down_for(&self, key: &str, now: Instant) -> Option<Duration> {
+        self.down_since
+            .get(key)
+            .map(|since| now.duration_since(*since))
+    }
+
+    // Il path è di nuovo disponibile o è stato rimosso: true se non lo era
+    pub fn clear_down(&mut self, key: &str) -> bool {
+        self.down_since.remove(key).is_some()
+    }
+}
@@ -1,19 +1,48 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
+use damping::{Damping, HealthPolicy, Quarantine};
+use engarde_common::auth::{self, COOKIE_LEN};
+use engarde_common::batch::{Receiver, Sender, BATCH_SIZE};
+use engarde_common::dns;
+use engarde_common::fec::{Fec, FecConfig, PARITY_OVERHEAD};
+use engarde_common::framing::{FrameType, Framing, Header, HEADER_LEN};
+use engarde_common::metrics::{
+    fec_values, framing_values, MetricsWriter, PathCounters, FEC_COUNTERS, FRAMING_COUNTERS,
+    PATH_COUNTERS,
+};
+use engarde_common::pool::{BufferPool, Packet};
+use engarde_common::probe::{mtu_reply_for, reply_for, ProbeStats, PROBE_HEALTH_INTERVALS};
+use engarde_common::queue::{DropPolicy, SendQueue, DEFAULT_QUEUE_SIZE};
+use engarde_common::scheduler::{Mode, PathInfo, Scheduler};
+use engarde_common::tcp::{read_datagram, TcpLink};
+use engarde_common::web;
 use if_addrs::get_if_addrs;
 use log::{info, warn};
-use mime_guess;
+use policy::{patch_excluded_interfaces, InterfacePolicy, QuotaAction};
+use ratelimit::RateLimiter;
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 use tokio::sync::RwLock;
-use tokio::{net::UdpSocket, time};
+use tokio::time;
+use usage::Usage;
 use warp::Filter;
 
+mod damping;
+mod pmtu;
+mod policy;
+mod ratelimit;
+mod usage;
+
 //
 // CONFIGURAZIONE
 //
@@ -23,7 +52,7 @@ struct Config {
     client: ClientConfig,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct ClientConfig {
     #[serde(rename = "description")]
     description: Option<String>,
@@ -31,6 +60,28 @@ struct ClientConfig {
     listen_addr: String,
     #[serde(rename = "dstAddr")]
     dst_addr: String,
+    // Altri indirizzi del server: ogni interfaccia crea un path verso ognuno
+    #[serde(rename = "dstAddrs", default)]
+    dst_addrs: Vec<String>,
+    // Ogni quanti secondi risolvere di nuovo le destinazioni con un nome, se
+    // assente si segue il TTL della risposta
+    #[serde(rename = "dnsRefresh")]
+    dns_refresh: Option<u64>,
+    // Porta TCP del server per i path con transport tcp o auto, se assente la
+    // stessa della destinazione
+    #[serde(rename = "tcpPort")]
+    tcp_port: Option<u16>,
+    // Con transport auto un path passa a TCP se non riceve nulla per
+    // tcpFallback millisecondi
+    #[serde(rename = "tcpFallback")]
+    tcp_fallback: Option<u64>,
+    // Chiave condivisa con il server, che accetta un path solo dopo che ha
+    // dimostrato di conoscerla (vedi auth.rs)
+    psk: Option<String>,
+    // Un path autenticato ripete lo scambio se non riceve nulla per authRefresh
+    // millisecondi, deve restare sotto il clientTimeout del server
+    #[serde(rename = "authRefresh")]
+    auth_refresh: Option<u64>,
     #[serde(rename = "writeTimeout")]
     write_timeout: Option<u64>, // in milliseconds
     #[serde(rename = "excludedInterfaces")]
@@ -39,17 +90,107 @@ struct ClientConfig {
     dst_overrides: Vec<DstOverride>,
     #[serde(rename = "webManager")]
     web_manager: Option<WebManagerConfig>,
+    // Usa anche gli indirizzi IPv6 ULA (fc00::/7) come sorgente
+    #[serde(rename = "ipv6AllowUla", default)]
+    ipv6_allow_ula: bool,
+    // Lega ogni socket alla sua interfaccia con SO_BINDTODEVICE, così non servono
+    // regole di policy routing per far uscire il traffico dal link giusto
+    #[serde(rename = "bindToDevice", default)]
+    bind_to_device: bool,
+    // Impostazioni specifiche per interfaccia
+    #[serde(default)]
+    interfaces: Vec<InterfaceConfig>,
+    // Header engarde con numero di sequenza, va abilitato anche sul server
+    #[serde(default)]
+    framing: bool,
+    // Intervallo dei probe di RTT/jitter/perdita su ogni path, in millisecondi
+    #[serde(rename = "probeInterval")]
+    probe_interval: Option<u64>,
+    // File in cui salvare le esclusioni cambiate dalla web UI, ricaricate all'avvio
+    #[serde(rename = "stateFile")]
+    state_file: Option<String>,
+    // Come distribuire i pacchetti sui path: redundant, balance o failover
+    #[serde(default)]
+    mode: Mode,
+    // Un path è sano se ha ricevuto qualcosa negli ultimi healthTimeout millisecondi
+    #[serde(rename = "healthTimeout")]
+    health_timeout: Option<u64>,
+    // Frame di parità Reed-Solomon per ricostruire i pacchetti persi, richiede il framing
+    fec: Option<FecConfig>,
+    // Dimensione massima di un pacchetto Wireguard, i più grandi vengono scartati
+    #[serde(rename = "maxDatagramSize")]
+    max_datagram_size: Option<usize>,
+    // Ogni quanti secondi ripetere la ricerca del path MTU di ogni path
+    #[serde(rename = "mtuProbeInterval")]
+    mtu_probe_interval: Option<u64>,
+    // Su Linux legge e scrive più datagram per chiamata (recvmmsg/sendmmsg, GSO/GRO)
+    #[serde(rename = "batchIo", default)]
+    batch_io: bool,
+    // Pacchetti in coda per ogni path e cosa scartare quando la coda è piena
+    #[serde(rename = "sendQueueSize")]
+    send_queue_size: Option<usize>,
+    #[serde(rename = "dropPolicy", default)]
+    drop_policy: DropPolicy,
+    // File in cui salvare il traffico di ogni interfaccia nel ciclo di fatturazione
+    #[serde(rename = "usageFile")]
+    usage_file: Option<String>,
+    // Smorzamento dei flap delle interfacce e quarantena dei path instabili
+    #[serde(rename = "healthPolicy", default)]
+    health_policy: HealthPolicy,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct InterfaceConfig {
+    #[serde(rename = "ifName")]
+    if_name: String,
+    // SO_MARK applicato ai socket dell'interfaccia, da usare con "ip rule fwmark"
+    fwmark: Option<u32>,
+    // Peso nella modalità balance (default 1, 0 = solo se gli altri non sono sani)
+    weight: Option<u32>,
+    // Priorità nella modalità failover, il valore più basso è il path principale
+    priority: Option<u32>,
+    // Limiti di invio dell'interfaccia: byte e pacchetti al secondo, e quanti
+    // byte possono partire di seguito oltre il limite (default 100ms di maxBps)
+    #[serde(rename = "maxBps")]
+    max_bps: Option<u64>,
+    #[serde(rename = "maxPps")]
+    max_pps: Option<u64>,
+    burst: Option<u64>,
+    // Byte (inviati più ricevuti) per ciclo di fatturazione, il ciclo inizia
+    // ogni mese il giorno billingDay (default 1). Superata la quota l'interfaccia
+    // viene esclusa o messa in standby fino al ciclo successivo.
+    quota: Option<u64>,
+    #[serde(rename = "billingDay")]
+    billing_day: Option<u32>,
+    #[serde(rename = "quotaAction", default)]
+    quota_action: QuotaAction,
+    // Trasporto dei path dell'interfaccia: udp, tcp o auto
+    #[serde(default)]
+    transport: Transport,
+}
+
+// Con auto il path usa UDP e passa a TCP (fino a quando viene ricreato) se su
+// UDP non arriva più nulla, ad esempio su una rete che blocca UDP
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Transport {
+    #[default]
+    Udp,
+    Tcp,
+    Auto,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct DstOverride {
     #[serde(rename = "ifName")]
     if_name: String,
     #[serde(rename = "dstAddr")]
     dst_addr: String,
+    #[serde(rename = "dstAddrs", default)]
+    dst_addrs: Vec<String>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct WebManagerConfig {
     #[serde(rename = "listenAddr")]
     listen_addr: String,
@@ -57,25 +198,567 @@ struct WebManagerConfig {
     password: String,
 }
 
+// Configurazione corrente, sostituita quando viene ricaricata con SIGHUP
+type SharedConfig = Arc<Mutex<ClientConfig>>;
+
+fn load_config(path: &str) -> Result<ClientConfig, String> {
+    let config_str =
+        std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    let config: Config =
+        serde_yaml::from_str(&config_str).map_err(|e| format!("Error parsing config: {}", e))?;
+    let cfg = config.client;
+    if cfg.listen_addr.is_empty() {
+        return Err("No listen_addr specified".to_string());
+    }
+    if cfg.dst_addr.is_empty() {
+        return Err("No dst_addr specified".to_string());
+    }
+    if let Some(fec) = &cfg.fec {
+        if !cfg.framing {
+            return Err("fec requires framing: true".to_string());
+        }
+        if cfg.mode == Mode::Redundant {
+            // La parità andrebbe ad aggiungersi alla copia su tutti i path
+            return Err(
+                "fec can't be used with mode: redundant, use balance or failover".to_string(),
+            );
+        }
+        if !fec.is_valid() {
+            return Err(format!(
+                "Invalid fec: dataShards {} parityShards {}",
+                fec.data_shards, fec.parity_shards
+            ));
+        }
+    }
+    if let Some(size) = cfg.max_datagram_size {
+        if !(MIN_DATAGRAM_SIZE..=MAX_DATAGRAM_SIZE).contains(&size) {
+            return Err(format!(
+                "Invalid maxDatagramSize {}, must be between {} and {}",
+                size, MIN_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE
+            ));
+        }
+    }
+    if cfg.send_queue_size == Some(0) {
+        return Err("Invalid sendQueueSize 0, must be at least 1".to_string());
+    }
+    if cfg.dns_refresh == Some(0) {
+        return Err("Invalid dnsRefresh 0, must be at least 1".to_string());
+    }
+    if cfg.psk.as_deref() == Some("") {
+        return Err("Invalid psk, must not be empty".to_string());
+    }
+    if cfg.auth_refresh == Some(0) {
+        return Err("Invalid authRefresh 0, must be at least 1".to_string());
+    }
+    if cfg.mode != Mode::Redundant && cfg.probe_interval.is_none() {
+        warn!("probeInterval is not set: in balance and failover mode a path looks healthy only while WireGuard is receiving");
+    }
+    if let Some(loss) = cfg.health_policy.quarantine_loss {
+        if !(0.0..=100.0).contains(&loss) {
+            return Err(format!(
+                "Invalid quarantineLoss {}, must be between 0 and 100",
+                loss
+            ));
+        }
+    }
+    for iface in &cfg.interfaces {
+        let limits = [
+            ("maxBps", iface.max_bps),
+            ("maxPps", iface.max_pps),
+            ("burst", iface.burst),
+            ("quota", iface.quota),
+        ];
+        if let Some((name, _)) = limits.iter().find(|(_, v)| *v == Some(0)) {
+            return Err(format!(
+                "Invalid {} 0 for interface {}",
+                name, iface.if_name
+            ));
+        }
+        if let Some(day) = iface.billing_day {
+            if !(1..=28).contains(&day) {
+                return Err(format!(
+                    "Invalid billingDay {} for interface {}, must be between 1 and 28",
+                    day, iface.if_name
+                ));
+            }
+        }
+    }
+    let mut addrs = vec![&cfg.listen_addr];
+    addrs.extend(cfg.web_manager.iter().map(|web| &web.listen_addr));
+    for addr in addrs {
+        addr.parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid address {}: {}", addr, e))?;
+    }
+    // Le destinazioni possono anche essere nomi
+    let mut dsts = vec![&cfg.dst_addr];
+    dsts.extend(&cfg.dst_addrs);
+    for ov in &cfg.dst_overrides {
+        dsts.push(&ov.dst_addr);
+        dsts.extend(&ov.dst_addrs);
+    }
+    for dst in dsts {
+        if dst.parse::<SocketAddr>().is_err() && dns::split_host_port(dst).is_none() {
+            return Err(format!(
+                "Invalid destination {}, must be ip:port or host:port",
+                dst
+            ));
+        }
+    }
+    Ok(cfg)
+}
+
+//
+// Ricaricamento della configurazione
+//
+
+// Annota un'impostazione cambiata che viene applicata subito
+fn note_applied<T: PartialEq>(
+    name: &'static str,
+    current: &T,
+    new: &T,
+    applied: &mut Vec<&'static str>,
+) {
+    if current != new {
+        applied.push(name);
+    }
+}
+
+// Un'impostazione che richiede il riavvio mantiene il valore corrente
+fn keep_current<T: PartialEq + Clone>(
+    name: &'static str,
+    current: &T,
+    new: &mut T,
+    restart: &mut Vec<&'static str>,
+) {
+    if current != new {
+        restart.push(name);
+        *new = current.clone();
+    }
+}
+
+// Restituisce la configurazione da usare, le impostazioni applicate e quelle
+// che richiedono il riavvio
+fn merge_reload(
+    current: &ClientConfig,
+    mut new: ClientConfig,
+) -> (ClientConfig, Vec<&'static str>, Vec<&'static str>) {
+    let mut applied = Vec::new();
+    let mut restart = Vec::new();
+    note_applied(
+        "description",
+        &current.description,
+        &new.description,
+        &mut applied,
+    );
+    note_applied("dstAddr", &current.dst_addr, &new.dst_addr, &mut applied);
+    note_applied("dstAddrs", &current.dst_addrs, &new.dst_addrs, &mut applied);
+    note_applied(
+        "dnsRefresh",
+        &current.dns_refresh,
+        &new.dns_refresh,
+        &mut applied,
+    );
+    note_applied("tcpPort", &current.tcp_port, &new.tcp_port, &mut applied);
+    note_applied(
+        "tcpFallback",
+        &current.tcp_fallback,
+        &new.tcp_fallback,
+        &mut applied,
+    );
+    note_applied(
+        "writeTimeout",
+        &current.write_timeout,
+        &new.write_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "excludedInterfaces",
+        &current.excluded_interfaces,
+        &new.excluded_interfaces,
+        &mut applied,
+    );
+    note_applied(
+        "dstOverrides",
+        &current.dst_overrides,
+        &new.dst_overrides,
+        &mut applied,
+    );
+    note_applied(
+        "ipv6AllowUla",
+        &current.ipv6_allow_ula,
+        &new.ipv6_allow_ula,
+        &mut applied,
+    );
+    note_applied("mode", &current.mode, &new.mode, &mut applied);
+    note_applied(
+        "authRefresh",
+        &current.auth_refresh,
+        &new.auth_refresh,
+        &mut applied,
+    );
+    note_applied(
+        "interfaces",
+        &current.interfaces,
+        &new.interfaces,
+        &mut applied,
+    );
+    note_applied(
+        "healthTimeout",
+        &current.health_timeout,
+        &new.health_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "stateFile",
+        &current.state_file,
+        &new.state_file,
+        &mut applied,
+    );
+    note_applied(
+        "healthPolicy",
+        &current.health_policy,
+        &new.health_policy,
+        &mut applied,
+    );
+    note_applied(
+        "dropPolicy",
+        &current.drop_policy,
+        &new.drop_policy,
+        &mut applied,
+    );
+    keep_current(
+        "listenAddr",
+        &current.listen_addr,
+        &mut new.listen_addr,
+        &mut restart,
+    );
+    keep_current(
+        "bindToDevice",
+        &current.bind_to_device,
+        &mut new.bind_to_device,
+        &mut restart,
+    );
+    keep_current("framing", &current.framing, &mut new.framing, &mut restart);
+    keep_current("fec", &current.fec, &mut new.fec, &mut restart);
+    keep_current(
+        "maxDatagramSize",
+        &current.max_datagram_size,
+        &mut new.max_datagram_size,
+        &mut restart,
+    );
+    keep_current(
+        "batchIo",
+        &current.batch_io,
+        &mut new.batch_io,
+        &mut restart,
+    );
+    keep_current(
+        "sendQueueSize",
+        &current.send_queue_size,
+        &mut new.send_queue_size,
+        &mut restart,
+    );
+    keep_current(
+        "usageFile",
+        &current.usage_file,
+        &mut new.usage_file,
+        &mut restart,
+    );
+    keep_current(
+        "mtuProbeInterval",
+        &current.mtu_probe_interval,
+        &mut new.mtu_probe_interval,
+        &mut restart,
+    );
+    keep_current("psk", &current.psk, &mut new.psk, &mut restart);
+    keep_current(
+        "probeInterval",
+        &current.probe_interval,
+        &mut new.probe_interval,
+        &mut restart,
+    );
+    match (&current.web_manager, &mut new.web_manager) {
+        (Some(cur), Some(web)) => {
+            if cur.username != web.username || cur.password != web.password {
+                applied.push("webManager credentials");
+            }
+            keep_current(
+                "webManager.listenAddr",
+                &cur.listen_addr,
+                &mut web.listen_addr,
+                &mut restart,
+            );
+        }
+        (None, None) => {}
+        _ => keep_current(
+            "webManager",
+            &current.web_manager,
+            &mut new.web_manager,
+            &mut restart,
+        ),
+    }
+    (new, applied, restart)
+}
+
+// Interfacce aggiunte, rimosse o cambiate nella sezione interfaces
+fn changed_interfaces(current: &ClientConfig, new: &ClientConfig) -> Vec<String> {
+    let mut names: Vec<String> = current
+        .interfaces
+        .iter()
+        .chain(&new.interfaces)
+        .map(|i| i.if_name.clone())
+        .collect();
+    names.sort();
+    names.dedup();
+    names.retain(|name| get_interface_config(name, current) != get_interface_config(name, new));
+    names
+}
+
+// Peso, priorità, fwmark, trasporto e limiti di invio vengono letti quando la
+// routine viene creata: le routine delle interfacce cambiate vengono sostituite
+// da routine nuove sullo stesso indirizzo e destinazione
+async fn recreate_routines(tunnel: &Tunnel, config: &SharedConfig, ifnames: &[String]) {
+    tunnel
+        .rate_limits
+        .lock()
+        .unwrap()
+        .retain(|ifname, _| !ifnames.contains(ifname));
+    let cfg = config.lock().unwrap().clone();
+    let routines: Vec<SendingRoutine> = tunnel
+        .sending_channels
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|routine| ifnames.contains(&routine.ifname))
+        .cloned()
+        .collect();
+    for routine in routines {
+        info!(
+            "Settings of interface '{}' changed, recreating routine to {}",
+            routine.ifname, routine.dst
+        );
+        create_send_thread(
+            &routine.ifname,
+            routine.src_addr,
+            &routine.dst,
+            routine.dst_addr(),
+            tunnel,
+            config,
+            &cfg,
+        )
+        .await;
+    }
+}
+
+async fn reload_on_sighup(
+    config_path: String,
+    tunnel: Tunnel,
+    config: SharedConfig,
+    policy: Arc<InterfacePolicy>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Cannot install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading {}", config_path);
+        let new = match load_config(&config_path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                warn!("Configuration not reloaded: {}", e);
+                continue;
+            }
+        };
+        let changed = {
+            let mut current = config.lock().unwrap();
+            let (merged, applied, restart) = merge_reload(&current, new);
+            if merged.excluded_interfaces != current.excluded_interfaces {
+                policy.set_static_exclusions(merged.excluded_interfaces.clone());
+            }
+            let changed = changed_interfaces(&current, &merged);
+            *current = merged;
+            if applied.is_empty() {
+                info!("Configuration reloaded, no changes applied");
+            } else {
+                info!("Configuration reloaded, applied: {}", applied.join(", "));
+            }
+            if !restart.is_empty() {
+                warn!(
+                    "Changes that need a restart (ignored): {}",
+                    restart.join(", ")
+                );
+            }
+            changed
+        };
+        if !changed.is_empty() {
+            recreate_routines(&tunnel, &config, &changed).await;
+        }
+    }
+}
+
 //
 // SENDING ROUTINE (per ogni interfaccia)
 //
 
 #[derive(Clone)]
 struct SendingRoutine {
+    ifname: String,
     src_sock: Arc<UdpSocket>,
-    src_addr: String,
-    dst_addr: SocketAddr,
+    sender: Arc<Sender>,
+    // Pacchetti da inviare sul path, svuotata dal task send_queued
+    queue: Arc<SendQueue>,
+    // Limite di banda dell'interfaccia, condiviso con le altre sue routine
+    rate_limit: Option<Arc<RateLimiter>>,
+    src_addr: IpAddr,
+    // Destinazione configurata (anche un nome), identifica il path
+    dst: String,
+    // Indirizzo attuale della destinazione, cambia se il nome viene risolto in
+    // un altro
+    dst_addr: Arc<Mutex<SocketAddr>>,
+    // Connessione TCP verso il server. Quando il path passa a TCP (subito con
+    // transport tcp) tcp_only viene impostato e, mentre la connessione manca,
+    // non si invia nulla su UDP.
+    tcp: Arc<Mutex<Option<Arc<TcpLink>>>>,
+    tcp_only: Arc<AtomicBool>,
+    // Con la psk: se il server ha accettato il path, che fino ad allora non
+    // viene usato
+    authenticated: Option<Arc<AtomicBool>>,
+    weight: u32,
+    priority: u32,
     last_rec: Arc<Mutex<Instant>>,
     bytes_total: Arc<Mutex<u64>>,
     last_traffic_check: Arc<Mutex<Instant>>,
     last_traffic_total: Arc<Mutex<u64>>,
-    // Campo presente per compatibilità con Go
+    // Frame ricevuti su questo path e, all'ultima lettura della web API,
+    // (frame del path, frame unici del tunnel) per calcolare la perdita
+    frames_rx: Arc<Mutex<u64>>,
+    last_loss_check: Arc<Mutex<(u64, u64)>>,
+    probe: Arc<Mutex<ProbeStats>>,
+    // Payload UDP più grande che attraversa il path, se è stato misurato
+    path_mtu: Arc<Mutex<Option<usize>>>,
+    // Se il path è in quarantena (healthPolicy)
+    quarantine: Arc<Mutex<Option<Quarantine>>>,
+    metrics: Arc<PathMetrics>,
+    // Come nella versione Go: impostato quando la routine viene rimossa,
+    // fa terminare i task della routine
     is_closing: Arc<Mutex<bool>>,
 }
 
+impl SendingRoutine {
+    fn dst_addr(&self) -> SocketAddr {
+        *self.dst_addr.lock().unwrap()
+    }
+
+    fn authenticated(&self) -> bool {
+        self.authenticated
+            .as_ref()
+            .is_none_or(|a| a.load(Ordering::Relaxed))
+    }
+
+    // Il server vede un nuovo mittente (o un nuovo server), va ripetuta
+    // l'autenticazione
+    fn reauthenticate(&self) {
+        if let Some(authenticated) = &self.authenticated {
+            authenticated.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn tcp(&self) -> Option<Arc<TcpLink>> {
+        self.tcp.lock().unwrap().clone()
+    }
+
+    // Invia sul trasporto attuale del path
+    async fn send<P: AsRef<[u8]>>(&self, packets: &[P]) -> std::io::Result<()> {
+        match self.tcp() {
+            Some(link) => link.send(packets),
+            None if self.tcp_only.load(Ordering::Relaxed) => {
+                Err(std::io::Error::from(std::io::ErrorKind::NotConnected))
+            }
+            None => {
+                self.sender
+                    .send(&self.src_sock, packets, self.dst_addr())
+                    .await
+            }
+        }
+    }
+}
+
+// Una routine per ogni coppia interfaccia/destinazione (quindi anche una per
+// famiglia di indirizzi), indicizzata con path_key()
 type SendingChannels = Arc<Mutex<HashMap<String, SendingRoutine>>>;
 
+fn path_key(ifname: &str, dst: &str) -> String {
+    format!("{}/{}", ifname, dst)
+}
+
+// Contatori di un path per /metrics, restano anche quando la routine viene
+// rimossa e ricreata
+struct PathMetrics {
+    ifname: String,
+    dst_addr: String,
+    counters: PathCounters,
+    ups: AtomicU64,
+    downs: AtomicU64,
+}
+
+impl PathMetrics {
+    fn labels(&self) -> [(&str, &str); 2] {
+        [("interface", &self.ifname), ("dst", &self.dst_addr)]
+    }
+}
+
+type PathMetricsMap = Arc<Mutex<HashMap<String, Arc<PathMetrics>>>>;
+
+// Stato condiviso del tunnel: il socket verso Wireguard, l'indirizzo da cui
+// Wireguard ci scrive, i path attivi, il framing e la FEC (se abilitati)
+#[derive(Clone)]
+struct Tunnel {
+    wg_sock: Arc<UdpSocket>,
+    wg_addr: Arc<RwLock<Option<SocketAddr>>>,
+    sending_channels: SendingChannels,
+    framing: Option<Arc<Framing>>,
+    fec: Option<Arc<Fec>>,
+    path_metrics: PathMetricsMap,
+    // Dimensione massima dei pacchetti di Wireguard (maxDatagramSize)
+    max_datagram: usize,
+    // Pacchetti di Wireguard scartati perché più grandi di max_datagram
+    wg_truncated: Arc<AtomicU64>,
+    batch_io: bool,
+    wg_sender: Arc<Sender>,
+    // Dimensione della coda di invio di ogni path (sendQueueSize)
+    send_queue: usize,
+    // Limiti di banda per interfaccia, creati con la prima routine dell'interfaccia
+    rate_limits: Arc<Mutex<HashMap<String, Arc<RateLimiter>>>>,
+    // Traffico di ogni interfaccia nel ciclo di fatturazione
+    usage: Arc<Usage>,
+    // Path in attesa di essere creati o rimossi (healthPolicy)
+    damping: Arc<Mutex<Damping>>,
+    // Ultima risoluzione delle destinazioni con un nome, per path
+    resolved: Arc<Mutex<HashMap<String, Resolution>>>,
+    psk: Option<Arc<[u8]>>,
+}
+
+impl Tunnel {
+    // Byte aggiunti da engarde al pacchetto di Wireguard nel caso peggiore
+    fn overhead(&self) -> usize {
+        let framing = if self.framing.is_some() {
+            HEADER_LEN
+        } else {
+            0
+        };
+        let fec = if self.fec.is_some() {
+            PARITY_OVERHEAD
+        } else {
+            0
+        };
+        framing + fec
+    }
+}
+
 //
 // Strutture per la Web API
 //
@@ -84,10 +767,46 @@ type SendingChannels = Arc<Mutex<HashMap<String, SendingRoutine>>>;
 struct WebInterface {
     name: String,
     status: String,
-    senderAddress: String,
-    dstAddress: String,
+    // Perché il path non è attivo (pending, down, quarantined, ...)
+    #[serde(rename = "statusReason")]
+    status_reason: Option<String>,
+    #[serde(rename = "senderAddress")]
+    sender_address: String,
+    #[serde(rename = "dstAddress")]
+    dst_address: String,
+    // Trasporto usato ora dal path: udp o tcp
+    transport: Option<String>,
     last: Option<u64>,
-    trafficBps: Option<u64>,
+    #[serde(rename = "trafficBps")]
+    traffic_bps: Option<u64>,
+    // Percentuale di pacchetti persi sul path, solo con il framing abilitato
+    loss: Option<f64>,
+    // Statistiche dei probe, solo con probeInterval impostato
+    #[serde(rename = "rttMs")]
+    rtt_ms: Option<f64>,
+    #[serde(rename = "jitterMs")]
+    jitter_ms: Option<f64>,
+    #[serde(rename = "probeLoss")]
+    probe_loss: Option<f64>,
+    // Payload UDP più grande che attraversa il path, con mtuProbeInterval impostato
+    #[serde(rename = "pathMtu")]
+    path_mtu: Option<usize>,
+    // Pacchetti nella coda di invio e pacchetti scartati perché era piena
+    #[serde(rename = "queueDepth")]
+    queue_depth: Option<usize>,
+    #[serde(rename = "queueDrops")]
+    queue_drops: Option<u64>,
+    // Pacchetti scartati dal limite maxBps/maxPps dell'interfaccia
+    #[serde(rename = "rateLimitDrops")]
+    rate_limit_drops: Option<u64>,
+    // Traffico dell'interfaccia nel ciclo di fatturazione e sua quota
+    #[serde(rename = "usageTx")]
+    usage_tx: Option<u64>,
+    #[serde(rename = "usageRx")]
+    usage_rx: Option<u64>,
+    #[serde(rename = "cycleStart")]
+    cycle_start: Option<String>,
+    quota: Option<u64>,
 }
 
 #[derive(Serialize)]
@@ -95,14 +814,33 @@ struct GetListResponse {
     r#type: String,
     version: String,
     description: String,
-    listenAddress: String,
+    #[serde(rename = "listenAddress")]
+    listen_address: String,
     interfaces: Vec<WebInterface>,
     #[serde(rename = "wgMtu")]
     wg_mtu: Option<u32>,
+    // MTU di Wireguard che passa su tutti i path misurati
+    #[serde(rename = "safeWgMtu")]
+    safe_wg_mtu: Option<usize>,
+    mode: Mode,
+    // Pacchetti ricostruiti dalla FEC, solo con fec abilitata
+    #[serde(rename = "fecRecovered")]
+    fec_recovered: Option<u64>,
 }
 
 static VERSION: &str = "0.1.2";
 
+// Priorità delle interfacce senza priority, dopo quelle configurate
+const DEFAULT_PRIORITY: u32 = 100;
+
+// Limiti di maxDatagramSize: il minimo di IPv4 e il massimo payload UDP
+const DEFAULT_DATAGRAM_SIZE: usize = 1500;
+const MIN_DATAGRAM_SIZE: usize = 576;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// Header e tag che Wireguard aggiunge ai pacchetti del tunnel
+const WG_OVERHEAD: usize = 32;
+
 //
 // Custom rejection per Warp
 //
@@ -114,287 +852,1441 @@ impl warp::reject::Reject for CustomReject {}
 //
 // Gestione delle esclusioni
 //
-static mut EXCLUSION_SWAPS: Option<Mutex<HashMap<String, bool>>> = None;
 
-fn is_swapped(name: &str) -> bool {
-    unsafe {
-        if let Some(ref m) = EXCLUSION_SWAPS {
-            let swaps = m.lock().unwrap();
-            swaps.get(name).copied().unwrap_or(false)
+// Contenuto dello stateFile
+#[derive(Serialize, Deserialize, Default)]
+struct ExclusionState {
+    swaps: Vec<String>,
+}
+
+// Scrive su un file temporaneo e lo rinomina, così il file non resta mai a metà
+fn write_file_atomic(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        std::io::Write::write_all(&mut file, contents)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)
+}
+
+fn load_exclusion_state(path: &str) -> ExclusionState {
+    match std::fs::read_to_string(path) {
+        Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+            warn!("Ignoring invalid state file {}: {}", path, e);
+            ExclusionState::default()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => ExclusionState::default(),
+        Err(e) => {
+            warn!("Cannot read state file {}: {}", path, e);
+            ExclusionState::default()
+        }
+    }
+}
+
+fn save_exclusion_state(policy: &InterfacePolicy, cfg: &ClientConfig) {
+    let path = match &cfg.state_file {
+        Some(path) => path,
+        None => return,
+    };
+    let state = ExclusionState {
+        swaps: policy.swapped(),
+    };
+    let data = serde_json::to_vec_pretty(&state).unwrap();
+    if let Err(e) = write_file_atomic(path, &data) {
+        warn!("Cannot write state file {}: {}", path, e);
+    }
+}
+
+// Riscrive excludedInterfaces nel file YAML lasciando invariate le altre chiavi
+fn write_excluded_interfaces(config_path: &str, excluded: &[String]) -> Result<(), String> {
+    let config_str = std::fs::read_to_string(config_path)
+        .map_err(|e| format!("Error reading {}: {}", config_path, e))?;
+    // Cambia solo la riga di excludedInterfaces, i commenti restano
+    let data = patch_excluded_interfaces(&config_str, excluded)
+        .ok_or_else(|| "No client section in config".to_string())?;
+    let config: Config =
+        serde_yaml::from_str(&data).map_err(|e| format!("Error parsing updated config: {}", e))?;
+    if config.client.excluded_interfaces != excluded {
+        return Err("Cannot update excludedInterfaces in the config file".to_string());
+    }
+    write_file_atomic(config_path, data.as_bytes())
+        .map_err(|e| format!("Error writing {}: {}", config_path, e))
+}
+
+//
+// Traffico e quote
+//
+
+// Ogni quanto sommare il traffico dei path e controllare le quote, e ogni
+// quanti controlli salvare l'usageFile
+const USAGE_INTERVAL: Duration = Duration::from_secs(10);
+const USAGE_SAVE_EVERY: u32 = 6;
+
+fn load_usage(path: &str) -> Usage {
+    match std::fs::read_to_string(path) {
+        Ok(data) => Usage::from_json(&data).unwrap_or_else(|e| {
+            warn!("Ignoring invalid usage file {}: {}", path, e);
+            Usage::new()
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Usage::new(),
+        Err(e) => {
+            warn!("Cannot read usage file {}: {}", path, e);
+            Usage::new()
+        }
+    }
+}
+
+fn save_usage(usage: &Usage, cfg: &ClientConfig) {
+    let path = match &cfg.usage_file {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = write_file_atomic(path, &usage.to_json()) {
+        warn!("Cannot write usage file {}: {}", path, e);
+    }
+}
+
+fn billing_day(ifname: &str, cfg: &ClientConfig) -> u32 {
+    get_interface_config(ifname, cfg)
+        .and_then(|iface| iface.billing_day)
+        .unwrap_or(1)
+}
+
+// Somma il traffico dei path alla loro interfaccia e applica le quote
+async fn track_usage(tunnel: Tunnel, config: SharedConfig, policy: Arc<InterfacePolicy>) {
+    // Contatori di ogni path al controllo precedente
+    let mut last: HashMap<String, (u64, u64)> = HashMap::new();
+    let mut changed = false;
+    let mut checks = 0u32;
+    loop {
+        time::sleep(USAGE_INTERVAL).await;
+        let cfg = config.lock().unwrap().clone();
+        let today = usage::today();
+        let paths: Vec<(String, Arc<PathMetrics>)> = tunnel
+            .path_metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let mut deltas: HashMap<String, (u64, u64)> = cfg
+            .interfaces
+            .iter()
+            .filter(|iface| iface.quota.is_some())
+            .map(|iface| (iface.if_name.clone(), (0, 0)))
+            .collect();
+        for (key, metrics) in paths {
+            let tx = metrics.counters.tx_bytes.load(Ordering::Relaxed);
+            let rx = metrics.counters.rx_bytes.load(Ordering::Relaxed);
+            let (last_tx, last_rx) = last.insert(key, (tx, rx)).unwrap_or((0, 0));
+            let delta = deltas.entry(metrics.ifname.clone()).or_default();
+            delta.0 += tx - last_tx;
+            delta.1 += rx - last_rx;
+        }
+        for (ifname, (tx, rx)) in deltas {
+            let cycle_start = usage::cycle_start(today, billing_day(&ifname, &cfg));
+            // Anche senza traffico, per azzerare i contatori all'inizio del ciclo
+            let before = tunnel.usage.get(&ifname);
+            tunnel.usage.add(&ifname, tx, rx, &cycle_start);
+            changed |= tunnel.usage.get(&ifname) != before;
+        }
+        let mut transition = false;
+        for iface in &cfg.interfaces {
+            let quota = match iface.quota {
+                Some(quota) => quota,
+                None => continue,
+            };
+            let used = tunnel.usage.get(&iface.if_name).map_or(0, |u| u.total());
+            let action = (used >= quota).then_some(iface.quota_action);
+            if !policy.set_quota(&iface.if_name, action) {
+                continue;
+            }
+            transition = true;
+            match action {
+                Some(QuotaAction::Exclude) => warn!(
+                    "Interface '{}' reached its quota ({} of {} bytes), excluding it until the next billing cycle",
+                    iface.if_name, used, quota
+                ),
+                Some(QuotaAction::Standby) => warn!(
+                    "Interface '{}' reached its quota ({} of {} bytes), standby until the next billing cycle",
+                    iface.if_name, used, quota
+                ),
+                None => info!(
+                    "Interface '{}' is within its quota again ({} of {} bytes)",
+                    iface.if_name, used, quota
+                ),
+            }
+        }
+        checks += 1;
+        if changed && (transition || checks.is_multiple_of(USAGE_SAVE_EVERY)) {
+            save_usage(&tunnel.usage, &cfg);
+            changed = false;
+        }
+    }
+}
+
+//
+// Funzioni per le interfacce
+//
+
+fn is_usable_source(ip: &IpAddr, cfg: &ClientConfig) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => !ipv4.is_link_local() && !ipv4.is_loopback(),
+        IpAddr::V6(ipv6) => {
+            let seg0 = ipv6.segments()[0];
+            let link_local = (seg0 & 0xffc0) == 0xfe80;
+            let ula = (seg0 & 0xfe00) == 0xfc00;
+            !ipv6.is_loopback()
+                && !ipv6.is_unspecified()
+                && !link_local
+                && (!ula || cfg.ipv6_allow_ula)
+        }
+    }
+}
+
+// Primo indirizzo utilizzabile dell'interfaccia per la famiglia richiesta
+fn get_address_by_interface(ifname: &str, ipv6: bool, cfg: &ClientConfig) -> Option<IpAddr> {
+    if let Ok(ifaces) = get_if_addrs() {
+        for iface in ifaces {
+            if iface.name != ifname {
+                continue;
+            }
+            let ip = iface.ip();
+            if ip.is_ipv6() == ipv6 && is_usable_source(&ip, cfg) {
+                return Some(ip);
+            }
+        }
+    }
+    None
+}
+
+// Tutti gli indirizzi utilizzabili dell'interfaccia, IPv4 prima di IPv6
+fn get_addresses_by_interface(ifname: &str, cfg: &ClientConfig) -> Vec<IpAddr> {
+    [false, true]
+        .iter()
+        .filter_map(|&ipv6| get_address_by_interface(ifname, ipv6, cfg))
+        .collect()
+}
+
+// Le destinazioni dell'interfaccia: gli override (anche più di uno, ad esempio
+// uno IPv4 e uno IPv6) oppure il dstAddr generale, ognuno con i suoi dstAddrs.
+// Ogni destinazione diventa un path separato.
+fn get_dsts_by_ifname(ifname: &str, cfg: &ClientConfig) -> Vec<String> {
+    let mut dsts: Vec<String> = cfg
+        .dst_overrides
+        .iter()
+        .filter(|ov| ov.if_name == ifname)
+        .flat_map(|ov| std::iter::once(&ov.dst_addr).chain(&ov.dst_addrs))
+        .cloned()
+        .collect();
+    if dsts.is_empty() {
+        dsts = std::iter::once(&cfg.dst_addr)
+            .chain(&cfg.dst_addrs)
+            .cloned()
+            .collect();
+    }
+    let mut seen = HashSet::new();
+    dsts.retain(|dst| seen.insert(dst.clone()));
+    dsts
+}
+
+fn interface_exists(ifname: &str) -> bool {
+    if let Ok(ifaces) = get_if_addrs() {
+        for iface in ifaces {
+            if iface.name == ifname {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn get_interface_config<'a>(ifname: &str, cfg: &'a ClientConfig) -> Option<&'a InterfaceConfig> {
+    cfg.interfaces.iter().find(|i| i.if_name == ifname)
+}
+
+fn read_interface_mtu(ifname: &str) -> Option<u32> {
+    let path = format!("/sys/class/net/{}/mtu", ifname);
+    let value = std::fs::read_to_string(path).ok()?;
+    value.trim().parse().ok()
+}
+
+//
+// Socket UDP
+//
+
+// Socket legato all'interfaccia (bindToDevice, fwmark) e all'indirizzo
+fn path_socket(
+    ifname: &str,
+    bind_addr: SocketAddr,
+    ty: socket2::Type,
+    protocol: socket2::Protocol,
+    cfg: &ClientConfig,
+) -> std::io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(bind_addr), ty, Some(protocol))?;
+    if cfg.bind_to_device {
+        socket.bind_device(Some(ifname.as_bytes()))?;
+    }
+    if let Some(mark) = get_interface_config(ifname, cfg).and_then(|i| i.fwmark) {
+        socket.set_mark(mark)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&bind_addr.into())?;
+    Ok(socket)
+}
+
+fn bind_path_socket(
+    ifname: &str,
+    bind_addr: SocketAddr,
+    cfg: &ClientConfig,
+) -> std::io::Result<UdpSocket> {
+    let socket = path_socket(
+        ifname,
+        bind_addr,
+        socket2::Type::DGRAM,
+        socket2::Protocol::UDP,
+        cfg,
+    )?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn create_udp_socket(
+    ifname: &str,
+    source_addr: IpAddr,
+    cfg: &ClientConfig,
+) -> Option<Arc<UdpSocket>> {
+    let bind_addr = SocketAddr::new(source_addr, 0);
+    match bind_path_socket(ifname, bind_addr, cfg) {
+        Ok(sock) => Some(Arc::new(sock)),
+        Err(e) => {
+            warn!("Cannot create socket on {} ({}): {}", bind_addr, ifname, e);
+            None
+        }
+    }
+}
+
+//
+// Routine per ciascuna interfaccia
+//
+
+async fn create_send_thread(
+    ifname: &str,
+    source_addr: IpAddr,
+    dst: &str,
+    dst_addr: SocketAddr,
+    tunnel: &Tunnel,
+    config: &SharedConfig,
+    cfg: &ClientConfig,
+) {
+    let src_sock = match create_udp_socket(ifname, source_addr, cfg) {
+        Some(s) => s,
+        None => return,
+    };
+    let metrics = tunnel
+        .path_metrics
+        .lock()
+        .unwrap()
+        .entry(path_key(ifname, dst))
+        .or_insert_with(|| {
+            Arc::new(PathMetrics {
+                ifname: ifname.to_string(),
+                dst_addr: dst.to_string(),
+                counters: PathCounters::default(),
+                ups: AtomicU64::new(0),
+                downs: AtomicU64::new(0),
+            })
+        })
+        .clone();
+    metrics.ups.fetch_add(1, Ordering::Relaxed);
+    let if_cfg = get_interface_config(ifname, cfg);
+    let rate_limit = if_cfg.and_then(|i| {
+        let mut limits = tunnel.rate_limits.lock().unwrap();
+        if let Some(limit) = limits.get(ifname) {
+            return Some(limit.clone());
+        }
+        let min_burst = (tunnel.max_datagram + tunnel.overhead()) as u64;
+        let limit = Arc::new(RateLimiter::new(i.max_bps, i.max_pps, i.burst, min_burst)?);
+        limits.insert(ifname.to_string(), limit.clone());
+        Some(limit)
+    });
+    let transport = if_cfg.map(|i| i.transport).unwrap_or_default();
+    let routine = SendingRoutine {
+        ifname: ifname.to_string(),
+        src_sock: src_sock.clone(),
+        sender: Arc::new(Sender::new(tunnel.batch_io)),
+        queue: Arc::new(SendQueue::new(tunnel.send_queue)),
+        rate_limit,
+        src_addr: source_addr,
+        dst: dst.to_string(),
+        dst_addr: Arc::new(Mutex::new(dst_addr)),
+        tcp: Arc::new(Mutex::new(None)),
+        tcp_only: Arc::new(AtomicBool::new(transport == Transport::Tcp)),
+        authenticated: tunnel
+            .psk
+            .as_ref()
+            .map(|_| Arc::new(AtomicBool::new(false))),
+        weight: if_cfg.and_then(|i| i.weight).unwrap_or(1),
+        priority: if_cfg.and_then(|i| i.priority).unwrap_or(DEFAULT_PRIORITY),
+        last_rec: Arc::new(Mutex::new(Instant::now())),
+        bytes_total: Arc::new(Mutex::new(0)),
+        last_traffic_check: Arc::new(Mutex::new(Instant::now())),
+        last_traffic_total: Arc::new(Mutex::new(0)),
+        frames_rx: Arc::new(Mutex::new(0)),
+        last_loss_check: Arc::new(Mutex::new((
+            0,
+            tunnel
+                .framing
+                .as_ref()
+                .map(|f| f.unique.load(Ordering::Relaxed))
+                .unwrap_or(0),
+        ))),
+        probe: Arc::new(Mutex::new(ProbeStats::new())),
+        path_mtu: Arc::new(Mutex::new(None)),
+        quarantine: Arc::new(Mutex::new(None)),
+        metrics,
+        is_closing: Arc::new(Mutex::new(false)),
+    };
+    let routine_clone = routine.clone();
+    let ifname_owned = ifname.to_string();
+    let tunnel_clone = tunnel.clone();
+    tokio::spawn(async move {
+        wg_write_back(&ifname_owned, routine_clone, tunnel_clone).await;
+    });
+    tokio::spawn(send_queued(routine.clone(), config.clone()));
+    if let Some(authenticated) = &routine.authenticated {
+        tokio::spawn(authenticate_path(
+            routine.clone(),
+            authenticated.clone(),
+            config.clone(),
+        ));
+    }
+    if transport != Transport::Udp {
+        tokio::spawn(run_tcp(
+            routine.clone(),
+            tunnel.clone(),
+            config.clone(),
+            transport,
+        ));
+    }
+    if let Some(interval) = cfg.probe_interval {
+        let routine_clone = routine.clone();
+        let ifname_owned = ifname.to_string();
+        let interval = Duration::from_millis(interval.max(10));
+        tokio::spawn(async move {
+            probe_path(&ifname_owned, routine_clone, interval).await;
+        });
+    }
+    if let Some(interval) = cfg.mtu_probe_interval {
+        // Socket separato: i probe con il bit DF non cambiano il socket dei dati
+        if let Some(sock) = create_udp_socket(ifname, source_addr, cfg) {
+            let routine_clone = routine.clone();
+            let ifname_owned = ifname.to_string();
+            let high = tunnel.max_datagram + tunnel.overhead();
+            let interval = Duration::from_secs(interval.max(10));
+            let psk = tunnel.psk.clone();
+            tokio::spawn(async move {
+                discover_path_mtu(&ifname_owned, routine_clone, sock, high, interval, psk).await;
+            });
+        }
+    }
+    // Se il path esisteva già (reload di interfaces) la routine vecchia viene
+    // chiusa solo dopo che la nuova ha preso il suo posto
+    let old = tunnel
+        .sending_channels
+        .lock()
+        .unwrap()
+        .insert(path_key(ifname, dst), routine);
+    close_routine(old);
+}
+
+async fn wg_write_back(ifname: &str, routine: SendingRoutine, tunnel: Tunnel) {
+    let mut receiver = Receiver::new(tunnel.max_datagram + tunnel.overhead(), 0, tunnel.batch_io);
+    // La FEC tiene gli ultimi pacchetti ricevuti: con GRO ognuno terrebbe
+    // occupato un buffer da 64KB
+    if tunnel.fec.is_none() {
+        receiver.enable_gro(&routine.src_sock);
+    }
+    // I datagram letti passano a Wireguard e alla FEC senza copiarli
+    let mut pool = BufferPool::new(receiver.buf_len());
+    loop {
+        // La lettura ha una scadenza per poter controllare is_closing
+        let res = time::timeout(Duration::from_secs(1), receiver.recv(&routine.src_sock)).await;
+        if *routine.is_closing.lock().unwrap() {
+            break;
+        }
+        match res {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Error reading from interface {}: {}", ifname, e);
+                break;
+            }
+            Err(_) => continue,
+        }
+        // I pacchetti per Wireguard partono insieme alla fine del blocco
+        let mut out = ToWireguard {
+            packets: Vec::with_capacity(receiver.count()),
+            recovered: Vec::new(),
+        };
+        let dst_addr = routine.dst_addr();
+        for (packet, src_addr, truncated) in receiver.take_all(&mut pool) {
+            if src_addr != dst_addr {
+                warn!(
+                    "Ignoring packet on interface {} from unexpected source {}",
+                    ifname, src_addr
+                );
+                continue;
+            }
+            handle_path_packet(&routine, &tunnel, packet, truncated, &mut out).await;
+        }
+        out.flush(&tunnel).await;
+    }
+    // Qui potresti rimuovere la routine dalla mappa se necessario
+}
+
+// I datagram del server per Wireguard e i pacchetti ricostruiti dalla FEC,
+// scritti insieme alla fine di ogni blocco
+#[derive(Default)]
+struct ToWireguard {
+    packets: Vec<Packet>,
+    recovered: Vec<(Header, Packet)>,
+}
+
+impl ToWireguard {
+    async fn flush(self, tunnel: &Tunnel) {
+        write_to_wireguard(tunnel, &self.packets).await;
+        forward_recovered(tunnel, self.recovered).await;
+    }
+}
+
+// Un datagram ricevuto dal server sul path, via UDP o TCP: risponde ai probe e
+// mette i dati in out
+async fn handle_path_packet(
+    routine: &SendingRoutine,
+    tunnel: &Tunnel,
+    packet: Packet,
+    truncated: bool,
+    out: &mut ToWireguard,
+) {
+    let n = packet.len();
+    *routine.last_rec.lock().unwrap() = Instant::now();
+    routine.metrics.counters.add_rx(n);
+    if truncated {
+        routine
+            .metrics
+            .counters
+            .truncated
+            .fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Dropping truncated datagram on interface {}, larger than maxDatagramSize {}",
+            routine.ifname, tunnel.max_datagram
+        );
+        return;
+    }
+    let parsed = Header::parse(&packet);
+    // I probe restano tra client e server, non vanno a Wireguard
+    match parsed {
+        Some((header, _)) if header.kind == FrameType::Probe => {
+            let reply = reply_for(&header);
+            if let Err(e) = routine.send(&[reply]).await {
+                warn!(
+                    "Error replying to probe on interface {}: {}",
+                    routine.ifname, e
+                );
+            }
+            return;
+        }
+        Some((header, _)) if header.kind == FrameType::ProbeReply => {
+            routine
+                .probe
+                .lock()
+                .unwrap()
+                .on_reply(&header, Instant::now());
+            return;
+        }
+        Some((header, _)) if header.kind == FrameType::MtuProbe => {
+            let reply = mtu_reply_for(&header, n);
+            if let Err(e) = routine.send(&[reply]).await {
+                warn!(
+                    "Error replying to MTU probe on interface {}: {}",
+                    routine.ifname, e
+                );
+            }
+            return;
+        }
+        Some((header, cookie)) if header.kind == FrameType::AuthChallenge => {
+            if let Some(psk) = tunnel.psk.as_ref().filter(|_| cookie.len() == COOKIE_LEN) {
+                let body = [cookie, &auth::tag(psk, cookie)].concat();
+                let response = auth::frame(FrameType::AuthResponse, &body);
+                if let Err(e) = routine.send(&[response]).await {
+                    warn!(
+                        "Error answering authentication on interface {}: {}",
+                        routine.ifname, e
+                    );
+                }
+            }
+            return;
+        }
+        Some((header, _)) if header.kind == FrameType::AuthOk => {
+            if let Some(authenticated) = &routine.authenticated {
+                if !authenticated.swap(true, Ordering::Relaxed) {
+                    info!(
+                        "Path {} authenticated",
+                        path_key(&routine.ifname, &routine.dst)
+                    );
+                }
+            }
+            return;
+        }
+        Some((header, _))
+            if matches!(
+                header.kind,
+                FrameType::MtuProbeReply | FrameType::AuthHello | FrameType::AuthResponse
+            ) =>
+        {
+            return
+        }
+        _ => {}
+    }
+    *routine.bytes_total.lock().unwrap() += n as u64;
+    let payload = match &tunnel.framing {
+        Some(framing) => match parsed {
+            Some((header, body)) if header.kind == FrameType::Parity => {
+                match &tunnel.fec {
+                    Some(fec) => out.recovered.extend(fec.on_parity(&header, body)),
+                    None => {
+                        framing.invalid.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            "Dropping FEC parity frame on interface {}, is fec enabled on the client?",
+                            routine.ifname
+                        );
+                    }
+                }
+                return;
+            }
+            Some((header, _)) => {
+                *routine.frames_rx.lock().unwrap() += 1;
+                if !framing.accept(&header) {
+                    return;
+                }
+                let payload = packet.slice(HEADER_LEN..n);
+                if let Some(fec) = &tunnel.fec {
+                    out.recovered.extend(fec.on_data(&header, &payload));
+                }
+                payload
+            }
+            None => {
+                framing.invalid.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Dropping unframed packet on interface {}, is framing enabled on the server?",
+                    routine.ifname
+                );
+                return;
+            }
+        },
+        None => packet,
+    };
+    out.packets.push(payload);
+}
+
+async fn write_to_wireguard(tunnel: &Tunnel, packets: &[Packet]) {
+    if packets.is_empty() {
+        return;
+    }
+    if let Some(addr) = *tunnel.wg_addr.read().await {
+        if let Err(e) = tunnel.wg_sender.send(&tunnel.wg_sock, packets, addr).await {
+            warn!("Error writing to WireGuard: {}", e);
+        }
+    }
+}
+
+// Inoltra a Wireguard i pacchetti ricostruiti dalla FEC che non sono già arrivati
+async fn forward_recovered(tunnel: &Tunnel, recovered: Vec<(Header, Packet)>) {
+    let framing = match &tunnel.framing {
+        Some(framing) => framing,
+        None => return,
+    };
+    let packets: Vec<Packet> = recovered
+        .into_iter()
+        .filter(|(header, _)| framing.accept(header))
+        .map(|(_, payload)| payload)
+        .collect();
+    write_to_wireguard(tunnel, &packets).await;
+}
+
+// Ripete la ricerca del path MTU ogni interval, finché la routine è attiva
+async fn discover_path_mtu(
+    ifname: &str,
+    routine: SendingRoutine,
+    sock: Arc<UdpSocket>,
+    high: usize,
+    interval: Duration,
+    psk: Option<Arc<[u8]>>,
+) {
+    loop {
+        let dst = routine.dst_addr();
+        let low = pmtu::min_payload(dst.is_ipv6()).min(high);
+        // Su TCP il path MTU non conta, i datagram non vengono frammentati
+        let mtu = if routine.tcp_only.load(Ordering::Relaxed) {
+            Ok(None)
         } else {
-            false
+            pmtu::discover(&sock, dst, low, high, psk.as_deref()).await
+        };
+        let mtu = match mtu {
+            Ok(mtu) => mtu,
+            Err(e) => {
+                warn!("MTU probe failed on interface {} to {}: {}", ifname, dst, e);
+                None
+            }
+        };
+        if *routine.is_closing.lock().unwrap() {
+            break;
+        }
+        let previous = std::mem::replace(&mut *routine.path_mtu.lock().unwrap(), mtu);
+        if previous != mtu {
+            match mtu {
+                Some(mtu) => info!("Path MTU on interface {} to {}: {}", ifname, dst, mtu),
+                None => warn!("No answer to MTU probes on interface {} to {}", ifname, dst),
+            }
+        }
+        let next = Instant::now() + interval;
+        while Instant::now() < next {
+            time::sleep(Duration::from_secs(1)).await;
+            if *routine.is_closing.lock().unwrap() {
+                return;
+            }
         }
     }
 }
 
-fn is_excluded(name: &str, excl: &[String]) -> bool {
-    for ex in excl {
-        if ex == name {
-            return !is_swapped(name);
+async fn probe_path(ifname: &str, routine: SendingRoutine, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if *routine.is_closing.lock().unwrap() {
+            break;
+        }
+        // Il server scarta i probe dei path che non ha ancora accettato
+        if !routine.authenticated() {
+            continue;
+        }
+        let packet = routine.probe.lock().unwrap().next_probe(Instant::now());
+        match routine.send(&[packet]).await {
+            // Il path TCP si sta riconnettendo
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {}
+            Err(e) => warn!("Error sending probe on interface {}: {}", ifname, e),
+            Ok(()) => {}
+        }
+    }
+}
+
+//
+// Trasporto TCP
+//
+
+// Con transport auto, da quanto il path non deve ricevere nulla per passare a TCP
+const DEFAULT_TCP_FALLBACK: u64 = 10000;
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Attesa prima di riconnettere un path TCP
+const TCP_RETRY: Duration = Duration::from_secs(5);
+
+// Tiene aperta la connessione TCP del path finché la routine è attiva. Con
+// transport auto il path passa a TCP solo dopo tcpFallback millisecondi senza
+// ricevere nulla su UDP.
+async fn run_tcp(
+    routine: SendingRoutine,
+    tunnel: Tunnel,
+    config: SharedConfig,
+    transport: Transport,
+) {
+    let key = path_key(&routine.ifname, &routine.dst);
+    if transport == Transport::Auto {
+        loop {
+            time::sleep(Duration::from_secs(1)).await;
+            if *routine.is_closing.lock().unwrap() {
+                return;
+            }
+            let fallback = config
+                .lock()
+                .unwrap()
+                .tcp_fallback
+                .unwrap_or(DEFAULT_TCP_FALLBACK);
+            let silent = routine.last_rec.lock().unwrap().elapsed();
+            if silent >= Duration::from_millis(fallback) {
+                warn!(
+                    "Nothing received on path {} for {:.1}s, switching to TCP",
+                    key,
+                    silent.as_secs_f64()
+                );
+                routine.tcp_only.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+    }
+    while !*routine.is_closing.lock().unwrap() {
+        let cfg = config.lock().unwrap().clone();
+        let dst = routine.dst_addr();
+        let server = SocketAddr::new(dst.ip(), cfg.tcp_port.unwrap_or(dst.port()));
+        match connect_tcp(&routine.ifname, routine.src_addr, server, &cfg).await {
+            Ok(stream) => {
+                info!("Path {} connected over TCP to {}", key, server);
+                let (reader, writer) = stream.into_split();
+                *routine.tcp.lock().unwrap() = Some(Arc::new(TcpLink::new(writer)));
+                routine.reauthenticate();
+                // La lettura non ha una scadenza, annullarla a metà di un
+                // datagram renderebbe la connessione illeggibile: si controlla
+                // is_closing qui e si interrompe il task
+                let mut reading =
+                    tokio::spawn(tcp_read_back(routine.clone(), tunnel.clone(), reader));
+                let error = loop {
+                    match time::timeout(Duration::from_secs(1), &mut reading).await {
+                        Ok(res) => break res.ok(),
+                        Err(_) if *routine.is_closing.lock().unwrap() => {
+                            reading.abort();
+                            break None;
+                        }
+                        Err(_) => {}
+                    }
+                };
+                *routine.tcp.lock().unwrap() = None;
+                if let Some(e) = error {
+                    warn!("TCP connection of path {} closed: {}", key, e);
+                }
+            }
+            Err(e) => warn!("Cannot connect path {} over TCP to {}: {}", key, server, e),
+        }
+        let next = Instant::now() + TCP_RETRY;
+        while Instant::now() < next && !*routine.is_closing.lock().unwrap() {
+            time::sleep(Duration::from_secs(1)).await;
         }
     }
-    is_swapped(name)
 }
 
-fn swap_exclusion(ifname: &str) {
-    unsafe {
-        if let Some(ref m) = EXCLUSION_SWAPS {
-            let mut swaps = m.lock().unwrap();
-            if swaps.get(ifname).copied().unwrap_or(false) {
-                swaps.remove(ifname);
-            } else {
-                swaps.insert(ifname.to_string(), true);
-            }
-        }
-    }
+// Connessione TCP dall'indirizzo del path, con le stesse opzioni dei socket UDP
+async fn connect_tcp(
+    ifname: &str,
+    source_addr: IpAddr,
+    server: SocketAddr,
+    cfg: &ClientConfig,
+) -> std::io::Result<TcpStream> {
+    let socket = path_socket(
+        ifname,
+        SocketAddr::new(source_addr, 0),
+        socket2::Type::STREAM,
+        socket2::Protocol::TCP,
+        cfg,
+    )?;
+    socket.set_nodelay(true)?;
+    let socket = TcpSocket::from_std_stream(socket.into());
+    time::timeout(TCP_CONNECT_TIMEOUT, socket.connect(server))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
 }
 
-fn reset_exclusions() {
-    unsafe {
-        if let Some(ref m) = EXCLUSION_SWAPS {
-            let mut swaps = m.lock().unwrap();
-            swaps.clear();
-        }
+// Legge i datagram del server dalla connessione TCP del path, fino a un errore
+async fn tcp_read_back(
+    routine: SendingRoutine,
+    tunnel: Tunnel,
+    reader: OwnedReadHalf,
+) -> std::io::Error {
+    let mut reader = tokio::io::BufReader::new(reader);
+    // Come per UDP i datagram letti passano a Wireguard e alla FEC senza copiarli
+    let mut pool = BufferPool::new(tunnel.max_datagram + tunnel.overhead());
+    let mut buf = pool.buffer();
+    loop {
+        let n = match read_datagram(&mut reader, &mut buf).await {
+            Ok(n) => n,
+            Err(e) => return e,
+        };
+        let packet = pool.wrap(&mut buf, 0..n);
+        let mut out = ToWireguard::default();
+        handle_path_packet(&routine, &tunnel, packet, false, &mut out).await;
+        out.flush(&tunnel).await;
     }
 }
 
 //
-// Funzioni per le interfacce
+// Autenticazione dei path (psk)
 //
 
-fn get_address_by_interface(ifname: &str) -> Option<String> {
-    if let Ok(ifaces) = get_if_addrs() {
-        for iface in ifaces {
-            if iface.name == ifname {
-                if let std::net::IpAddr::V4(ipv4) = iface.ip() {
-                    let ip_str = ipv4.to_string();
-                    if ip_str.starts_with("169.254.") || ip_str.starts_with("127.") {
-                        continue;
-                    }
-                    return Some(ip_str);
-                }
+// Un path autenticato ripete lo scambio se non riceve nulla da authRefresh,
+// ad esempio perché il server è ripartito o l'ha rimosso per clientTimeout
+const DEFAULT_AUTH_REFRESH: u64 = 5000;
+// Richieste senza risposta prima di avvisare
+const AUTH_WARN_AFTER: u32 = 5;
+
+// Chiede al server di accettare il path, finché la routine è attiva
+async fn authenticate_path(
+    routine: SendingRoutine,
+    authenticated: Arc<AtomicBool>,
+    config: SharedConfig,
+) {
+    let key = path_key(&routine.ifname, &routine.dst);
+    // La richiesta è grande quanto la sfida del server
+    let hello = auth::frame(FrameType::AuthHello, &[0; COOKIE_LEN]);
+    let mut attempts = 0;
+    while !*routine.is_closing.lock().unwrap() {
+        let refresh = Duration::from_millis(
+            config
+                .lock()
+                .unwrap()
+                .auth_refresh
+                .unwrap_or(DEFAULT_AUTH_REFRESH),
+        );
+        let stale = routine.last_rec.lock().unwrap().elapsed() >= refresh;
+        if authenticated.load(Ordering::Relaxed) {
+            attempts = 0;
+        } else {
+            attempts += 1;
+            if attempts == AUTH_WARN_AFTER {
+                warn!(
+                    "Path {} not accepted by the server yet, is the same psk set on the server?",
+                    key
+                );
             }
         }
+        if attempts > 0 || stale {
+            match routine.send(&[&hello]).await {
+                // Il path TCP si sta riconnettendo
+                Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {}
+                Err(e) => warn!(
+                    "Error sending authentication on interface {}: {}",
+                    routine.ifname, e
+                ),
+                Ok(()) => {}
+            }
+        }
+        time::sleep(refresh.min(Duration::from_secs(1))).await;
     }
-    None
 }
 
-fn get_dst_by_ifname(ifname: &str, cfg: &ClientConfig) -> String {
-    for ov in &cfg.dst_overrides {
-        if ov.if_name == ifname {
-            return ov.dst_addr.clone();
-        }
+fn close_routine(routine: Option<SendingRoutine>) {
+    if let Some(routine) = routine {
+        routine.metrics.downs.fetch_add(1, Ordering::Relaxed);
+        *routine.is_closing.lock().unwrap() = true;
+        routine.queue.close();
     }
-    cfg.dst_addr.clone()
 }
 
-fn interface_exists(ifname: &str) -> bool {
-    if let Ok(ifaces) = get_if_addrs() {
-        for iface in ifaces {
-            if iface.name == ifname {
-                return true;
-            }
+// Mette il path in quarantena o lo fa uscire, secondo healthPolicy
+fn update_quarantine(routine: &SendingRoutine, health: &HealthPolicy, now: Instant) {
+    let gap = now.duration_since(*routine.last_rec.lock().unwrap());
+    let loss = routine.probe.lock().unwrap().loss(now);
+    let reason = health.quarantine_reason(gap, loss);
+    let mut quarantine = routine.quarantine.lock().unwrap();
+    match (reason, quarantine.as_mut()) {
+        (Some(reason), Some(q)) => {
+            q.last_bad = now;
+            q.reason = reason;
+        }
+        (Some(reason), None) => {
+            warn!(
+                "Path {} quarantined: {}",
+                path_key(&routine.ifname, &routine.dst),
+                reason
+            );
+            *quarantine = Some(Quarantine {
+                last_bad: now,
+                reason,
+            });
+        }
+        (None, Some(q)) if now.duration_since(q.last_bad) >= health.quarantine_time() => {
+            info!(
+                "Path {} leaves quarantine",
+                path_key(&routine.ifname, &routine.dst)
+            );
+            *quarantine = None;
         }
+        _ => {}
     }
-    false
-}
-
-fn read_interface_mtu(ifname: &str) -> Option<u32> {
-    let path = format!("/sys/class/net/{}/mtu", ifname);
-    let value = std::fs::read_to_string(path).ok()?;
-    value.trim().parse().ok()
 }
 
 //
-// Socket UDP
+// Destinazioni con un nome
 //
 
-async fn create_udp_socket(source_addr: &str) -> Option<Arc<UdpSocket>> {
-    let bind_addr = format!("{}:0", source_addr);
-    match UdpSocket::bind(&bind_addr).await {
-        Ok(sock) => Some(Arc::new(sock)),
-        Err(e) => {
-            warn!("Cannot create socket on {}: {}", bind_addr, e);
-            None
-        }
-    }
-}
+// Senza dnsRefresh si segue il TTL, ma non più spesso di così
+const MIN_DNS_REFRESH: Duration = Duration::from_secs(5);
+// Senza TTL (resolver di sistema)
+const DEFAULT_DNS_REFRESH: Duration = Duration::from_secs(60);
+// Nuovo tentativo dopo una risoluzione fallita
+const DNS_RETRY: Duration = Duration::from_secs(5);
 
-//
-// Routine per ciascuna interfaccia
-//
+struct Resolution {
+    addr: Option<SocketAddr>,
+    // Quando risolvere di nuovo
+    refresh: Instant,
+    running: bool,
+    failed: bool,
+}
 
-async fn create_send_thread(
+// L'indirizzo della destinazione del path. Per un nome è l'ultimo risolto (None
+// finché non ce n'è uno) e, quando è ora, parte una nuova risoluzione.
+fn resolve_dst(
     ifname: &str,
-    source_addr: &str,
-    wg_sock: Arc<UdpSocket>,
-    wg_addr: Arc<RwLock<Option<SocketAddr>>>,
-    sending_channels: SendingChannels,
+    dst: &str,
+    tunnel: &Tunnel,
     cfg: &ClientConfig,
-) {
-    let dst_str = get_dst_by_ifname(ifname, cfg);
-    let dst_addr: SocketAddr = match dst_str.parse() {
-        Ok(addr) => addr,
-        Err(e) => {
-            warn!("Cannot resolve destination address {}: {}", dst_str, e);
-            return;
-        }
-    };
-    let src_sock = match create_udp_socket(source_addr).await {
-        Some(s) => s,
-        None => return,
-    };
-    let routine = SendingRoutine {
-        src_sock: src_sock.clone(),
-        src_addr: source_addr.to_string(),
-        dst_addr,
-        last_rec: Arc::new(Mutex::new(Instant::now())),
-        bytes_total: Arc::new(Mutex::new(0)),
-        last_traffic_check: Arc::new(Mutex::new(Instant::now())),
-        last_traffic_total: Arc::new(Mutex::new(0)),
-        is_closing: Arc::new(Mutex::new(false)),
-    };
-    let routine_clone = routine.clone();
-    let ifname_owned = ifname.to_string();
-    tokio::spawn(async move {
-        wg_write_back(&ifname_owned, routine_clone, wg_sock, wg_addr).await;
+    now: Instant,
+) -> Option<SocketAddr> {
+    if let Ok(addr) = dst.parse() {
+        return Some(addr);
+    }
+    let (host, port) = dns::split_host_port(dst)?;
+    let key = path_key(ifname, dst);
+    let mut resolved = tunnel.resolved.lock().unwrap();
+    let entry = resolved.entry(key.clone()).or_insert(Resolution {
+        addr: None,
+        refresh: now,
+        running: false,
+        failed: false,
     });
-    sending_channels
-        .lock()
-        .unwrap()
-        .insert(ifname.to_string(), routine);
+    if !entry.running && entry.refresh <= now {
+        entry.running = true;
+        tokio::spawn(resolve_path_dst(
+            ifname.to_string(),
+            host.to_string(),
+            port,
+            key,
+            tunnel.clone(),
+            cfg.clone(),
+        ));
+    }
+    entry.addr
 }
 
-async fn wg_write_back(
-    ifname: &str,
-    routine: SendingRoutine,
-    wg_sock: Arc<UdpSocket>,
-    wg_addr: Arc<RwLock<Option<SocketAddr>>>,
+// Risolve il nome attraverso l'interfaccia del path, prima IPv4 e poi IPv6
+async fn resolve_path_dst(
+    ifname: String,
+    host: String,
+    port: u16,
+    key: String,
+    tunnel: Tunnel,
+    cfg: ClientConfig,
 ) {
-    let mut buf = vec![0u8; 1500];
-    loop {
-        let (n, src_addr) = match routine.src_sock.recv_from(&mut buf).await {
-            Ok(res) => res,
-            Err(e) => {
-                warn!("Error reading from interface {}: {}", ifname, e);
-                break;
-            }
+    let mut answer = None;
+    for ipv6 in [false, true] {
+        let Some(ip) = get_address_by_interface(&ifname, ipv6, &cfg) else {
+            continue;
         };
-        if src_addr != routine.dst_addr {
+        let sock = create_udp_socket(&ifname, ip, &cfg);
+        answer = dns::resolve(&host, ipv6, sock.as_deref()).await;
+        if answer.is_some() {
+            break;
+        }
+    }
+    let now = Instant::now();
+    let mut resolved = tunnel.resolved.lock().unwrap();
+    // Il path non serve più
+    let Some(entry) = resolved.get_mut(&key) else {
+        return;
+    };
+    entry.running = false;
+    let Some(answer) = answer else {
+        if !entry.failed {
+            warn!("Cannot resolve {} on interface '{}'", host, ifname);
+        }
+        entry.failed = true;
+        entry.refresh = now + DNS_RETRY;
+        return;
+    };
+    // Con più indirizzi si resta su quello in uso, se c'è ancora
+    let addr = entry
+        .addr
+        .filter(|addr| answer.addrs.contains(&addr.ip()))
+        .unwrap_or(SocketAddr::new(answer.addrs[0], port));
+    if entry.addr != Some(addr) || entry.failed {
+        if answer.ttl.is_some() {
+            info!("{} on interface '{}' resolved to {}", host, ifname, addr);
+        } else {
+            // Nessun nameserver raggiungibile dall'interfaccia
             warn!(
-                "Ignoring packet on interface {} from unexpected source {}",
-                ifname, src_addr
+                "{} on interface '{}' resolved to {} by the system resolver, not through the interface",
+                host, ifname, addr
             );
-            continue;
-        }
-        *routine.last_rec.lock().unwrap() = Instant::now();
-        *routine.bytes_total.lock().unwrap() += n as u64;
-        if let Some(addr) = *wg_addr.read().await {
-            if let Err(e) = wg_sock.send_to(&buf[..n], addr).await {
-                warn!("Error writing to WireGuard: {}", e);
-            }
         }
     }
-    // Qui potresti rimuovere la routine dalla mappa se necessario
+    entry.addr = Some(addr);
+    entry.failed = false;
+    entry.refresh = now
+        + match cfg.dns_refresh {
+            Some(secs) => Duration::from_secs(secs),
+            None => answer
+                .ttl
+                .map_or(DEFAULT_DNS_REFRESH, |ttl| ttl.max(MIN_DNS_REFRESH)),
+        };
+}
+
+// Aggiorna l'indirizzo della destinazione se il nome ora è risolto in un
+// altro. false se è cambiata la famiglia: la routine va ricreata.
+fn update_dst(routine: &SendingRoutine, addr: Option<SocketAddr>) -> bool {
+    let Some(addr) = addr else {
+        return true;
+    };
+    let mut current = routine.dst_addr.lock().unwrap();
+    if addr == *current {
+        return true;
+    }
+    if addr.is_ipv6() != current.is_ipv6() {
+        return false;
+    }
+    info!(
+        "Destination {} of interface '{}' moved from {} to {}",
+        routine.dst, routine.ifname, *current, addr
+    );
+    *current = addr;
+    routine.reauthenticate();
+    true
 }
 
 async fn update_available_interfaces(
-    wg_sock: Arc<UdpSocket>,
-    wg_addr: Arc<RwLock<Option<SocketAddr>>>,
-    sending_channels: SendingChannels,
-    cfg: ClientConfig,
+    tunnel: Tunnel,
+    config: SharedConfig,
+    policy: Arc<InterfacePolicy>,
 ) {
+    // Le interfacce presenti all'avvio vengono usate subito, senza minUpTime,
+    // anche se la destinazione viene risolta più tardi
+    let mut first_scan = true;
+    let mut unresolved_at_start = HashSet::new();
     loop {
+        let cfg = config.lock().unwrap().clone();
+        let health = &cfg.health_policy;
+        let now = Instant::now();
         let ifaces = get_if_addrs().unwrap_or_default();
+        // Path di cui serve la risoluzione della destinazione
+        let mut wanted = HashSet::new();
         {
-            let mut channels = sending_channels.lock().unwrap();
+            let mut channels = tunnel.sending_channels.lock().unwrap();
+            let mut damping = tunnel.damping.lock().unwrap();
             let keys: Vec<String> = channels.keys().cloned().collect();
-            for ifname in keys {
-                if !interface_exists(&ifname) || is_excluded(&ifname, &cfg.excluded_interfaces) {
+            for key in keys {
+                let routine = channels.get(&key).unwrap();
+                let ifname = routine.ifname.clone();
+                if policy.is_excluded(&ifname) {
+                    info!("Interface '{}' excluded, removing routine", ifname);
+                    damping.clear_down(&key);
+                    close_routine(channels.remove(&key));
+                    continue;
+                }
+                if !get_dsts_by_ifname(&ifname, &cfg).contains(&routine.dst) {
+                    info!(
+                        "Destination {} no longer configured for interface '{}', removing routine",
+                        routine.dst, ifname
+                    );
+                    damping.clear_down(&key);
+                    close_routine(channels.remove(&key));
+                    continue;
+                }
+                wanted.insert(key.clone());
+                let addr = resolve_dst(&ifname, &routine.dst, &tunnel, &cfg, now);
+                if !update_dst(routine, addr) {
                     info!(
-                        "Interface '{}' not available or excluded, removing routine",
-                        ifname
+                        "Destination {} of interface '{}' changed address family, recreating routine",
+                        routine.dst, ifname
                     );
-                    channels.remove(&ifname);
-                } else if let Some(current_ip) = get_address_by_interface(&ifname) {
-                    if current_ip != channels.get(&ifname).unwrap().src_addr {
-                        info!("Interface '{}' changed address, recreating routine", ifname);
-                        channels.remove(&ifname);
+                    damping.clear_down(&key);
+                    close_routine(channels.remove(&key));
+                    continue;
+                }
+                let ipv6 = routine.dst_addr().is_ipv6();
+                let current_ip = if interface_exists(&ifname) {
+                    get_address_by_interface(&ifname, ipv6, &cfg)
+                } else {
+                    None
+                };
+                match current_ip {
+                    Some(ip) if ip == routine.src_addr => {
+                        if damping.clear_down(&key) {
+                            info!("Interface '{}' is back towards {}", ifname, routine.dst);
+                        }
+                        update_quarantine(routine, health, now);
+                    }
+                    Some(_) => {
+                        info!(
+                            "Interface '{}' changed address, recreating routine to {}",
+                            ifname, routine.dst
+                        );
+                        damping.clear_down(&key);
+                        close_routine(channels.remove(&key));
+                    }
+                    None => {
+                        // Con removeDelay si aspetta che l'interfaccia torni
+                        let remove_delay = health.remove_delay();
+                        if damping.down_for(&key, now).is_none() && !remove_delay.is_zero() {
+                            info!(
+                                "Interface '{}' not available, removing routine to {} in {:?} unless it comes back",
+                                ifname, routine.dst, remove_delay
+                            );
+                        }
+                        if damping.down(&key, now, remove_delay) {
+                            info!("Interface '{}' not available, removing routine", ifname);
+                            damping.clear_down(&key);
+                            close_routine(channels.remove(&key));
+                        }
                     }
                 }
             }
         }
+        let mut seen = HashSet::new();
+        // Path disponibili che aspettano minUpTime
+        let mut pending = HashSet::new();
         for iface in ifaces {
             let ifname = iface.name;
-            if is_excluded(&ifname, &cfg.excluded_interfaces) {
+            if !seen.insert(ifname.clone()) {
+                continue;
+            }
+            if policy.is_excluded(&ifname) {
                 continue;
             }
-            if sending_channels.lock().unwrap().contains_key(&ifname) {
+            if get_addresses_by_interface(&ifname, &cfg).is_empty() {
                 continue;
             }
-            if let Some(ip) = get_address_by_interface(&ifname) {
-                info!("New interface '{}' with IP '{}'", ifname, ip);
-                create_send_thread(
-                    &ifname,
-                    &ip,
-                    wg_sock.clone(),
-                    wg_addr.clone(),
-                    sending_channels.clone(),
-                    &cfg,
-                )
-                .await;
+            for dst in get_dsts_by_ifname(&ifname, &cfg) {
+                let key = path_key(&ifname, &dst);
+                if tunnel.sending_channels.lock().unwrap().contains_key(&key) {
+                    continue;
+                }
+                wanted.insert(key.clone());
+                let Some(dst_addr) = resolve_dst(&ifname, &dst, &tunnel, &cfg, now) else {
+                    if first_scan {
+                        unresolved_at_start.insert(key);
+                    }
+                    continue;
+                };
+                // La sorgente deve essere della stessa famiglia della destinazione
+                if let Some(ip) = get_address_by_interface(&ifname, dst_addr.is_ipv6(), &cfg) {
+                    if !first_scan && !unresolved_at_start.contains(&key) {
+                        let mut damping = tunnel.damping.lock().unwrap();
+                        let min_up = health.min_up_time();
+                        if damping.pending(&key, now).is_none() && !min_up.is_zero() {
+                            info!(
+                                "Interface '{}' is up, waiting {:?} before using it towards {}",
+                                ifname, min_up, dst_addr
+                            );
+                        }
+                        if !damping.up(&key, now, min_up) {
+                            pending.insert(key);
+                            continue;
+                        }
+                    }
+                    info!(
+                        "New interface '{}' with IP '{}' towards {}",
+                        ifname, ip, dst_addr
+                    );
+                    unresolved_at_start.remove(&key);
+                    create_send_thread(&ifname, ip, &dst, dst_addr, &tunnel, &config, &cfg).await;
+                }
             }
         }
+        tunnel
+            .resolved
+            .lock()
+            .unwrap()
+            .retain(|key, _| wanted.contains(key));
+        tunnel
+            .damping
+            .lock()
+            .unwrap()
+            .retain_pending(|key| pending.contains(key));
+        first_scan = false;
         time::sleep(Duration::from_secs(1)).await;
     }
 }
 
+// Invia i pacchetti in coda sul path, finché la routine non viene rimossa
+async fn send_queued(routine: SendingRoutine, config: SharedConfig) {
+    let mut packets = Vec::with_capacity(BATCH_SIZE);
+    while routine.queue.pop(&mut packets, BATCH_SIZE).await {
+        let counters = &routine.metrics.counters;
+        let write_timeout =
+            Duration::from_millis(config.lock().unwrap().write_timeout.unwrap_or(10));
+        match time::timeout(write_timeout, routine.send(&packets)).await {
+            Ok(Ok(_)) => {
+                for packet in &packets {
+                    *routine.bytes_total.lock().unwrap() += packet.len() as u64;
+                    counters.add_tx(packet.len());
+                }
+            }
+            Ok(Err(e)) => {
+                counters.send_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("Error writing to {}: {}", routine.ifname, e);
+            }
+            Err(_) => {
+                counters.write_timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!("Timeout writing to {}", routine.ifname);
+            }
+        }
+        packets.clear();
+    }
+}
+
+// true se il pacchetto rientra nel limite maxBps/maxPps dell'interfaccia
+fn within_rate_limit(routine: &SendingRoutine, len: usize, now: Instant) -> bool {
+    let allowed = routine
+        .rate_limit
+        .as_ref()
+        .is_none_or(|limit| limit.allow(len, now));
+    if !allowed {
+        routine
+            .metrics
+            .counters
+            .rate_limit_drops
+            .fetch_add(1, Ordering::Relaxed);
+    }
+    allowed
+}
+
+// Mette il pacchetto nelle code dei path selezionati. L'ultimo lo riceve
+// senza clonarlo, così con un solo path il pacchetto torna subito al pool.
+fn fan_out(
+    routines: &[(String, SendingRoutine)],
+    selected: &[usize],
+    packet: Packet,
+    policy: DropPolicy,
+) {
+    if let Some((&last, others)) = selected.split_last() {
+        for &p in others {
+            enqueue(&routines[p].1, packet.clone(), policy);
+        }
+        enqueue(&routines[last].1, packet, policy);
+    }
+}
+
+fn enqueue(routine: &SendingRoutine, packet: Packet, policy: DropPolicy) {
+    if !routine.queue.push(packet, policy) {
+        routine
+            .metrics
+            .counters
+            .queue_drops
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Senza probe conta solo l'ultimo pacchetto ricevuto dal path
+fn is_healthy(
+    routine: &SendingRoutine,
+    now: Instant,
+    health_timeout: Duration,
+    probe_interval: Option<Duration>,
+) -> bool {
+    match probe_interval {
+        Some(interval) => {
+            let window = health_timeout.max(interval * PROBE_HEALTH_INTERVALS);
+            routine.probe.lock().unwrap().replied_within(now, window)
+        }
+        None => now.duration_since(*routine.last_rec.lock().unwrap()) < health_timeout,
+    }
+}
+
 async fn receive_from_wireguard(
-    wg_sock: Arc<UdpSocket>,
-    sending_channels: SendingChannels,
-    wg_addr: Arc<RwLock<Option<SocketAddr>>>,
-    write_timeout: Duration,
+    tunnel: Tunnel,
+    config: SharedConfig,
+    policy: Arc<InterfacePolicy>,
 ) {
-    let mut buf = vec![0u8; 1500];
+    // Con il framing il pacchetto di Wireguard viene letto dopo lo spazio per l'header
+    let offset = if tunnel.framing.is_some() {
+        HEADER_LEN
+    } else {
+        0
+    };
+    let mut receiver = Receiver::new(tunnel.max_datagram, offset, tunnel.batch_io);
+    // I pacchetti letti sono condivisi tra i path senza copiarli
+    let mut pool = BufferPool::new(receiver.buf_len());
+    let mut scheduler = Scheduler::new();
     loop {
-        let (n, src_addr) = match wg_sock.recv_from(&mut buf).await {
-            Ok(res) => res,
-            Err(e) => {
-                warn!("Error reading from WireGuard: {}", e);
+        if let Err(e) = receiver.recv(&tunnel.wg_sock).await {
+            warn!("Error reading from WireGuard: {}", e);
+            continue;
+        }
+        let (mode, health_timeout, probe_interval, drop_policy) = {
+            let cfg = config.lock().unwrap();
+            (
+                cfg.mode,
+                Duration::from_millis(cfg.health_timeout.unwrap_or(1000)),
+                cfg.probe_interval.map(Duration::from_millis),
+                cfg.drop_policy,
+            )
+        };
+        let channels_snapshot = tunnel.sending_channels.lock().unwrap().clone();
+        // I path non ancora accettati dal server (psk) non vengono usati
+        let mut routines: Vec<(String, SendingRoutine)> = channels_snapshot
+            .into_iter()
+            .filter(|(_, routine)| routine.authenticated())
+            .collect();
+        routines.sort_by(|a, b| a.0.cmp(&b.0));
+        let now = Instant::now();
+        // Le interfacce oltre la quota in standby e i path in quarantena
+        // vengono usati per ultimi
+        let reserve: Vec<bool> = routines
+            .iter()
+            .map(|(_, routine)| {
+                policy.is_standby(&routine.ifname) || routine.quarantine.lock().unwrap().is_some()
+            })
+            .collect();
+        let paths: Vec<PathInfo> = routines
+            .iter()
+            .zip(&reserve)
+            .map(|((key, routine), &reserve)| PathInfo {
+                key,
+                weight: if reserve { 0 } else { routine.weight },
+                priority: if reserve { u32::MAX } else { routine.priority },
+                healthy: is_healthy(routine, now, health_timeout, probe_interval),
+            })
+            .collect();
+        let use_reserve = !paths.iter().zip(&reserve).any(|(p, &r)| p.healthy && !r);
+        scheduler.set_paths(&paths);
+
+        let mut wg_addr = None;
+        for i in 0..receiver.count() {
+            let (_, src_addr, truncated) = receiver.get(i);
+            if truncated {
+                tunnel.wg_truncated.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Dropping truncated packet from WireGuard, larger than maxDatagramSize {}",
+                    tunnel.max_datagram
+                );
                 continue;
             }
-        };
-        {
-            let mut wg_addr_lock = wg_addr.write().await;
-            *wg_addr_lock = Some(src_addr);
-        }
-        let channels_snapshot = sending_channels.lock().unwrap().clone();
-        let sends = channels_snapshot.into_iter().map(|(ifname, routine)| {
-            let src_sock = routine.src_sock.clone();
-            let dst_addr = routine.dst_addr;
-            let bytes_total = routine.bytes_total.clone();
-            let data = buf[..n].to_vec();
-            async move {
-                let fut = src_sock.send_to(&data, dst_addr);
-                let result = tokio::time::timeout(write_timeout, fut).await;
-                if matches!(result, Ok(Ok(_))) {
-                    *bytes_total.lock().unwrap() += data.len() as u64;
+            wg_addr = Some(src_addr);
+            let packet = receiver.with_headroom_mut(i);
+            let mut parity = Vec::new();
+            if let Some(framing) = &tunnel.framing {
+                let header = framing.next_header(FrameType::Data);
+                header.write(packet);
+                // Quando un gruppo è completo la FEC aggiunge i suoi frame di parità
+                if let Some(fec) = &tunnel.fec {
+                    parity = fec.encode(&header, &packet[HEADER_LEN..]);
                 }
-                (ifname, result)
             }
-        });
-        let results = futures::future::join_all(sends).await;
-        for (ifname, result) in results {
-            match result {
-                Ok(Ok(_)) => {}
-                Ok(Err(e)) => {
-                    warn!("Error writing to {}: {}", ifname, e);
+            let packet = receiver.take(i, &mut pool);
+            // I frame di parità sono distribuiti sui path come i pacchetti di dati
+            let parity = parity.into_iter().map(Packet::from_vec);
+            for datagram in std::iter::once(packet).chain(parity) {
+                if mode == Mode::Redundant {
+                    let selected = scheduler.select_with(mode, &paths, |p| {
+                        (!reserve[p] || use_reserve)
+                            && within_rate_limit(&routines[p].1, datagram.len(), now)
+                    });
+                    fan_out(&routines, &selected, datagram, drop_policy);
+                    continue;
                 }
-                Err(_) => {
-                    warn!("Timeout writing to {}", ifname);
+                let previous = scheduler.active();
+                // Un path oltre il suo limite viene saltato, il pacchetto va al successivo
+                let selected = scheduler.select_with(mode, &paths, |p| {
+                    within_rate_limit(&routines[p].1, datagram.len(), now)
+                });
+                if mode == Mode::Failover && scheduler.active() != previous {
+                    match scheduler.active() {
+                        Some(p) => info!("Failover: sending on {}", paths[p].key),
+                        None => warn!("Failover: no healthy path, sending on all paths"),
+                    }
                 }
+                fan_out(&routines, &selected, datagram, drop_policy);
             }
         }
+        if let Some(addr) = wg_addr {
+            *tunnel.wg_addr.write().await = Some(addr);
+        }
     }
 }
 
@@ -424,75 +2316,315 @@ async fn serve_embedded_file(path: warp::path::Tail) -> Result<impl warp::Reply,
 }
 
 async fn handle_get_list(
-    sending_channels: SendingChannels,
+    tunnel: Tunnel,
     cfg: ClientConfig,
+    policy: Arc<InterfacePolicy>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let now = Instant::now();
-    let channels = sending_channels.lock().unwrap();
+    let channels = tunnel.sending_channels.lock().unwrap();
+    let damping = tunnel.damping.lock().unwrap();
     let mut interfaces = Vec::new();
-    let ifaces = get_if_addrs().unwrap_or_default();
+    let mut names: Vec<String> = get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|iface| iface.name)
+        .collect();
+    // Anche le interfacce sparite con le routine in attesa di removeDelay
+    names.extend(channels.values().map(|r| r.ifname.clone()));
     let mut seen = HashSet::new();
-    for iface in ifaces {
-        let ifname = iface.name;
+    for ifname in names {
         if !seen.insert(ifname.clone()) {
             continue;
         }
-        let address = get_address_by_interface(&ifname).unwrap_or_else(|| "".to_string());
-        let status;
-        let dst = get_dst_by_ifname(&ifname, &cfg);
-        let last;
-        let traffic_bps;
-        if is_excluded(&ifname, &cfg.excluded_interfaces) {
-            status = "excluded".to_string();
-            last = None;
-            traffic_bps = None;
-        } else if let Some(routine) = channels.get(&ifname) {
-            status = "active".to_string();
+        let excluded = policy.is_excluded(&ifname);
+        let quota_state = policy.quota(&ifname);
+        let usage = tunnel.usage.get(&ifname);
+        let quota = get_interface_config(&ifname, &cfg).and_then(|iface| iface.quota);
+        let mut routines: Vec<&SendingRoutine> =
+            channels.values().filter(|r| r.ifname == ifname).collect();
+        routines.sort_by_key(|r| r.dst_addr());
+        if excluded || routines.is_empty() {
+            let address = get_addresses_by_interface(&ifname, &cfg)
+                .first()
+                .map(|ip| ip.to_string())
+                .unwrap_or_default();
+            // Da quanto l'interfaccia aspetta minUpTime
+            let waiting = get_dsts_by_ifname(&ifname, &cfg)
+                .iter()
+                .filter_map(|dst| damping.pending(&path_key(&ifname, dst), now))
+                .max();
+            let (status, status_reason) = if quota_state == Some(QuotaAction::Exclude) {
+                ("over-quota", Some("quota reached".to_string()))
+            } else if excluded {
+                ("excluded", None)
+            } else if let Some(waiting) = waiting {
+                let min_up = cfg.health_policy.min_up_time();
+                let reason = format!(
+                    "up for {}s of {}s",
+                    waiting.as_secs(),
+                    min_up.as_secs_f64().ceil()
+                );
+                ("pending", Some(reason))
+            } else {
+                ("idle", None)
+            };
+            interfaces.push(WebInterface {
+                name: ifname.clone(),
+                status: status.to_string(),
+                status_reason,
+                sender_address: address,
+                dst_address: get_dsts_by_ifname(&ifname, &cfg).join(", "),
+                transport: None,
+                last: None,
+                traffic_bps: None,
+                loss: None,
+                rtt_ms: None,
+                jitter_ms: None,
+                probe_loss: None,
+                path_mtu: None,
+                queue_depth: None,
+                queue_drops: None,
+                rate_limit_drops: None,
+                usage_tx: usage.as_ref().map(|u| u.tx),
+                usage_rx: usage.as_ref().map(|u| u.rx),
+                cycle_start: usage.as_ref().map(|u| u.cycle_start.clone()),
+                quota,
+            });
+            continue;
+        }
+        // Una riga per ogni routine dell'interfaccia (ad esempio IPv4 e IPv6)
+        for routine in routines {
             let elapsed = now
                 .duration_since(*routine.last_rec.lock().unwrap())
                 .as_secs();
-            last = Some(elapsed);
             let total = *routine.bytes_total.lock().unwrap();
             let mut last_total = routine.last_traffic_total.lock().unwrap();
             let mut last_check = routine.last_traffic_check.lock().unwrap();
             let delta = total.saturating_sub(*last_total);
-            let elapsed_seconds = now
-                .duration_since(*last_check)
-                .as_secs_f64()
-                .max(0.5);
-            traffic_bps = Some((delta as f64 / elapsed_seconds) as u64);
+            let elapsed_seconds = now.duration_since(*last_check).as_secs_f64().max(0.5);
+            let traffic_bps = Some((delta as f64 / elapsed_seconds) as u64);
             *last_total = total;
             *last_check = now;
-        } else {
-            status = "idle".to_string();
-            last = None;
-            traffic_bps = None;
-        }
-        interfaces.push(WebInterface {
-            name: ifname,
-            status,
-            senderAddress: address,
-            dstAddress: dst,
-            last,
-            trafficBps: traffic_bps,
-        });
+            let loss = tunnel.framing.as_ref().and_then(|f| {
+                let frames = *routine.frames_rx.lock().unwrap();
+                let unique = f.unique.load(Ordering::Relaxed);
+                let mut last = routine.last_loss_check.lock().unwrap();
+                let (d_frames, d_unique) = (frames - last.0, unique - last.1);
+                *last = (frames, unique);
+                if d_unique == 0 {
+                    return None;
+                }
+                let received = (d_frames as f64 / d_unique as f64).min(1.0);
+                Some(((1.0 - received) * 1000.0).round() / 10.0)
+            });
+            let key = path_key(&ifname, &routine.dst);
+            let quarantine = routine.quarantine.lock().unwrap();
+            let (status, status_reason) = if let Some(down) = damping.down_for(&key, now) {
+                let reason = format!("interface unavailable for {}s", down.as_secs());
+                ("down", Some(reason))
+            } else if !routine.authenticated() {
+                let reason = "waiting for the server to accept the psk".to_string();
+                ("authenticating", Some(reason))
+            } else if let Some(q) = quarantine.as_ref() {
+                ("quarantined", Some(q.reason.clone()))
+            } else if quota_state == Some(QuotaAction::Standby) {
+                ("standby", Some("quota reached".to_string()))
+            } else {
+                ("active", None)
+            };
+            // Per un nome anche l'indirizzo in uso
+            let dst_addr = routine.dst_addr();
+            let dst_address = if routine.dst.parse::<SocketAddr>().is_ok() {
+                routine.dst.clone()
+            } else {
+                format!("{} ({})", routine.dst, dst_addr)
+            };
+            let transport = if routine.tcp_only.load(Ordering::Relaxed) {
+                "tcp"
+            } else {
+                "udp"
+            };
+            let probe = routine.probe.lock().unwrap();
+            interfaces.push(WebInterface {
+                name: ifname.clone(),
+                status: status.to_string(),
+                status_reason,
+                sender_address: routine.src_addr.to_string(),
+                dst_address,
+                transport: Some(transport.to_string()),
+                last: Some(elapsed),
+                traffic_bps,
+                loss,
+                rtt_ms: probe.rtt_ms(),
+                jitter_ms: probe.jitter_ms(),
+                probe_loss: probe.loss(now),
+                path_mtu: *routine.path_mtu.lock().unwrap(),
+                queue_depth: Some(routine.queue.depth()),
+                queue_drops: Some(routine.metrics.counters.queue_drops.load(Ordering::Relaxed)),
+                rate_limit_drops: routine.rate_limit.as_ref().map(|_| {
+                    routine
+                        .metrics
+                        .counters
+                        .rate_limit_drops
+                        .load(Ordering::Relaxed)
+                }),
+                usage_tx: usage.as_ref().map(|u| u.tx),
+                usage_rx: usage.as_ref().map(|u| u.rx),
+                cycle_start: usage.as_ref().map(|u| u.cycle_start.clone()),
+                quota,
+            });
+        }
     }
+    // Il pacchetto di Wireguard con l'overhead di engarde deve passare su ogni
+    // path misurato e stare in maxDatagramSize
+    let safe_wg_mtu = channels
+        .values()
+        .filter_map(|r| *r.path_mtu.lock().unwrap())
+        .min()
+        .map(|mtu| {
+            mtu.saturating_sub(tunnel.overhead())
+                .min(tunnel.max_datagram)
+                .saturating_sub(WG_OVERHEAD)
+        });
     let response = GetListResponse {
         r#type: "client".to_string(),
         version: VERSION.to_string(),
         description: cfg.description.unwrap_or_default(),
-        listenAddress: cfg.listen_addr,
+        listen_address: cfg.listen_addr,
         interfaces,
         wg_mtu: read_interface_mtu("wg0"),
+        safe_wg_mtu,
+        mode: cfg.mode,
+        fec_recovered: tunnel
+            .fec
+            .as_ref()
+            .map(|fec| fec.recovered.load(Ordering::Relaxed)),
     };
     Ok(warp::reply::json(&response))
 }
 
+// Metriche in formato testo Prometheus, una serie per ogni path mai creato
+async fn handle_metrics(tunnel: Tunnel) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = Instant::now();
+    let channels = tunnel.sending_channels.lock().unwrap().clone();
+    let mut paths: Vec<(String, Arc<PathMetrics>)> = tunnel
+        .path_metrics
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    paths.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut w = MetricsWriter::new();
+    w.family("engarde_path_up", "1 if the path is active", "gauge");
+    for (key, m) in &paths {
+        let up = if channels.contains_key(key) { 1.0 } else { 0.0 };
+        w.sample("engarde_path_up", &m.labels(), up);
+    }
+    w.family(
+        "engarde_path_last_receive_seconds",
+        "Seconds since the last packet received on the path",
+        "gauge",
+    );
+    for (key, m) in &paths {
+        if let Some(routine) = channels.get(key) {
+            let age = now
+                .duration_since(*routine.last_rec.lock().unwrap())
+                .as_secs_f64();
+            w.sample("engarde_path_last_receive_seconds", &m.labels(), age);
+        }
+    }
+    w.family(
+        "engarde_path_up_transitions_total",
+        "Times the path went up",
+        "counter",
+    );
+    for (_, m) in &paths {
+        let ups = m.ups.load(Ordering::Relaxed);
+        w.sample("engarde_path_up_transitions_total", &m.labels(), ups as f64);
+    }
+    w.family(
+        "engarde_path_down_transitions_total",
+        "Times the path went down",
+        "counter",
+    );
+    for (_, m) in &paths {
+        let downs = m.downs.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_path_down_transitions_total",
+            &m.labels(),
+            downs as f64,
+        );
+    }
+    w.family(
+        "engarde_path_mtu_bytes",
+        "Largest UDP payload that reaches the other side on the path",
+        "gauge",
+    );
+    for (key, m) in &paths {
+        if let Some(mtu) = channels.get(key).and_then(|r| *r.path_mtu.lock().unwrap()) {
+            w.sample("engarde_path_mtu_bytes", &m.labels(), mtu as f64);
+        }
+    }
+    w.family(
+        "engarde_path_queue_depth",
+        "Packets waiting in the send queue of the path",
+        "gauge",
+    );
+    for (key, m) in &paths {
+        if let Some(routine) = channels.get(key) {
+            let depth = routine.queue.depth();
+            w.sample("engarde_path_queue_depth", &m.labels(), depth as f64);
+        }
+    }
+    let values: Vec<[u64; 9]> = paths.iter().map(|(_, m)| m.counters.values()).collect();
+    for (i, (suffix, help)) in PATH_COUNTERS.iter().enumerate() {
+        let name = format!("engarde_path_{}", suffix);
+        w.family(&name, help, "counter");
+        for ((_, m), v) in paths.iter().zip(&values) {
+            w.sample(&name, &m.labels(), v[i] as f64);
+        }
+    }
+    if let Some(framing) = &tunnel.framing {
+        let values = framing_values(framing);
+        for (i, (name, help)) in FRAMING_COUNTERS.iter().enumerate() {
+            w.family(name, help, "counter");
+            w.sample(name, &[], values[i] as f64);
+        }
+    }
+    w.family(
+        "engarde_wireguard_truncated_total",
+        "WireGuard packets dropped because larger than maxDatagramSize",
+        "counter",
+    );
+    w.sample(
+        "engarde_wireguard_truncated_total",
+        &[],
+        tunnel.wg_truncated.load(Ordering::Relaxed) as f64,
+    );
+    if let Some(fec) = &tunnel.fec {
+        let values = fec_values(fec);
+        for (i, (name, help)) in FEC_COUNTERS.iter().enumerate() {
+            w.family(name, help, "counter");
+            w.sample(name, &[], values[i] as f64);
+        }
+    }
+    Ok(warp::reply::with_header(
+        w.finish(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
 async fn handle_swap_exclusion(
     body: serde_json::Value,
+    cfg: ClientConfig,
+    policy: Arc<InterfacePolicy>,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(iface) = body.get("interface").and_then(|v| v.as_str()) {
-        swap_exclusion(iface);
+        policy.swap(iface);
+        save_exclusion_state(&policy, &cfg);
         let resp = serde_json::json!({ "status": "ok" });
         Ok(warp::reply::json(&resp))
     } else {
@@ -500,16 +2632,28 @@ async fn handle_swap_exclusion(
     }
 }
 
-async fn handle_reset_exclusions() -> Result<impl warp::Reply, warp::Rejection> {
-    reset_exclusions();
+async fn handle_reset_exclusions(
+    cfg: ClientConfig,
+    policy: Arc<InterfacePolicy>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    policy.reset();
+    save_exclusion_state(&policy, &cfg);
     let resp = serde_json::json!({ "status": "ok" });
     Ok(warp::reply::json(&resp))
 }
 
-async fn handle_include(body: serde_json::Value) -> Result<impl warp::Reply, warp::Rejection> {
+async fn handle_include(
+    body: serde_json::Value,
+    cfg: ClientConfig,
+    policy: Arc<InterfacePolicy>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(iface) = body.get("interface").and_then(|v| v.as_str()) {
-        if is_swapped(iface) {
-            swap_exclusion(iface); // toggle to include
+        if policy.quota(iface) == Some(QuotaAction::Exclude) {
+            // Resta esclusa fino all'inizio del ciclo successivo
+            let resp = serde_json::json!({ "status": "over-quota" });
+            Ok(warp::reply::json(&resp))
+        } else if policy.include(iface) {
+            save_exclusion_state(&policy, &cfg);
             let resp = serde_json::json!({ "status": "ok" });
             Ok(warp::reply::json(&resp))
         } else {
@@ -521,10 +2665,14 @@ async fn handle_include(body: serde_json::Value) -> Result<impl warp::Reply, war
     }
 }
 
-async fn handle_exclude(body: serde_json::Value) -> Result<impl warp::Reply, warp::Rejection> {
+async fn handle_exclude(
+    body: serde_json::Value,
+    cfg: ClientConfig,
+    policy: Arc<InterfacePolicy>,
+) -> Result<impl warp::Reply, warp::Rejection> {
     if let Some(iface) = body.get("interface").and_then(|v| v.as_str()) {
-        if !is_swapped(iface) {
-            swap_exclusion(iface); // toggle to exclude
+        if policy.exclude(iface) {
+            save_exclusion_state(&policy, &cfg);
             let resp = serde_json::json!({ "status": "ok" });
             Ok(warp::reply::json(&resp))
         } else {
@@ -536,51 +2684,125 @@ async fn handle_exclude(body: serde_json::Value) -> Result<impl warp::Reply, war
     }
 }
 
-fn with_sending_channels(
-    sending_channels: SendingChannels,
-) -> impl Filter<Extract = (SendingChannels,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || sending_channels.clone())
+// Riporta le esclusioni cambiate dalla web UI in excludedInterfaces nel file
+// di configurazione, poi azzera le inversioni
+async fn handle_save_exclusions(
+    config: SharedConfig,
+    config_path: String,
+    policy: Arc<InterfacePolicy>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let excluded = policy.effective_exclusions();
+    if let Err(e) = write_excluded_interfaces(&config_path, &excluded) {
+        warn!("Cannot save exclusions: {}", e);
+        let resp = serde_json::json!({ "status": "error", "error": e });
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&resp),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        ));
+    }
+    info!(
+        "Saved excludedInterfaces [{}] to {}",
+        excluded.join(", "),
+        config_path
+    );
+    policy.make_static(excluded.clone());
+    let cfg = {
+        let mut current = config.lock().unwrap();
+        current.excluded_interfaces = excluded.clone();
+        current.clone()
+    };
+    save_exclusion_state(&policy, &cfg);
+    let resp = serde_json::json!({ "status": "ok", "excludedInterfaces": excluded });
+    Ok(warp::reply::with_status(
+        warp::reply::json(&resp),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+fn with_tunnel(
+    tunnel: Tunnel,
+) -> impl Filter<Extract = (Tunnel,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || tunnel.clone())
 }
 
 fn with_client_config(
-    cfg: ClientConfig,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (ClientConfig,), Error = std::convert::Infallible> + Clone {
-    warp::any().map(move || cfg.clone())
+    warp::any().map(move || config.lock().unwrap().clone())
+}
+
+fn with_policy(
+    policy: Arc<InterfacePolicy>,
+) -> impl Filter<Extract = (Arc<InterfacePolicy>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || policy.clone())
 }
 
 async fn run_webserver(
     listen_addr: &str,
-    web_cfg: WebManagerConfig,
-    sending_channels: SendingChannels,
-    cfg: ClientConfig,
+    tunnel: Tunnel,
+    config: SharedConfig,
+    config_path: String,
+    policy: Arc<InterfacePolicy>,
 ) {
     let static_route = warp::path::tail().and_then(serve_embedded_file);
+    let metrics_route = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_tunnel(tunnel.clone()))
+        .and_then(handle_metrics);
     let get_list_route = warp::path!("api" / "v1" / "get-list")
-        .and(with_sending_channels(sending_channels.clone()))
-        .and(with_client_config(cfg.clone()))
+        .and(with_tunnel(tunnel))
+        .and(with_client_config(config.clone()))
+        .and(with_policy(policy.clone()))
         .and_then(handle_get_list);
     let swap_exclusion_route = warp::path!("api" / "v1" / "swap-exclusion")
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_client_config(config.clone()))
+        .and(with_policy(policy.clone()))
         .and_then(handle_swap_exclusion);
     let reset_exclusions_route = warp::path!("api" / "v1" / "reset-exclusions")
         .and(warp::post())
+        .and(with_client_config(config.clone()))
+        .and(with_policy(policy.clone()))
         .and_then(handle_reset_exclusions);
     let include_route = warp::path!("api" / "v1" / "include")
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_client_config(config.clone()))
+        .and(with_policy(policy.clone()))
         .and_then(handle_include);
     let exclude_route = warp::path!("api" / "v1" / "exclude")
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_client_config(config.clone()))
+        .and(with_policy(policy.clone()))
         .and_then(handle_exclude);
+    let config_filter = config.clone();
+    let save_exclusions_route = warp::path!("api" / "v1" / "save-exclusions")
+        .and(warp::post())
+        .and(warp::any().map(move || config_filter.clone()))
+        .and(warp::any().map(move || config_path.clone()))
+        .and(with_policy(policy))
+        .and_then(handle_save_exclusions);
 
-    let routes = get_list_route
-        .or(swap_exclusion_route)
-        .or(reset_exclusions_route)
-        .or(include_route)
-        .or(exclude_route)
-        .or(static_route);
+    // Le credenziali vengono lette ad ogni richiesta, così valgono subito dopo un reload
+    let credentials = move || {
+        let config = config.lock().unwrap();
+        let web_conf = config.web_manager.as_ref()?;
+        Some((web_conf.username.clone(), web_conf.password.clone()))
+    };
+    let routes = web::with_auth(credentials)
+        .and(
+            get_list_route
+                .or(metrics_route)
+                .or(swap_exclusion_route)
+                .or(reset_exclusions_route)
+                .or(include_route)
+                .or(exclude_route)
+                .or(save_exclusions_route)
+                .or(static_route),
+        )
+        .recover(web::handle_rejection);
 
     info!("Webserver (management) listening on {}", listen_addr);
     warp::serve(routes)
@@ -599,23 +2821,19 @@ async fn main() {
     let config_path = std::env::args()
         .nth(1)
         .unwrap_or_else(|| "engarde.yml".to_string());
-    let config_str = std::fs::read_to_string(&config_path)
-        .unwrap_or_else(|e| panic!("Error reading {}: {}", config_path, e));
-    let config: Config =
-        serde_yaml::from_str(&config_str).unwrap_or_else(|e| panic!("Error parsing config: {}", e));
-    let cfg = config.client.clone();
-
-    if cfg.listen_addr.is_empty() {
-        panic!("No listen_addr specified");
-    }
-    if cfg.dst_addr.is_empty() {
-        panic!("No dst_addr specified");
-    }
-
-    let write_timeout = Duration::from_millis(cfg.write_timeout.unwrap_or(10));
+    let cfg = load_config(&config_path).unwrap_or_else(|e| panic!("{}", e));
 
-    unsafe {
-        EXCLUSION_SWAPS = Some(Mutex::new(HashMap::new()));
+    let policy = Arc::new(InterfacePolicy::new(cfg.excluded_interfaces.clone()));
+    if let Some(path) = &cfg.state_file {
+        let state = load_exclusion_state(path);
+        if !state.swaps.is_empty() {
+            info!(
+                "Restored exclusion changes from {}: {}",
+                path,
+                state.swaps.join(", ")
+            );
+        }
+        policy.restore(state.swaps);
     }
     let sending_channels: SendingChannels = Arc::new(Mutex::new(HashMap::new()));
 
@@ -629,28 +2847,89 @@ async fn main() {
 
     let wg_addr: Arc<RwLock<Option<SocketAddr>>> = Arc::new(RwLock::new(None));
 
+    let framing = if cfg.framing {
+        info!("Engarde framing enabled");
+        Some(Arc::new(Framing::new()))
+    } else {
+        None
+    };
+    let fec = cfg.fec.as_ref().map(|fec| {
+        info!(
+            "FEC enabled: {} parity frames every {} packets",
+            fec.parity_shards, fec.data_shards
+        );
+        Arc::new(Fec::new(fec))
+    });
+    if cfg.batch_io {
+        if cfg!(target_os = "linux") {
+            info!("Batched I/O enabled");
+        } else {
+            warn!("batchIo is only supported on Linux, using standard I/O");
+        }
+    }
+    let tunnel = Tunnel {
+        wg_sock,
+        wg_addr,
+        sending_channels,
+        framing,
+        fec,
+        path_metrics: Arc::new(Mutex::new(HashMap::new())),
+        max_datagram: cfg.max_datagram_size.unwrap_or(DEFAULT_DATAGRAM_SIZE),
+        wg_truncated: Arc::new(AtomicU64::new(0)),
+        batch_io: cfg.batch_io,
+        wg_sender: Arc::new(Sender::new(cfg.batch_io)),
+        send_queue: cfg.send_queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+        rate_limits: Arc::new(Mutex::new(HashMap::new())),
+        damping: Arc::new(Mutex::new(Damping::default())),
+        resolved: Arc::new(Mutex::new(HashMap::new())),
+        psk: cfg.psk.as_ref().map(|psk| Arc::from(psk.as_bytes())),
+        usage: Arc::new(
+            cfg.usage_file
+                .as_deref()
+                .map(load_usage)
+                .unwrap_or_default(),
+        ),
+    };
+    if cfg.usage_file.is_none() && cfg.interfaces.iter().any(|i| i.quota.is_some()) {
+        warn!(
+            "Interface quotas are set without usageFile, usage restarts from zero at every restart"
+        );
+    }
+
+    let config: SharedConfig = Arc::new(Mutex::new(cfg.clone()));
+
     if let Some(web) = cfg.web_manager.clone() {
-        let listen = web.listen_addr.clone();
-        let sending_channels_clone = sending_channels.clone();
-        let cfg_clone = cfg.clone();
+        let tunnel_clone = tunnel.clone();
+        let config_clone = config.clone();
+        let config_path = config_path.clone();
+        let policy_clone = policy.clone();
         tokio::spawn(async move {
-            run_webserver(&listen, web, sending_channels_clone, cfg_clone).await;
+            run_webserver(
+                &web.listen_addr,
+                tunnel_clone,
+                config_clone,
+                config_path,
+                policy_clone,
+            )
+            .await;
         });
     }
 
-    let sending_channels_clone = sending_channels.clone();
-    let cfg_clone = cfg.clone();
-    let wg_sock_clone = wg_sock.clone();
-    let wg_addr_clone = wg_addr.clone();
+    let tunnel_clone = tunnel.clone();
+    let config_clone = config.clone();
+    let policy_clone = policy.clone();
     tokio::spawn(async move {
-        update_available_interfaces(
-            wg_sock_clone,
-            wg_addr_clone,
-            sending_channels_clone,
-            cfg_clone,
-        )
-        .await;
+        update_available_interfaces(tunnel_clone, config_clone, policy_clone).await;
     });
 
-    receive_from_wireguard(wg_sock, sending_channels, wg_addr, write_timeout).await;
+    tokio::spawn(track_usage(tunnel.clone(), config.clone(), policy.clone()));
+
+    tokio::spawn(reload_on_sighup(
+        config_path,
+        tunnel.clone(),
+        config.clone(),
+        policy.clone(),
+    ));
+
+    receive_from_wireguard(tunnel, config, policy).await;
 }
@@ -0,0 +1,171 @@
+//
+// Ricerca attiva del path MTU di ogni path
+//
+// Da un socket dedicato, legato alla stessa interfaccia e allo stesso indirizzo
+// del path, si inviano frame MtuProbe di dimensione crescente con il bit DF
+// (IP_PMTUDISC_PROBE, mai frammentati). L'altro lato risponde con un
+// MtuProbeReply piccolo che contiene la dimensione ricevuta, così conta solo
+// la direzione di andata. La dimensione massima viene cercata per bisezione.
+//
+// Il risultato è il payload UDP più grande che attraversa il path.
+//
+// Con la psk ogni probe porta dopo l'header il suo tag (auth::probe_tag),
+// altrimenti il server non risponde.
+//
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use engarde_common::auth::{self, TAG_LEN};
+use engarde_common::framing::{FrameType, Header, HEADER_LEN};
+
+// Tentativi per ogni dimensione prima di considerarla troppo grande
+const ATTEMPTS: usize = 2;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Payload UDP che ogni path deve poter trasportare (MTU minimo di IPv4 e IPv6)
+pub fn min_payload(ipv6: bool) -> usize {
+    if ipv6 {
+        1280 - 40 - 8
+    } else {
+        576 - 20 - 8
+    }
+}
+
+// Bisezione tra la dimensione più grande passata e la più piccola fallita
+struct Search {
+    low: usize,
+    high: usize,
+}
+
+impl Search {
+    fn next_size(&self) -> Option<usize> {
+        if self.low >= self.high {
+            None
+        } else {
+            Some((self.low + self.high).div_ceil(2))
+        }
+    }
+
+    fn on_result(&mut self, size: usize, passed: bool) {
+        if passed {
+            self.low = size;
+        } else {
+            self.high = size - 1;
+        }
+    }
+}
+
+// Imposta il bit DF sui pacchetti del socket ignorando il path MTU in cache
+fn set_pmtu_probe(sock: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    let (level, name, value) = if ipv6 {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )
+    } else {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn probe_frame(seq: u32, size: usize, psk: Option<&[u8]>) -> Vec<u8> {
+    let mut frame = vec![0u8; size.max(HEADER_LEN + TAG_LEN)];
+    let header = Header {
+        kind: FrameType::MtuProbe,
+        session: 0,
+        seq,
+    };
+    header.write(&mut frame);
+    if let Some(psk) = psk {
+        frame[HEADER_LEN..HEADER_LEN + TAG_LEN].copy_from_slice(&auth::probe_tag(psk, &header));
+    }
+    frame
+}
+
+// Aspetta la risposta al probe seq, true se l'altro lato ha ricevuto almeno size byte
+async fn wait_reply(sock: &UdpSocket, dst: SocketAddr, seq: u32, size: usize) -> bool {
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut buf = [0u8; 64];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let (n, src) = match tokio::time::timeout(remaining, sock.recv_from(&mut buf)).await {
+            Ok(Ok(res)) => res,
+            _ => return false,
+        };
+        if src != dst {
+            continue;
+        }
+        if let Some((header, body)) = Header::parse(&buf[..n]) {
+            if header.kind == FrameType::MtuProbeReply && header.seq == seq && body.len() >= 2 {
+                return u16::from_be_bytes([body[0], body[1]]) as usize >= size;
+            }
+        }
+    }
+}
+
+// Invia il probe seq di size byte, true se arriva all'altro lato
+async fn try_size(
+    sock: &UdpSocket,
+    dst: SocketAddr,
+    seq: u32,
+    size: usize,
+    psk: Option<&[u8]>,
+) -> io::Result<bool> {
+    let frame = probe_frame(seq, size, psk);
+    for _ in 0..ATTEMPTS {
+        match sock.send_to(&frame, dst).await {
+            Ok(_) => {}
+            // Più grande dell'MTU dell'interfaccia
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        if wait_reply(sock, dst, seq, size).await {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Cerca il payload UDP più grande (tra low e high) che arriva a dst.
+// None se il path non risponde nemmeno alla dimensione minima.
+pub async fn discover(
+    sock: &UdpSocket,
+    dst: SocketAddr,
+    low: usize,
+    high: usize,
+    psk: Option<&[u8]>,
+) -> io::Result<Option<usize>> {
+    set_pmtu_probe(sock, dst.is_ipv6())?;
+    if !try_size(sock, dst, 0, low, psk).await? {
+        return Ok(None);
+    }
+    let mut search = Search { low, high };
+    let mut seq = 0;
+    while let Some(size) = search.next_size() {
+        seq += 1;
+        let passed = try_size(sock, dst, seq, size, psk).await?;
+        search.on_result(size, passed);
+    }
+    Ok(Some(search.low))
+}
@@ -0,0 +1,429 @@
+//
+// Politica delle interfacce
+//
+// Tiene insieme le esclusioni della configurazione (excludedInterfaces) e le
+// inversioni fatte a runtime dalla web UI. Un'interfaccia è esclusa se è nella
+// configurazione oppure se è stata invertita, ma non entrambe le cose.
+//
+// Un'interfaccia che ha superato la sua quota di traffico è esclusa, o messa
+// in standby, finché non inizia il ciclo successivo, qualunque sia la sua
+// esclusione dalla configurazione o dalla web UI.
+//
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+// Cosa fare di un'interfaccia che ha superato la quota
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    // Nessun pacchetto viene più inviato sull'interfaccia
+    #[default]
+    Exclude,
+    // L'interfaccia viene usata solo se nessun'altra è sana
+    Standby,
+}
+
+struct PolicyState {
+    excluded: Vec<String>,
+    swaps: BTreeSet<String>,
+    over_quota: BTreeMap<String, QuotaAction>,
+}
+
+impl PolicyState {
+    fn is_excluded(&self, name: &str) -> bool {
+        self.excluded.iter().any(|ex| ex == name) != self.swaps.contains(name)
+    }
+
+    fn swap(&mut self, name: &str) {
+        if !self.swaps.remove(name) {
+            self.swaps.insert(name.to_string());
+        }
+    }
+}
+
+pub struct InterfacePolicy {
+    state: Mutex<PolicyState>,
+}
+
+impl InterfacePolicy {
+    pub fn new(excluded: Vec<String>) -> Self {
+        InterfacePolicy {
+            state: Mutex::new(PolicyState {
+                excluded,
+                swaps: BTreeSet::new(),
+                over_quota: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn is_excluded(&self, name: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.is_excluded(name) || state.over_quota.get(name) == Some(&QuotaAction::Exclude)
+    }
+
+    pub fn is_standby(&self, name: &str) -> bool {
+        self.state.lock().unwrap().over_quota.get(name) == Some(&QuotaAction::Standby)
+    }
+
+    // Stato della quota dell'interfaccia: None se non l'ha superata
+    pub fn quota(&self, name: &str) -> Option<QuotaAction> {
+        self.state.lock().unwrap().over_quota.get(name).copied()
+    }
+
+    // Imposta l'azione per la quota superata, true se è cambiata
+    pub fn set_quota(&self, name: &str, action: Option<QuotaAction>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let previous = match action {
+            Some(action) => state.over_quota.insert(name.to_string(), action),
+            None => state.over_quota.remove(name),
+        };
+        previous != action
+    }
+
+    // Inverte l'esclusione dell'interfaccia rispetto allo stato attuale
+    pub fn swap(&self, name: &str) {
+        self.state.lock().unwrap().swap(name);
+    }
+
+    // true se l'interfaccia era esclusa ed è stata inclusa
+    pub fn include(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.is_excluded(name) {
+            return false;
+        }
+        state.swap(name);
+        true
+    }
+
+    // true se l'interfaccia era inclusa ed è stata esclusa
+    pub fn exclude(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.is_excluded(name) {
+            return false;
+        }
+        state.swap(name);
+        true
+    }
+
+    // Torna alle sole esclusioni della configurazione
+    pub fn reset(&self) {
+        self.state.lock().unwrap().swaps.clear();
+    }
+
+    // Le interfacce invertite a runtime, in ordine alfabetico
+    pub fn swapped(&self) -> Vec<String> {
+        self.state.lock().unwrap().swaps.iter().cloned().collect()
+    }
+
+    // Ripristina le inversioni salvate (ad esempio nello stateFile)
+    pub fn restore(&self, swaps: Vec<String>) {
+        self.state.lock().unwrap().swaps = swaps.into_iter().collect();
+    }
+
+    // Nuove esclusioni della configurazione (reload), le inversioni restano
+    pub fn set_static_exclusions(&self, excluded: Vec<String>) {
+        self.state.lock().unwrap().excluded = excluded;
+    }
+
+    // Le esclusioni effettive, nell'ordine della configurazione e poi delle inversioni
+    pub fn effective_exclusions(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut result: Vec<String> = state
+            .excluded
+            .iter()
+            .filter(|name| !state.swaps.contains(*name))
+            .cloned()
+            .collect();
+        result.extend(
+            state
+                .swaps
+                .iter()
+                .filter(|name| !state.excluded.contains(name))
+                .cloned(),
+        );
+        result
+    }
+
+    // Le esclusioni salvate nella configurazione diventano quelle statiche e
+    // le inversioni vengono azzerate
+    pub fn make_static(&self, excluded: Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.excluded = excluded;
+        state.swaps.clear();
+    }
+}
+
+// Sostituisce il valore di excludedInterfaces nella sezione client del testo
+// YAML della configurazione, lasciando intatto il resto (commenti compresi).
+// Se la chiave manca viene aggiunta all'inizio della sezione. None se la
+// sezione client non c'è.
+pub fn patch_excluded_interfaces(config: &str, excluded: &[String]) -> Option<String> {
+    const KEY: &str = "excludedInterfaces:";
+    let newline = if config.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let lines: Vec<&str> = config.split_inclusive('\n').collect();
+    let indent = |line: &str| line.len() - line.trim_start_matches(' ').len();
+    let blank = |line: &str| {
+        let t = line.trim();
+        t.is_empty() || t.starts_with('#')
+    };
+    let comment = |rest: &str| {
+        let t = rest.trim();
+        t.starts_with('#').then(|| t.to_string())
+    };
+
+    let start = lines.iter().position(|line| {
+        line.strip_prefix("client:")
+            .is_some_and(|rest| rest.trim().is_empty() || rest.trim().starts_with('#'))
+    })?;
+    let end = (start + 1..lines.len())
+        .find(|&i| !blank(lines[i]) && indent(lines[i]) == 0)
+        .unwrap_or(lines.len());
+    let key_indent = (start + 1..end)
+        .find(|&i| !blank(lines[i]))
+        .map_or(2, |i| indent(lines[i]));
+
+    let values: Vec<String> = excluded
+        .iter()
+        .map(|name| serde_json::to_string(name).unwrap())
+        .collect();
+    let mut entry = format!("{}{} [{}]", " ".repeat(key_indent), KEY, values.join(", "));
+
+    let key = (start + 1..end)
+        .find(|&i| indent(lines[i]) == key_indent && lines[i].trim_start().starts_with(KEY));
+    let (first, last) = match key {
+        Some(first) => {
+            let rest = &lines[first].trim_start()[KEY.len()..];
+            let mut last = first;
+            if rest.trim_start().starts_with('[') {
+                // Lista tra parentesi, anche su più righe
+                let mut depth = 0i32;
+                for (i, line) in lines.iter().enumerate().take(end).skip(first) {
+                    let text = if i == first { rest } else { line };
+                    depth += text.matches('[').count() as i32 - text.matches(']').count() as i32;
+                    last = i;
+                    if depth <= 0 {
+                        break;
+                    }
+                }
+                let tail = lines[last].rsplit_once(']').map_or("", |(_, tail)| tail);
+                if let Some(c) = comment(tail) {
+                    entry = format!("{} {}", entry, c);
+                }
+            } else if blank(rest) {
+                // Lista a blocchi: le righe più indentate o che iniziano con "-"
+                for (i, line) in lines.iter().enumerate().take(end).skip(first + 1) {
+                    if blank(line) {
+                        continue;
+                    }
+                    let item = indent(line) == key_indent && line.trim_start().starts_with('-');
+                    if indent(line) <= key_indent && !item {
+                        break;
+                    }
+                    last = i;
+                }
+                if let Some(c) = comment(rest) {
+                    entry = format!("{} {}", entry, c);
+                }
+            }
+            (first, last)
+        }
+        // La chiave viene aggiunta subito dopo "client:"
+        None => (start + 1, start),
+    };
+
+    let mut result = String::with_capacity(config.len() + entry.len());
+    for line in &lines[..first] {
+        result.push_str(line);
+    }
+    if !result.ends_with('\n') {
+        result.push_str(newline);
+    }
+    result.push_str(&entry);
+    result.push_str(newline);
+    for line in &lines[last + 1..] {
+        result.push_str(line);
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(excluded: &[&str]) -> InterfacePolicy {
+        InterfacePolicy::new(excluded.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn static_exclusions() {
+        let p = policy(&["lo", "wg0"]);
+        assert!(p.is_excluded("lo"));
+        assert!(p.is_excluded("wg0"));
+        assert!(!p.is_excluded("eth0"));
+        assert!(p.swapped().is_empty());
+    }
+
+    #[test]
+    fn exclude_included_interface() {
+        let p = policy(&["lo"]);
+        assert!(p.exclude("eth0"));
+        assert!(p.is_excluded("eth0"));
+        // Una seconda richiesta non cambia nulla ("already-excluded")
+        assert!(!p.exclude("eth0"));
+        assert!(p.is_excluded("eth0"));
+        assert_eq!(p.swapped(), vec!["eth0"]);
+    }
+
+    #[test]
+    fn include_statically_excluded_interface() {
+        let p = policy(&["wwan0"]);
+        assert!(p.include("wwan0"));
+        assert!(!p.is_excluded("wwan0"));
+        // Una seconda richiesta non cambia nulla ("already-included")
+        assert!(!p.include("wwan0"));
+        assert!(!p.is_excluded("wwan0"));
+        assert_eq!(p.swapped(), vec!["wwan0"]);
+    }
+
+    #[test]
+    fn include_after_exclude_clears_swap() {
+        let p = policy(&[]);
+        assert!(p.exclude("eth1"));
+        assert!(p.include("eth1"));
+        assert!(!p.is_excluded("eth1"));
+        assert!(p.swapped().is_empty());
+    }
+
+    #[test]
+    fn swap_toggles() {
+        let p = policy(&["lo"]);
+        p.swap("eth0");
+        p.swap("lo");
+        assert!(p.is_excluded("eth0"));
+        assert!(!p.is_excluded("lo"));
+        p.swap("eth0");
+        p.swap("lo");
+        assert!(!p.is_excluded("eth0"));
+        assert!(p.is_excluded("lo"));
+        assert!(p.swapped().is_empty());
+    }
+
+    #[test]
+    fn reset_restores_config() {
+        let p = policy(&["lo"]);
+        p.exclude("eth0");
+        p.include("lo");
+        p.reset();
+        assert!(p.is_excluded("lo"));
+        assert!(!p.is_excluded("eth0"));
+    }
+
+    #[test]
+    fn restore_swaps() {
+        let p = policy(&["lo"]);
+        p.restore(vec!["eth1".to_string(), "lo".to_string()]);
+        assert!(p.is_excluded("eth1"));
+        assert!(!p.is_excluded("lo"));
+        assert_eq!(p.swapped(), vec!["eth1", "lo"]);
+    }
+
+    #[test]
+    fn reload_keeps_swaps() {
+        let p = policy(&["lo"]);
+        p.exclude("eth0");
+        p.set_static_exclusions(vec!["lo".to_string(), "eth2".to_string()]);
+        assert!(p.is_excluded("eth0"));
+        assert!(p.is_excluded("eth2"));
+        assert!(p.is_excluded("lo"));
+    }
+
+    #[test]
+    fn quota_exclusion_is_separate() {
+        let p = policy(&["lo"]);
+        assert!(p.set_quota("wwan0", Some(QuotaAction::Exclude)));
+        assert!(!p.set_quota("wwan0", Some(QuotaAction::Exclude)));
+        assert!(p.is_excluded("wwan0"));
+        // La web UI non può includerla, e le esclusioni salvate non cambiano
+        assert!(!p.include("wwan0"));
+        assert!(p.is_excluded("wwan0"));
+        assert_eq!(p.effective_exclusions(), vec!["lo"]);
+        assert!(p.set_quota("wwan0", Some(QuotaAction::Standby)));
+        assert!(!p.is_excluded("wwan0"));
+        assert!(p.is_standby("wwan0"));
+        assert!(p.set_quota("wwan0", None));
+        assert!(!p.is_standby("wwan0"));
+        assert_eq!(p.quota("wwan0"), None);
+    }
+
+    #[test]
+    fn effective_and_make_static() {
+        let p = policy(&["lo", "wwan0"]);
+        p.include("wwan0");
+        p.exclude("eth1");
+        let effective = p.effective_exclusions();
+        assert_eq!(effective, vec!["lo", "eth1"]);
+        p.make_static(effective.clone());
+        assert!(p.swapped().is_empty());
+        assert_eq!(p.effective_exclusions(), effective);
+        assert!(!p.is_excluded("wwan0"));
+        assert!(p.is_excluded("eth1"));
+    }
+
+    fn patch(config: &str, excluded: &[&str]) -> String {
+        let excluded: Vec<String> = excluded.iter().map(|s| s.to_string()).collect();
+        patch_excluded_interfaces(config, &excluded).unwrap()
+    }
+
+    #[test]
+    fn patch_inline_list_keeps_comments() {
+        let config = "# engarde\nclient:\n  # uplink da non usare\n  excludedInterfaces: [\"lo\"] # sempre lo\n  listenAddr: \"0.0.0.0:59401\"\n";
+        assert_eq!(
+            patch(config, &["lo", "wwan0"]),
+            "# engarde\nclient:\n  # uplink da non usare\n  excludedInterfaces: [\"lo\", \"wwan0\"] # sempre lo\n  listenAddr: \"0.0.0.0:59401\"\n"
+        );
+    }
+
+    #[test]
+    fn patch_block_list() {
+        let config = "client:\n  excludedInterfaces:\n    - \"lo\"\n    # commento\n    - \"wg0\"\n\n  # dopo la lista\n  writeTimeout: 10\nserver:\n  excludedInterfaces: []\n";
+        assert_eq!(
+            patch(config, &["eth1"]),
+            "client:\n  excludedInterfaces: [\"eth1\"]\n\n  # dopo la lista\n  writeTimeout: 10\nserver:\n  excludedInterfaces: []\n"
+        );
+        // Elementi allo stesso livello della chiave
+        let config = "client:\n  excludedInterfaces:\n  - lo\n  - wg0\n  mode: balance\n";
+        assert_eq!(
+            patch(config, &[]),
+            "client:\n  excludedInterfaces: []\n  mode: balance\n"
+        );
+    }
+
+    #[test]
+    fn patch_multiline_flow_list() {
+        let config = "client:\n    excludedInterfaces: [\n      \"lo\",\n      \"wg0\",\n    ]\n    mode: failover\n";
+        assert_eq!(
+            patch(config, &["lo"]),
+            "client:\n    excludedInterfaces: [\"lo\"]\n    mode: failover\n"
+        );
+    }
+
+    #[test]
+    fn patch_missing_key() {
+        let config = "server:\n  mode: balance\r\nclient: # client\r\n\r\n    mode: balance\r\n";
+        assert_eq!(
+            patch(config, &["lo"]),
+            "server:\n  mode: balance\r\nclient: # client\r\n    excludedInterfaces: [\"lo\"]\r\n\r\n    mode: balance\r\n"
+        );
+        assert_eq!(
+            patch("client:", &["lo"]),
+            "client:\n  excludedInterfaces: [\"lo\"]\n"
+        );
+        assert!(patch_excluded_interfaces("server:\n  mode: balance\n", &[]).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+//
+// Limite di banda e di pacchetti al secondo di un'interfaccia
+//
+// Token bucket: i token si ricaricano di maxBps byte (o maxPps pacchetti) al
+// secondo, fino a burst. Ogni pacchetto consuma la sua dimensione e un
+// pacchetto; se i token non bastano viene scartato su questo path, gli altri
+// path continuano a trasportarlo.
+//
+// Il limite è dell'interfaccia: le sue routine (IPv4 e IPv6) lo condividono.
+//
+
+use std::sync::Mutex;
+use std::time::Instant;
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+        }
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+    }
+}
+
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+    last: Instant,
+}
+
+impl RateLimiter {
+    // None se nessun limite è impostato. Il burst in byte è almeno min_burst,
+    // così un pacchetto della dimensione massima può sempre passare.
+    pub fn new(
+        max_bps: Option<u64>,
+        max_pps: Option<u64>,
+        burst: Option<u64>,
+        min_burst: u64,
+    ) -> Option<Self> {
+        if max_bps.is_none() && max_pps.is_none() {
+            return None;
+        }
+        // Di default 100ms di traffico
+        let bytes = max_bps.map(|rate| {
+            let burst = burst.unwrap_or(rate / 10).max(min_burst);
+            TokenBucket::new(rate, burst)
+        });
+        let packets = max_pps.map(|rate| TokenBucket::new(rate, (rate / 10).max(1)));
+        Some(RateLimiter {
+            state: Mutex::new(State {
+                bytes,
+                packets,
+                last: Instant::now(),
+            }),
+        })
+    }
+
+    // true se il pacchetto di len byte rientra nel limite
+    pub fn allow(&self, len: usize, now: Instant) -> bool {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.last = state.last.max(now);
+        let len = len as f64;
+        for bucket in [&mut state.bytes, &mut state.packets].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+        let bytes_ok = state.bytes.as_ref().is_none_or(|b| b.tokens >= len);
+        let packets_ok = state.packets.as_ref().is_none_or(|b| b.tokens >= 1.0);
+        if !bytes_ok || !packets_ok {
+            return false;
+        }
+        if let Some(bucket) = &mut state.bytes {
+            bucket.tokens -= len;
+        }
+        if let Some(bucket) = &mut state.packets {
+            bucket.tokens -= 1.0;
+        }
+        true
+    }
+}
@@ -0,0 +1,127 @@
+//
+// Traffico per interfaccia nel ciclo di fatturazione
+//
+// Byte inviati e ricevuti da ogni interfaccia, sommando tutti i suoi path, dal
+// giorno di inizio del ciclo (billingDay, ogni mese). Quando inizia un nuovo
+// ciclo i contatori ripartono da zero. Con usageFile impostato vengono salvati
+// e ricaricati all'avvio, così sopravvivono ai riavvii.
+//
+// Le date sono in UTC.
+//
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct InterfaceUsage {
+    pub tx: u64,
+    pub rx: u64,
+    // Primo giorno del ciclo a cui si riferiscono i contatori (AAAA-MM-GG)
+    #[serde(rename = "cycleStart")]
+    pub cycle_start: String,
+}
+
+impl InterfaceUsage {
+    pub fn total(&self) -> u64 {
+        self.tx + self.rx
+    }
+}
+
+// Contenuto dell'usageFile
+#[derive(Serialize, Deserialize, Default)]
+struct UsageState {
+    interfaces: BTreeMap<String, InterfaceUsage>,
+}
+
+pub struct Usage {
+    interfaces: Mutex<BTreeMap<String, InterfaceUsage>>,
+}
+
+impl Usage {
+    pub fn new() -> Self {
+        Usage {
+            interfaces: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Riprende i contatori salvati con to_json
+    pub fn from_json(data: &str) -> Result<Self, serde_json::Error> {
+        let state: UsageState = serde_json::from_str(data)?;
+        Ok(Usage {
+            interfaces: Mutex::new(state.interfaces),
+        })
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        let state = UsageState {
+            interfaces: self.interfaces.lock().unwrap().clone(),
+        };
+        serde_json::to_vec_pretty(&state).unwrap()
+    }
+
+    // Somma il traffico al ciclo in corso dell'interfaccia, azzerando i
+    // contatori se il ciclo è cambiato
+    pub fn add(&self, ifname: &str, tx: u64, rx: u64, cycle_start: &str) {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        let usage = interfaces.entry(ifname.to_string()).or_default();
+        if usage.cycle_start != cycle_start {
+            *usage = InterfaceUsage {
+                tx: 0,
+                rx: 0,
+                cycle_start: cycle_start.to_string(),
+            };
+        }
+        usage.tx += tx;
+        usage.rx += rx;
+    }
+
+    pub fn get(&self, ifname: &str) -> Option<InterfaceUsage> {
+        self.interfaces.lock().unwrap().get(ifname).cloned()
+    }
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Data di oggi in UTC (anno, mese, giorno)
+pub fn today() -> (i64, u32, u32) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    civil_from_days((secs / 86400) as i64)
+}
+
+// Inizio del ciclo che contiene la data: il billingDay di questo mese se è
+// già passato, altrimenti quello del mese precedente
+pub fn cycle_start(date: (i64, u32, u32), billing_day: u32) -> String {
+    let (year, month, day) = date;
+    let (year, month) = if day >= billing_day {
+        (year, month)
+    } else if month == 1 {
+        (year - 1, 12)
+    } else {
+        (year, month - 1)
+    };
+    format!("{:04}-{:02}-{:02}", year, month, billing_day)
+}
+
+// Giorni dal 1970-01-01 alla data del calendario gregoriano
+// (algoritmo "civil_from_days" di Howard Hinnant)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,158 @@
+//
+// Benchmark del fan-out di un pacchetto di Wireguard su più path
+//
+// "copy" è il fan-out di prima del pool: ogni datagram letto viene copiato
+// una volta e ogni path riceve gli indici dei datagram che gli toccano.
+// "vec" copia il datagram in un Packet fuori dal pool (Packet::from_vec).
+// "pool" è quello con i Packet del pool: il buffer letto passa ai path senza
+// copie e torna al pool quando tutti lo hanno inviato.
+//
+// "fec" è il costo per pacchetto ricevuto del decoder FEC, copiando il
+// pacchetto o tenendo quello del pool; "balance" è quello della scelta del
+// path in mode: balance.
+//
+// cargo bench --bench fanout
+//
+
+use engarde_common::fec::{Fec, FecConfig};
+use engarde_common::framing::{FrameType, Header};
+use engarde_common::pool::{BufferPool, Packet};
+use engarde_common::scheduler::{Mode, PathInfo, Scheduler};
+use std::hint::black_box;
+use std::time::Instant;
+
+const PACKETS: usize = 1_000_000;
+// Datagram letti con una recv, come BATCH_SIZE
+const BATCH: usize = 32;
+const SIZES: [usize; 2] = [100, 1420];
+const PATHS: [usize; 3] = [1, 3, 5];
+
+fn fanout_copy(size: usize, paths: usize) -> f64 {
+    let buf = vec![0u8; 1500 + 1];
+    let mut per_path: Vec<Vec<usize>> = vec![Vec::new(); paths];
+    let start = Instant::now();
+    for _ in 0..PACKETS / BATCH {
+        let mut datagrams: Vec<Vec<u8>> = Vec::with_capacity(BATCH);
+        for _ in 0..BATCH {
+            let n = black_box(size);
+            for indexes in per_path.iter_mut() {
+                indexes.push(datagrams.len());
+            }
+            datagrams.push(buf[..n].to_vec());
+        }
+        for indexes in per_path.iter_mut() {
+            let packets: Vec<&[u8]> = indexes.iter().map(|&i| datagrams[i].as_slice()).collect();
+            black_box(&packets);
+            indexes.clear();
+        }
+    }
+    start.elapsed().as_nanos() as f64 / PACKETS as f64
+}
+
+fn fanout_packets(size: usize, paths: usize, mut read: impl FnMut(usize) -> Packet) -> f64 {
+    let mut per_path: Vec<Vec<Packet>> = vec![Vec::new(); paths];
+    let start = Instant::now();
+    for _ in 0..PACKETS / BATCH {
+        for _ in 0..BATCH {
+            let packet = read(black_box(size));
+            // L'ultimo path riceve il pacchetto senza clonarlo
+            let (last, others) = per_path.split_last_mut().unwrap();
+            for packets in others {
+                packets.push(packet.clone());
+            }
+            last.push(packet);
+        }
+        for packets in per_path.iter_mut() {
+            black_box(&packets);
+            packets.clear();
+        }
+    }
+    start.elapsed().as_nanos() as f64 / PACKETS as f64
+}
+
+// Pacchetti di dati ricevuti con la FEC attiva, senza perdite
+fn fec_receive(size: usize, mut read: impl FnMut(usize) -> Packet) -> f64 {
+    let fec = Fec::new(&FecConfig {
+        data_shards: 8,
+        parity_shards: 2,
+    });
+    let start = Instant::now();
+    for seq in 0..PACKETS as u32 {
+        let header = Header {
+            kind: FrameType::Data,
+            session: 1,
+            seq,
+        };
+        let packet = read(black_box(size));
+        black_box(fec.on_data(&header, &packet));
+    }
+    start.elapsed().as_nanos() as f64 / PACKETS as f64
+}
+
+// Scelta del path in balance, con i path ricostruiti a ogni blocco come in
+// receive_from_wireguard
+fn balance(paths: usize) -> f64 {
+    let keys: Vec<String> = (0..paths)
+        .map(|i| format!("eth{}:192.0.2.{}", i, i))
+        .collect();
+    let info: Vec<PathInfo> = keys
+        .iter()
+        .map(|key| PathInfo {
+            key,
+            weight: 1,
+            priority: 0,
+            healthy: true,
+        })
+        .collect();
+    let mut scheduler = Scheduler::new();
+    let start = Instant::now();
+    for _ in 0..PACKETS / BATCH {
+        scheduler.set_paths(&info);
+        for _ in 0..BATCH {
+            black_box(scheduler.select(Mode::Balance, &info));
+        }
+    }
+    start.elapsed().as_nanos() as f64 / PACKETS as f64
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>6} {:>12} {:>12} {:>12}",
+        "size", "paths", "copy ns/pkt", "vec ns/pkt", "pool ns/pkt"
+    );
+    for size in SIZES {
+        for paths in PATHS {
+            let copy = fanout_copy(size, paths);
+            let buf = vec![0u8; 1500 + 1];
+            let vec = fanout_packets(size, paths, |n| Packet::from_vec(buf[..n].to_vec()));
+            let mut pool = BufferPool::new(1500 + 1);
+            let mut buf = pool.buffer();
+            // Come Receiver::take: il buffer letto diventa il pacchetto
+            let pooled = fanout_packets(size, paths, |n| pool.wrap(&mut buf, 0..n));
+            println!(
+                "{:>6} {:>6} {:>12.1} {:>12.1} {:>12.1}",
+                size, paths, copy, vec, pooled
+            );
+        }
+    }
+    println!();
+    println!(
+        "{:>6} {:>16} {:>16}",
+        "size", "fec copy ns/pkt", "fec pool ns/pkt"
+    );
+    for size in SIZES {
+        // Prima la FEC copiava ogni pacchetto ricevuto, ora tiene quello letto
+        // (Receiver::take_all)
+        let buf = vec![0u8; 1500 + 1];
+        let copy = fec_receive(size, |n| Packet::from_vec(buf[..n].to_vec()));
+        let mut pool = BufferPool::new(1500 + 1);
+        let mut buf = pool.buffer();
+        let pooled = fec_receive(size, |n| pool.wrap(&mut buf, 0..n));
+        println!("{:>6} {:>16.1} {:>16.1}", size, copy, pooled);
+    }
+    println!();
+    println!("{:>6} {:>12}", "paths", "balance ns/pkt");
+    for paths in PATHS {
+        println!("{:>6} {:>12.1}", paths, balance(paths));
+    }
+}
@@ -0,0 +1,177 @@
+//
+// Autenticazione dei path con una chiave condivisa (psk)
+//
+// Con la psk impostata il server aggiunge un path ai suoi client solo dopo
+// questo scambio:
+//
+//   client -> server  AuthHello      COOKIE_LEN byte a zero
+//   server -> client  AuthChallenge  cookie
+//   client -> server  AuthResponse   cookie e tag, HMAC-SHA256(psk, cookie)
+//   server -> client  AuthOk
+//
+// Il cookie è un HMAC dell'indirizzo del mittente con un segreto casuale del
+// server, diverso ad ogni intervallo di tempo: il server non tiene stato per
+// le richieste, e chi falsifica l'indirizzo sorgente non riceve il cookie e non
+// può completare lo scambio. La sfida non è più grande della richiesta, così il
+// server non moltiplica il traffico verso un indirizzo falsificato.
+//
+// Tutti i frame usano l'header del framing (vedi framing.rs), anche quando il
+// framing dei dati non è abilitato.
+//
+
+use crate::framing::{FrameType, Header, HEADER_LEN};
+use crate::web::constant_time_eq;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const COOKIE_LEN: usize = 16;
+pub const TAG_LEN: usize = 32;
+
+// Ogni quanti secondi cambiano i cookie di autenticazione; un cookie vale
+// anche nell'intervallo successivo
+const COOKIE_LIFETIME: u64 = 30;
+
+// Frame di autenticazione con il suo corpo
+pub fn frame(kind: FrameType, body: &[u8]) -> Vec<u8> {
+    let header = Header {
+        kind,
+        session: 0,
+        seq: 0,
+    };
+    let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+    buf.extend_from_slice(&header.to_bytes());
+    buf.extend_from_slice(body);
+    buf
+}
+
+// HMAC-SHA256 delle parti concatenate
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; TAG_LEN] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC accetta chiavi di ogni lunghezza");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// Tag dell'AuthResponse: prova che il client conosce la psk
+pub fn tag(psk: &[u8], cookie: &[u8]) -> [u8; TAG_LEN] {
+    hmac(psk, &[b"engarde-auth", cookie])
+}
+
+// Tag dei probe di MTU, che partono da un socket mai autenticato: il server
+// risponde solo ai probe che iniziano con il tag del loro header
+pub fn probe_tag(psk: &[u8], header: &Header) -> [u8; TAG_LEN] {
+    hmac(psk, &[b"engarde-mtu", &header.to_bytes()])
+}
+
+// Lato server dello scambio: cookie dei mittenti e verifica delle risposte
+// con la psk di un gruppo
+pub struct Verifier {
+    psk: Vec<u8>,
+    // Segreto dei cookie, casuale ad ogni avvio
+    secret: [u8; 32],
+}
+
+impl Verifier {
+    pub fn new(psk: &[u8]) -> Result<Self, getrandom::Error> {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret)?;
+        Ok(Verifier {
+            psk: psk.to_vec(),
+            secret,
+        })
+    }
+
+    // Intervallo di validità dei cookie in cui cade now
+    pub fn epoch(now: SystemTime) -> u64 {
+        now.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() / COOKIE_LIFETIME)
+            .unwrap_or(0)
+    }
+
+    // Cookie del mittente key nell'intervallo epoch
+    pub fn cookie(&self, key: &str, epoch: u64) -> [u8; COOKIE_LEN] {
+        let mac = hmac(&self.secret, &[&epoch.to_be_bytes(), key.as_bytes()]);
+        mac[..COOKIE_LEN].try_into().unwrap()
+    }
+
+    // true se il corpo dell'AuthResponse ha un cookie ancora valido per il
+    // mittente nell'intervallo epoch e il tag della psk
+    pub fn verify(&self, key: &str, body: &[u8], epoch: u64) -> bool {
+        if body.len() != COOKIE_LEN + TAG_LEN {
+            return false;
+        }
+        let (cookie, tag) = body.split_at(COOKIE_LEN);
+        let fresh = [epoch, epoch.wrapping_sub(1)]
+            .iter()
+            .any(|e| constant_time_eq(&self.cookie(key, *e), cookie));
+        fresh && constant_time_eq(&self::tag(&self.psk, cookie), tag)
+    }
+
+    // Tag all'inizio del corpo di un probe di MTU
+    pub fn verify_probe(&self, header: &Header, body: &[u8]) -> bool {
+        body.len() >= TAG_LEN && constant_time_eq(&probe_tag(&self.psk, header), &body[..TAG_LEN])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "192.0.2.1:4000";
+
+    // Risposta del client alla sfida, come in handle_path_packet
+    fn response(psk: &[u8], cookie: &[u8]) -> Vec<u8> {
+        [cookie, &tag(psk, cookie)].concat()
+    }
+
+    #[test]
+    fn valid_response_is_accepted() {
+        let verifier = Verifier::new(b"secret").unwrap();
+        let cookie = verifier.cookie(KEY, 100);
+        assert!(verifier.verify(KEY, &response(b"secret", &cookie), 100));
+        // Il cookie vale anche nell'intervallo successivo
+        assert!(verifier.verify(KEY, &response(b"secret", &cookie), 101));
+    }
+
+    #[test]
+    fn wrong_psk_is_rejected() {
+        let verifier = Verifier::new(b"secret").unwrap();
+        let cookie = verifier.cookie(KEY, 100);
+        assert!(!verifier.verify(KEY, &response(b"other", &cookie), 100));
+        // Corpo troncato
+        let body = response(b"secret", &cookie);
+        assert!(!verifier.verify(KEY, &body[..body.len() - 1], 100));
+    }
+
+    #[test]
+    fn expired_or_foreign_cookie_is_rejected() {
+        let verifier = Verifier::new(b"secret").unwrap();
+        let cookie = verifier.cookie(KEY, 100);
+        assert!(!verifier.verify(KEY, &response(b"secret", &cookie), 102));
+        assert!(!verifier.verify(KEY, &response(b"secret", &cookie), 99));
+        // Cookie di un altro mittente
+        assert!(!verifier.verify("192.0.2.1:4001", &response(b"secret", &cookie), 100));
+        // Cookie di un altro server (altro segreto), con la stessa psk
+        let other = Verifier::new(b"secret").unwrap();
+        assert!(!other.verify(KEY, &response(b"secret", &cookie), 100));
+    }
+
+    #[test]
+    fn probe_needs_the_psk_tag() {
+        let verifier = Verifier::new(b"secret").unwrap();
+        let header = Header {
+            kind: FrameType::MtuProbe,
+            session: 0,
+            seq: 7,
+        };
+        let body = [probe_tag(b"secret", &header).as_slice(), &[0; 100]].concat();
+        assert!(verifier.verify_probe(&header, &body));
+        assert!(!verifier.verify_probe(&header, &body[..TAG_LEN - 1]));
+        assert!(!verifier.verify_probe(&Header { seq: 8, ..header }, &body));
+        let wrong = [probe_tag(b"other", &header).as_slice(), &[0; 100]].concat();
+        assert!(!verifier.verify_probe(&header, &wrong));
+    }
+}
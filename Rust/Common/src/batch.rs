@@ -0,0 +1,526 @@
+//
+// I/O a blocchi sui socket UDP (opzionale, batchIo)
+//
+// Su Linux si ricevono fino a BATCH_SIZE datagram per chiamata con recvmmsg e
+// si inviano con sendmmsg. Se il kernel lo supporta, i pacchetti consecutivi
+// della stessa dimensione verso la stessa destinazione partono in un solo
+// messaggio con UDP_SEGMENT (GSO), e sui socket dei path il kernel può
+// consegnare più datagram uniti con UDP_GRO.
+//
+// Altrove, o con batchIo disattivato, si usano recv_from/send_to di tokio
+// un datagram alla volta.
+//
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::UdpSocket;
+
+use crate::pool::{BufferPool, Packet};
+
+// Datagram letti con una sola recvmmsg
+pub const BATCH_SIZE: usize = 32;
+// Con UDP_GRO un messaggio può contenere fino a 64KB di datagram uniti
+#[cfg(target_os = "linux")]
+const GRO_BUF_LEN: usize = 65536;
+
+struct Received {
+    buf: usize,
+    start: usize,
+    len: usize,
+    addr: SocketAddr,
+    truncated: bool,
+}
+
+// Buffer di ricezione di un socket. Ogni buffer lascia headroom byte liberi
+// prima del datagram, per scriverci l'header del framing senza copiarlo.
+pub struct Receiver {
+    batch: bool,
+    gro: bool,
+    // Datagram più grande accettato, quelli più lunghi sono segnati come troncati
+    max_len: usize,
+    headroom: usize,
+    bufs: Vec<Vec<u8>>,
+    received: Vec<Received>,
+    // Buffer tolti da take_all, per indice in bufs
+    taken: Vec<Option<Packet>>,
+}
+
+impl Receiver {
+    pub fn new(max_len: usize, headroom: usize, batch: bool) -> Self {
+        let batch = batch && cfg!(target_os = "linux");
+        let count = if batch { BATCH_SIZE } else { 1 };
+        Receiver {
+            batch,
+            gro: false,
+            max_len,
+            headroom,
+            // Un byte in più per riconoscere i datagram troncati
+            bufs: (0..count)
+                .map(|_| vec![0u8; headroom + max_len + 1])
+                .collect(),
+            received: Vec::with_capacity(count),
+            taken: vec![None; count],
+        }
+    }
+
+    // Chiede al kernel di unire i datagram ricevuti (UDP_GRO), solo in batch e
+    // senza headroom. false se non è supportato.
+    pub fn enable_gro(&mut self, sock: &UdpSocket) -> bool {
+        #[cfg(target_os = "linux")]
+        if self.batch && self.headroom == 0 && sys::set_gro(sock).is_ok() {
+            self.gro = true;
+            for buf in &mut self.bufs {
+                buf.resize(GRO_BUF_LEN, 0);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = sock;
+        self.gro
+    }
+
+    // Aspetta almeno un datagram e legge quelli già arrivati
+    pub async fn recv(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        self.received.clear();
+        #[cfg(target_os = "linux")]
+        if self.batch {
+            return self.recv_mmsg(sock).await;
+        }
+        let headroom = self.headroom;
+        let (n, addr) = sock.recv_from(&mut self.bufs[0][headroom..]).await?;
+        self.received.push(Received {
+            buf: 0,
+            start: headroom,
+            len: n,
+            addr,
+            truncated: n > self.max_len,
+        });
+        Ok(1)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_mmsg(&mut self, sock: &UdpSocket) -> io::Result<usize> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+        let messages = loop {
+            sock.readable().await?;
+            let res = sock.try_io(Interest::READABLE, || {
+                sys::recvmmsg(sock.as_raw_fd(), &mut self.bufs, self.headroom, self.gro)
+            });
+            match res {
+                Ok(messages) => break messages,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        for msg in messages {
+            let buf = msg.buf;
+            if msg.len == 0 {
+                self.received.push(Received {
+                    buf,
+                    start: self.headroom,
+                    len: 0,
+                    addr: msg.addr,
+                    truncated: msg.truncated,
+                });
+                continue;
+            }
+            // Con GRO il messaggio contiene datagram di msg.segment byte, l'ultimo può essere più corto
+            let segment = msg.segment.filter(|&s| s > 0).unwrap_or(msg.len);
+            let mut offset = 0;
+            while offset < msg.len {
+                let len = segment.min(msg.len - offset);
+                self.received.push(Received {
+                    buf,
+                    start: self.headroom + offset,
+                    len,
+                    addr: msg.addr,
+                    truncated: msg.truncated || len > self.max_len,
+                });
+                offset += len;
+            }
+        }
+        Ok(self.received.len())
+    }
+
+    // Datagram letti dall'ultima recv
+    pub fn count(&self) -> usize {
+        self.received.len()
+    }
+
+    // Datagram i: contenuto, mittente e se è stato troncato
+    pub fn get(&self, i: usize) -> (&[u8], SocketAddr, bool) {
+        let r = &self.received[i];
+        (
+            &self.bufs[r.buf][r.start..r.start + r.len],
+            r.addr,
+            r.truncated,
+        )
+    }
+
+    // Datagram i con davanti lo spazio di headroom, per scriverci l'header
+    pub fn with_headroom_mut(&mut self, i: usize) -> &mut [u8] {
+        let r = &self.received[i];
+        &mut self.bufs[r.buf][r.start - self.headroom..r.start + r.len]
+    }
+
+    // Dimensione dei buffer, per creare il pool da passare a take
+    pub fn buf_len(&self) -> usize {
+        self.bufs[0].len()
+    }
+
+    // Toglie il datagram i (con l'headroom) senza copiarlo: il suo buffer
+    // viene scambiato con uno libero del pool. Non si può usare con GRO, dove un
+    // buffer contiene più datagram (vedi take_all).
+    pub fn take(&mut self, i: usize, pool: &mut BufferPool) -> Packet {
+        debug_assert!(!self.gro);
+        let r = &self.received[i];
+        pool.wrap(
+            &mut self.bufs[r.buf],
+            r.start - self.headroom..r.start + r.len,
+        )
+    }
+
+    // Toglie tutti i datagram letti senza copiarli, come take: ogni buffer
+    // usato diventa un pacchetto del pool e i datagram che contiene (con GRO
+    // più di uno) ne sono parti. Restituisce contenuto, mittente e troncamento.
+    pub fn take_all(
+        &mut self,
+        pool: &mut BufferPool,
+    ) -> impl Iterator<Item = (Packet, SocketAddr, bool)> + '_ {
+        // Quelli della lettura precedente tornano al pool quando sono inviati
+        self.taken.fill(None);
+        for r in &self.received {
+            if self.taken[r.buf].is_none() {
+                let len = self.bufs[r.buf].len();
+                self.taken[r.buf] = Some(pool.wrap(&mut self.bufs[r.buf], 0..len));
+            }
+        }
+        let taken = &self.taken;
+        self.received.iter().map(move |r| {
+            let buf = taken[r.buf].as_ref().unwrap();
+            let packet = buf.slice(r.start..r.start + r.len);
+            (packet, r.addr, r.truncated)
+        })
+    }
+}
+
+// Invio su un socket: con batchIo più pacchetti per chiamata, e GSO finché il
+// kernel lo accetta
+pub struct Sender {
+    batch: bool,
+    gso: AtomicBool,
+}
+
+impl Sender {
+    pub fn new(batch: bool) -> Self {
+        let batch = batch && cfg!(target_os = "linux");
+        Sender {
+            batch,
+            gso: AtomicBool::new(batch),
+        }
+    }
+
+    // Invia tutti i pacchetti a dst, nell'ordine
+    pub async fn send<P: AsRef<[u8]>>(
+        &self,
+        sock: &UdpSocket,
+        packets: &[P],
+        dst: SocketAddr,
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.batch {
+            return self.send_mmsg(sock, packets, dst).await;
+        }
+        for packet in packets {
+            sock.send_to(packet.as_ref(), dst).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_mmsg<P: AsRef<[u8]>>(
+        &self,
+        sock: &UdpSocket,
+        packets: &[P],
+        dst: SocketAddr,
+    ) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+        let dst = socket2::SockAddr::from(dst);
+        let mut sent = 0;
+        while sent < packets.len() {
+            sock.writable().await?;
+            let gso = self.gso.load(Ordering::Relaxed);
+            let res = sock.try_io(Interest::WRITABLE, || {
+                sys::sendmmsg(sock.as_raw_fd(), &packets[sent..], &dst, gso)
+            });
+            match res {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                // GSO non supportato dal kernel o dall'interfaccia: si riprova senza
+                Err(e) if gso && matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL)) => {
+                    self.gso.store(false, Ordering::Relaxed);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::net::SocketAddr;
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::ptr;
+    use tokio::net::UdpSocket;
+
+    // Limiti di UDP_SEGMENT: segmenti per messaggio e dimensione totale
+    const GSO_MAX_SEGMENTS: usize = 64;
+    const GSO_MAX_BYTES: usize = 65000;
+    // Messaggi per sendmmsg
+    const SEND_BATCH: usize = 64;
+    // Spazio per un cmsg con un intero, allineato
+    const CMSG_LEN: usize = 32;
+
+    pub struct RawMessage {
+        // Indice del buffer in cui è stato letto
+        pub buf: usize,
+        pub len: usize,
+        pub addr: SocketAddr,
+        pub truncated: bool,
+        pub segment: Option<usize>,
+    }
+
+    pub fn set_gro(sock: &UdpSocket) -> io::Result<()> {
+        let value: libc::c_int = 1;
+        let ret = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn recvmmsg(
+        fd: RawFd,
+        bufs: &mut [Vec<u8>],
+        headroom: usize,
+        gro: bool,
+    ) -> io::Result<Vec<RawMessage>> {
+        let count = bufs.len();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; count];
+        let mut iovs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf[headroom..].as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len() - headroom,
+            })
+            .collect();
+        let mut controls = vec![[0u64; CMSG_LEN / 8]; count];
+        let mut headers: Vec<libc::mmsghdr> = (0..count)
+            .map(|i| {
+                let mut h: libc::mmsghdr = unsafe { mem::zeroed() };
+                h.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+                h.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                h.msg_hdr.msg_iov = &mut iovs[i];
+                h.msg_hdr.msg_iovlen = 1;
+                if gro {
+                    h.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+                    h.msg_hdr.msg_controllen = CMSG_LEN as _;
+                }
+                h
+            })
+            .collect();
+        let ret = unsafe {
+            libc::recvmmsg(
+                fd,
+                headers.as_mut_ptr(),
+                count as libc::c_uint,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut messages = Vec::with_capacity(ret as usize);
+        for (i, h) in headers.iter().take(ret as usize).enumerate() {
+            let addr = unsafe { socket2::SockAddr::new(addrs[i], h.msg_hdr.msg_namelen) };
+            // Un mittente non inet viene saltato, i messaggi successivi
+            // restano associati al loro buffer tramite buf
+            let addr = match addr.as_socket() {
+                Some(addr) => addr,
+                None => continue,
+            };
+            messages.push(RawMessage {
+                buf: i,
+                len: h.msg_len as usize,
+                addr,
+                truncated: h.msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+                segment: if gro { gro_segment(&h.msg_hdr) } else { None },
+            });
+        }
+        Ok(messages)
+    }
+
+    // Dimensione dei datagram uniti dal kernel, dal cmsg UDP_GRO
+    fn gro_segment(hdr: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                    let value = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(value as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    // Divide i pacchetti nei messaggi di una sendmmsg, come intervalli
+    // [inizio, fine). Con GSO un messaggio raccoglie pacchetti consecutivi
+    // della stessa dimensione (l'ultimo può essere più corto).
+    fn gso_runs<P: AsRef<[u8]>>(packets: &[P], gso: bool) -> Vec<(usize, usize)> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        let mut i = 0;
+        while i < packets.len() && runs.len() < SEND_BATCH {
+            let segment = packets[i].as_ref().len();
+            let mut end = i + 1;
+            let mut total = segment;
+            while gso
+                && segment > 0
+                && end < packets.len()
+                && end - i < GSO_MAX_SEGMENTS
+                && total + packets[end].as_ref().len() <= GSO_MAX_BYTES
+                && packets[end].as_ref().len() <= segment
+            {
+                total += packets[end].as_ref().len();
+                end += 1;
+                if packets[end - 1].as_ref().len() < segment {
+                    break;
+                }
+            }
+            runs.push((i, end));
+            i = end;
+        }
+        runs
+    }
+
+    // Invia i pacchetti a dst, restituisce quanti sono partiti
+    pub fn sendmmsg<P: AsRef<[u8]>>(
+        fd: RawFd,
+        packets: &[P],
+        dst: &socket2::SockAddr,
+        gso: bool,
+    ) -> io::Result<usize> {
+        let runs = gso_runs(packets, gso);
+        let i = runs.last().map_or(0, |&(_, end)| end);
+
+        let mut iovs: Vec<libc::iovec> = packets[..i]
+            .iter()
+            .map(|p| libc::iovec {
+                iov_base: p.as_ref().as_ptr() as *mut libc::c_void,
+                iov_len: p.as_ref().len(),
+            })
+            .collect();
+        let mut controls = vec![[0u64; CMSG_LEN / 8]; runs.len()];
+        let mut headers: Vec<libc::mmsghdr> = Vec::with_capacity(runs.len());
+        for (r, &(start, end)) in runs.iter().enumerate() {
+            let mut h: libc::mmsghdr = unsafe { mem::zeroed() };
+            h.msg_hdr.msg_name = dst.as_ptr() as *mut libc::c_void;
+            h.msg_hdr.msg_namelen = dst.len();
+            h.msg_hdr.msg_iov = unsafe { iovs.as_mut_ptr().add(start) };
+            h.msg_hdr.msg_iovlen = (end - start) as _;
+            if end - start > 1 {
+                let space = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as u32) } as usize;
+                h.msg_hdr.msg_control = controls[r].as_mut_ptr() as *mut libc::c_void;
+                h.msg_hdr.msg_controllen = space as _;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(&h.msg_hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        packets[start].as_ref().len() as u16,
+                    );
+                }
+            }
+            headers.push(h);
+        }
+        let ret =
+            unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as libc::c_uint, 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(runs
+            .iter()
+            .take(ret as usize)
+            .map(|(start, end)| end - start)
+            .sum())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn sizes(lens: &[usize]) -> Vec<Vec<u8>> {
+            lens.iter().map(|&len| vec![0u8; len]).collect()
+        }
+
+        #[test]
+        fn same_size_packets_share_a_message() {
+            let packets = sizes(&[1200, 1200, 1200, 800, 1200]);
+            assert_eq!(gso_runs(&packets, true), vec![(0, 4), (4, 5)]);
+            // Senza GSO ogni pacchetto è un messaggio
+            assert_eq!(
+                gso_runs(&packets, false),
+                vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5)]
+            );
+        }
+
+        #[test]
+        fn bigger_packet_starts_a_new_message() {
+            let packets = sizes(&[500, 500, 1200, 1200]);
+            assert_eq!(gso_runs(&packets, true), vec![(0, 2), (2, 4)]);
+            // Un pacchetto vuoto non può fare da segmento
+            let packets = sizes(&[0, 0, 100]);
+            assert_eq!(gso_runs(&packets, true), vec![(0, 1), (1, 2), (2, 3)]);
+        }
+
+        #[test]
+        fn segment_and_byte_limits() {
+            let packets = sizes(&[100; GSO_MAX_SEGMENTS + 6]);
+            assert_eq!(
+                gso_runs(&packets, true),
+                vec![
+                    (0, GSO_MAX_SEGMENTS),
+                    (GSO_MAX_SEGMENTS, GSO_MAX_SEGMENTS + 6)
+                ]
+            );
+            // 1500 * 43 = 64500, il 44° supererebbe GSO_MAX_BYTES
+            let packets = sizes(&[1500; 50]);
+            assert_eq!(gso_runs(&packets, true), vec![(0, 43), (43, 50)]);
+        }
+
+        #[test]
+        fn at_most_one_batch_of_messages() {
+            let packets = sizes(&[100; SEND_BATCH + 10]);
+            let runs = gso_runs(&packets, false);
+            assert_eq!(runs.len(), SEND_BATCH);
+            assert_eq!(runs.last(), Some(&(SEND_BATCH - 1, SEND_BATCH)));
+        }
+    }
+}
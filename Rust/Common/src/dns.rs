@@ -0,0 +1,396 @@
+//
+// Risoluzione dei nomi delle destinazioni
+//
+// Una destinazione può essere un nome (host:porta). Il nome viene chiesto
+// direttamente ai nameserver di /etc/resolv.conf, così si conosce il TTL della
+// risposta e la richiesta può partire da un socket scelto dal chiamante (nel
+// client quello dell'interfaccia del path). Se nessun nameserver risponde con
+// un indirizzo si usa il resolver di sistema, che non dà il TTL (e legge anche
+// /etc/hosts).
+//
+// Un nameserver locale (come 127.0.0.53 di systemd-resolved) non si raggiunge
+// dal socket di un path: in quel caso si usano i nameserver a cui inoltra, da
+// /run/systemd/resolve/resolv.conf. Il resolver di sistema invece non passa
+// dall'interfaccia del path.
+//
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time;
+
+// Attesa della risposta di ogni nameserver
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// Nameserver usati da systemd-resolved
+const SYSTEMD_RESOLV_CONF: &str = "/run/systemd/resolve/resolv.conf";
+
+pub struct Answer {
+    pub addrs: Vec<IpAddr>,
+    // TTL più basso dei record, None se la risposta viene dal resolver di sistema
+    pub ttl: Option<Duration>,
+}
+
+// Divide "host:porta" se host è un nome valido. Gli indirizzi IP non sono nomi,
+// vanno letti come SocketAddr.
+pub fn split_host_port(dst: &str) -> Option<(&str, u16)> {
+    let (host, port) = dst.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let labels_ok = host.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    });
+    // Come in RFC 1123 l'ultima etichetta non è solo numerica, così un IPv4
+    // sbagliato non viene preso per un nome
+    let numeric = host.rsplit('.').next()?.bytes().all(|b| b.is_ascii_digit());
+    (host.len() <= 253 && labels_ok && !numeric).then_some((host, port))
+}
+
+// Indirizzi IPv4 (o IPv6) del nome. Con sock le richieste partono da quel
+// socket e vanno solo ai nameserver della sua famiglia, altrimenti partono da
+// un socket temporaneo.
+pub async fn resolve(host: &str, ipv6: bool, sock: Option<&UdpSocket>) -> Option<Answer> {
+    let qtype = if ipv6 { TYPE_AAAA } else { TYPE_A };
+    for server in nameservers(sock.is_some()) {
+        let answer = match sock {
+            Some(sock) => {
+                if sock.local_addr().ok()?.is_ipv6() != server.is_ipv6() {
+                    continue;
+                }
+                query(sock, server, host, qtype).await
+            }
+            None => {
+                let any = if server.is_ipv6() {
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                } else {
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+                };
+                match UdpSocket::bind(SocketAddr::new(any, 0)).await {
+                    Ok(tmp) => query(&tmp, server, host, qtype).await,
+                    Err(_) => continue,
+                }
+            }
+        };
+        if let Some(answer) = answer.filter(|a| !a.addrs.is_empty()) {
+            return Some(answer);
+        }
+    }
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, 0))
+        .await
+        .ok()?
+        .map(|addr| addr.ip())
+        .filter(|ip| ip.is_ipv6() == ipv6)
+        .collect();
+    (!addrs.is_empty()).then_some(Answer { addrs, ttl: None })
+}
+
+// Nameserver di /etc/resolv.conf. Con from_path i nameserver locali sono
+// sostituiti da quelli di systemd-resolved.
+fn nameservers(from_path: bool) -> Vec<SocketAddr> {
+    let read = |path| parse_resolv_conf(&std::fs::read_to_string(path).unwrap_or_default());
+    let mut servers = read(RESOLV_CONF);
+    if from_path && servers.iter().any(|s| s.ip().is_loopback()) {
+        servers.retain(|s| !s.ip().is_loopback());
+        for server in read(SYSTEMD_RESOLV_CONF) {
+            if !server.ip().is_loopback() && !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+    servers
+}
+
+fn parse_resolv_conf(text: &str) -> Vec<SocketAddr> {
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                return None;
+            }
+            let ip: IpAddr = words.next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, 53))
+        })
+        .collect()
+}
+
+async fn query(sock: &UdpSocket, server: SocketAddr, host: &str, qtype: u16) -> Option<Answer> {
+    // Id e porta casuali rendono difficile falsificare la risposta
+    let mut id = [0u8; 2];
+    getrandom::getrandom(&mut id).ok()?;
+    let id = u16::from_be_bytes(id);
+    sock.send_to(&build_query(id, host, qtype), server)
+        .await
+        .ok()?;
+    let mut buf = [0u8; 1500];
+    let reply = async {
+        loop {
+            let (len, from) = sock.recv_from(&mut buf).await.ok()?;
+            if from != server {
+                continue;
+            }
+            if let Some(answer) = parse_response(&buf[..len], id, qtype) {
+                return Some(answer);
+            }
+        }
+    };
+    time::timeout(QUERY_TIMEOUT, reply).await.ok().flatten()
+}
+
+// Richiesta ricorsiva con una sola domanda
+fn build_query(id: u16, host: &str, qtype: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(18 + host.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Flag: solo "recursion desired"; una domanda, nessun record
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.') {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    packet
+}
+
+// None se il pacchetto non è la risposta alla richiesta id. Una risposta con
+// errore (ad esempio nome inesistente) non ha indirizzi.
+fn parse_response(buf: &[u8], id: u16, qtype: u16) -> Option<Answer> {
+    if read_u16(buf, 0)? != id {
+        return None;
+    }
+    let flags = read_u16(buf, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    let questions = read_u16(buf, 4)?;
+    let records = if flags & 0x000f == 0 {
+        read_u16(buf, 6)?
+    } else {
+        0
+    };
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl: Option<u32> = None;
+    for _ in 0..records {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let rttl = u32::from_be_bytes(buf.get(pos + 4..pos + 8)?.try_into().ok()?);
+        let len = read_u16(buf, pos + 8)? as usize;
+        let data = buf.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        // Gli altri record (ad esempio i CNAME) non servono
+        if rtype != qtype {
+            continue;
+        }
+        let addr = match data.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(data).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+            _ => continue,
+        };
+        addrs.push(addr);
+        ttl = Some(ttl.map_or(rttl, |t| t.min(rttl)));
+    }
+    Some(Answer {
+        addrs,
+        ttl: ttl.map(|t| Duration::from_secs(t as u64)),
+    })
+}
+
+// Salta un nome, che può finire con un puntatore compresso
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len & 0xc0 {
+            0 if len == 0 => return Some(pos + 1),
+            0 => pos += 1 + len,
+            0xc0 => {
+                buf.get(pos + 1)?;
+                return Some(pos + 2);
+            }
+            // 0x40 e 0x80 sono tipi di etichetta riservati
+            _ => return None,
+        }
+    }
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: u16 = 0x1234;
+
+    fn name(packet: &mut Vec<u8>, host: &str) {
+        for label in host.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+    }
+
+    fn record(packet: &mut Vec<u8>, owner: &[u8], rtype: u16, ttl: u32, data: &[u8]) {
+        packet.extend_from_slice(owner);
+        packet.extend_from_slice(&rtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&ttl.to_be_bytes());
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(data);
+    }
+
+    // Risposta alla richiesta di build_query, senza record
+    fn response(host: &str, qtype: u16, records: u16) -> Vec<u8> {
+        let mut packet = build_query(ID, host, qtype);
+        packet[2] = 0x81;
+        packet[3] = 0x80;
+        packet[6..8].copy_from_slice(&records.to_be_bytes());
+        packet
+    }
+
+    // Puntatore compresso al nome della domanda
+    const QNAME: [u8; 2] = [0xc0, 12];
+
+    #[test]
+    fn address_records_with_compressed_names() {
+        let mut packet = response("vpn.example.com", TYPE_A, 2);
+        record(&mut packet, &QNAME, TYPE_A, 300, &[192, 0, 2, 1]);
+        record(&mut packet, &QNAME, TYPE_A, 60, &[192, 0, 2, 2]);
+        let answer = parse_response(&packet, ID, TYPE_A).unwrap();
+        assert_eq!(
+            answer.addrs,
+            vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])]
+        );
+        assert_eq!(answer.ttl, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn cname_chain() {
+        let mut packet = response("vpn.example.com", TYPE_AAAA, 3);
+        // vpn.example.com -> edge.example.com -> host.cdn.example.net
+        let edge = packet.len() + 12;
+        let mut target = vec![4];
+        target.extend_from_slice(b"edge");
+        target.extend_from_slice(&[0xc0, 16]);
+        record(&mut packet, &QNAME, 5, 3600, &target);
+        let mut host = Vec::new();
+        name(&mut host, "host.cdn.example.net");
+        record(&mut packet, &[0xc0, edge as u8], 5, 600, &host);
+        let ip = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+        record(&mut packet, &host, TYPE_AAAA, 30, &ip.octets());
+        let answer = parse_response(&packet, ID, TYPE_AAAA).unwrap();
+        assert_eq!(answer.addrs, vec![IpAddr::V6(ip)]);
+        // Solo il TTL dei record di indirizzo conta
+        assert_eq!(answer.ttl, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn error_response_has_no_addresses() {
+        let mut packet = response("missing.example.com", TYPE_A, 1);
+        // NXDOMAIN: i record eventualmente presenti non vengono letti
+        packet[3] = 0x83;
+        record(&mut packet, &QNAME, TYPE_A, 300, &[192, 0, 2, 1]);
+        let answer = parse_response(&packet, ID, TYPE_A).unwrap();
+        assert!(answer.addrs.is_empty());
+        assert_eq!(answer.ttl, None);
+    }
+
+    #[test]
+    fn other_responses_are_ignored() {
+        let mut packet = response("vpn.example.com", TYPE_A, 1);
+        record(&mut packet, &QNAME, TYPE_A, 300, &[192, 0, 2, 1]);
+        assert!(parse_response(&packet, ID + 1, TYPE_A).is_none());
+        // Una richiesta, non una risposta
+        assert!(parse_response(&build_query(ID, "vpn.example.com", TYPE_A), ID, TYPE_A).is_none());
+        // Record di un'altra famiglia
+        let answer = parse_response(&packet, ID, TYPE_AAAA).unwrap();
+        assert!(answer.addrs.is_empty());
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let mut packet = response("vpn.example.com", TYPE_A, 2);
+        record(
+            &mut packet,
+            &QNAME,
+            5,
+            300,
+            &[3, b'c', b'd', b'n', 0xc0, 16],
+        );
+        record(&mut packet, &QNAME, TYPE_A, 300, &[192, 0, 2, 1]);
+        assert!(parse_response(&packet, ID, TYPE_A).is_some());
+        for len in 0..packet.len() {
+            assert!(
+                parse_response(&packet[..len], ID, TYPE_A).is_none(),
+                "{}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn skip_name_labels() {
+        let mut packet = Vec::new();
+        name(&mut packet, "a.bc");
+        assert_eq!(skip_name(&packet, 0), Some(6));
+        // Etichette seguite da un puntatore
+        let buf = [1, b'a', 0xc0, 0];
+        assert_eq!(skip_name(&buf, 0), Some(4));
+        assert_eq!(skip_name(&buf[..3], 0), None);
+        // Etichetta più lunga del pacchetto
+        assert_eq!(skip_name(&[5, b'a', 0], 0), None);
+        // Tipi di etichetta riservati
+        assert_eq!(skip_name(&[0x41, b'a', 0], 0), None);
+        assert_eq!(skip_name(&[0x80, 0], 0), None);
+    }
+
+    #[test]
+    fn query_format() {
+        let packet = build_query(0xabcd, "vpn.example.com", TYPE_AAAA);
+        assert_eq!(&packet[..4], &[0xab, 0xcd, 0x01, 0x00]);
+        assert_eq!(read_u16(&packet, 4), Some(1));
+        assert_eq!(skip_name(&packet, 12), Some(packet.len() - 4));
+        assert_eq!(read_u16(&packet, packet.len() - 4), Some(TYPE_AAAA));
+    }
+
+    #[test]
+    fn resolv_conf_nameservers() {
+        let text = "# commento\nsearch lan\nnameserver 127.0.0.53\nnameserver 2001:db8::53\n\
+                    nameserver fe80::1%eth0\noptions edns0\n";
+        assert_eq!(
+            parse_resolv_conf(text),
+            vec![
+                "127.0.0.53:53".parse().unwrap(),
+                "[2001:db8::53]:53".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(
+            split_host_port("vpn.example.com:59401"),
+            Some(("vpn.example.com", 59401))
+        );
+        assert_eq!(split_host_port("localhost:1"), Some(("localhost", 1)));
+        assert_eq!(split_host_port("192.0.2.1:59401"), None);
+        assert_eq!(split_host_port("[2001:db8::1]:59401"), None);
+        assert_eq!(split_host_port("vpn.example.com"), None);
+        assert_eq!(split_host_port("-vpn.example.com:1"), None);
+        assert_eq!(split_host_port("vpn..example.com:1"), None);
+    }
+}
@@ -0,0 +1,510 @@
+//
+// FEC (forward error correction) sopra il framing engarde
+//
+// Ogni dataShards pacchetti il mittente calcola parityShards frame di parità
+// Reed-Solomon e li invia come gli altri pacchetti: chi riceve può ricostruire
+// fino a parityShards pacchetti persi del gruppo senza ritrasmissioni.
+//
+// Frame di parità: header engarde (tipo Parity, seq = primo pacchetto del
+// gruppo) seguito da
+//
+//   0    pacchetti di dati nel gruppo (k)
+//   1    frame di parità nel gruppo (m)
+//   2    indice di questo frame di parità (0..m)
+//   3    riservato
+//   4..  shard
+//
+// Ogni shard contiene la lunghezza del pacchetto (u16 big endian) e il
+// pacchetto, con zeri fino alla lunghezza del pacchetto più lungo del gruppo.
+//
+
+use crate::framing::{FrameType, Header, HEADER_LEN};
+use crate::pool::Packet;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const PARITY_HEADER_LEN: usize = 4;
+// Byte in più di un frame di parità rispetto al pacchetto più lungo del gruppo
+pub const PARITY_OVERHEAD: usize = PARITY_HEADER_LEN + 2;
+// Pacchetti ricevuti tenuti per la ricostruzione, senza copiarli
+const RING_SIZE: usize = 1024;
+// Un gruppo incompleto viene abbandonato dopo questo tempo
+const GROUP_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_PENDING_GROUPS: usize = 256;
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct FecConfig {
+    // Pacchetti di dati per gruppo
+    #[serde(rename = "dataShards")]
+    pub data_shards: usize,
+    // Frame di parità per gruppo, cioè quanti pacchetti persi si possono ricostruire
+    #[serde(rename = "parityShards")]
+    pub parity_shards: usize,
+}
+
+impl FecConfig {
+    pub fn is_valid(&self) -> bool {
+        self.data_shards >= 1
+            && self.parity_shards >= 1
+            && self.data_shards + self.parity_shards <= 255
+            && self.data_shards <= RING_SIZE / 4
+    }
+}
+
+fn to_shard(payload: &[u8], shard_len: usize) -> Vec<u8> {
+    let mut shard = vec![0u8; shard_len];
+    shard[..2].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    shard[2..2 + payload.len()].copy_from_slice(payload);
+    shard
+}
+
+// Il pacchetto contenuto in uno shard ricostruito
+fn from_shard(shard: Vec<u8>) -> Option<Packet> {
+    let len = u16::from_be_bytes([*shard.first()?, *shard.get(1)?]) as usize;
+    if 2 + len > shard.len() {
+        return None;
+    }
+    Some(Packet::from_vec(shard).slice(2..2 + len))
+}
+
+struct Encoder {
+    rs: ReedSolomon,
+    k: usize,
+    m: usize,
+    first_seq: u32,
+    packets: Vec<Vec<u8>>,
+}
+
+impl Encoder {
+    fn push(&mut self, header: &Header, payload: &[u8]) -> Vec<Vec<u8>> {
+        // Il gruppo è fatto di numeri di sequenza consecutivi
+        if self.first_seq.wrapping_add(self.packets.len() as u32) != header.seq {
+            self.packets.clear();
+        }
+        if self.packets.is_empty() {
+            self.first_seq = header.seq;
+        }
+        self.packets.push(payload.to_vec());
+        if self.packets.len() < self.k {
+            return Vec::new();
+        }
+
+        let shard_len = 2 + self.packets.iter().map(|p| p.len()).max().unwrap_or(0);
+        let mut shards: Vec<Vec<u8>> = self
+            .packets
+            .drain(..)
+            .map(|p| to_shard(&p, shard_len))
+            .collect();
+        shards.resize(self.k + self.m, vec![0u8; shard_len]);
+        if self.rs.encode(&mut shards).is_err() {
+            return Vec::new();
+        }
+        let parity_header = Header {
+            kind: FrameType::Parity,
+            session: header.session,
+            seq: self.first_seq,
+        };
+        shards[self.k..]
+            .iter()
+            .enumerate()
+            .map(|(idx, shard)| {
+                let mut frame = Vec::with_capacity(HEADER_LEN + PARITY_HEADER_LEN + shard_len);
+                frame.extend_from_slice(&parity_header.to_bytes());
+                frame.extend_from_slice(&[self.k as u8, self.m as u8, idx as u8, 0]);
+                frame.extend_from_slice(shard);
+                frame
+            })
+            .collect()
+    }
+}
+
+// Frame di parità ricevuti per un gruppo non ancora completo
+struct PendingGroup {
+    k: usize,
+    shard_len: usize,
+    parity: Vec<Option<Vec<u8>>>,
+    created: Instant,
+}
+
+// I gruppi sono identificati da (sessione, primo numero di sequenza)
+type GroupKey = (u16, u32);
+
+struct Decoder {
+    ring: Vec<Option<(u16, u32, Packet)>>,
+    // Per ogni numero di sequenza (come in ring) il gruppo in attesa che lo contiene
+    groups: Vec<Option<GroupKey>>,
+    pending: HashMap<GroupKey, PendingGroup>,
+    done: VecDeque<GroupKey>,
+    codecs: HashMap<(usize, usize), ReedSolomon>,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Decoder {
+            ring: vec![None; RING_SIZE],
+            groups: vec![None; RING_SIZE],
+            pending: HashMap::new(),
+            done: VecDeque::new(),
+            codecs: HashMap::new(),
+        }
+    }
+
+    fn lookup(&self, session: u16, seq: u32) -> Option<&[u8]> {
+        match &self.ring[seq as usize % RING_SIZE] {
+            Some((s, q, payload)) if *s == session && *q == seq => Some(payload),
+            _ => None,
+        }
+    }
+
+    fn store(&mut self, session: u16, seq: u32, payload: Packet) {
+        self.ring[seq as usize % RING_SIZE] = Some((session, seq, payload));
+    }
+
+    fn mark_done(&mut self, key: GroupKey) {
+        self.pending.remove(&key);
+        self.done.push_back(key);
+        if self.done.len() > MAX_PENDING_GROUPS {
+            self.done.pop_front();
+        }
+    }
+
+    fn on_data(&mut self, header: &Header, payload: &Packet) -> Vec<(Header, Packet)> {
+        self.store(header.session, header.seq, payload.clone());
+        // Un pacchetto in ritardo può completare un gruppo che aspettava
+        let key = match self.groups[header.seq as usize % RING_SIZE] {
+            Some(key) if key.0 == header.session => key,
+            _ => return Vec::new(),
+        };
+        match self.pending.get(&key) {
+            Some(group) if (header.seq.wrapping_sub(key.1) as usize) < group.k => {
+                self.try_recover(key)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_parity(&mut self, header: &Header, body: &[u8], now: Instant) -> Vec<(Header, Packet)> {
+        if body.len() < PARITY_HEADER_LEN + 2 {
+            return Vec::new();
+        }
+        let (k, m, idx) = (body[0] as usize, body[1] as usize, body[2] as usize);
+        let shard = &body[PARITY_HEADER_LEN..];
+        if k == 0 || idx >= m || k + m > 255 || k > RING_SIZE / 4 {
+            return Vec::new();
+        }
+        let key = (header.session, header.seq);
+        if self.done.contains(&key) {
+            return Vec::new();
+        }
+        self.pending
+            .retain(|_, group| now.duration_since(group.created) < GROUP_TIMEOUT);
+        if self.pending.len() >= MAX_PENDING_GROUPS && !self.pending.contains_key(&key) {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, group)| group.created)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+        if !self.pending.contains_key(&key) {
+            for i in 0..k as u32 {
+                self.groups[key.1.wrapping_add(i) as usize % RING_SIZE] = Some(key);
+            }
+        }
+        let group = self.pending.entry(key).or_insert_with(|| PendingGroup {
+            k,
+            shard_len: shard.len(),
+            parity: vec![None; m],
+            created: now,
+        });
+        if group.k != k || group.parity.len() != m || group.shard_len != shard.len() {
+            return Vec::new();
+        }
+        group.parity[idx] = Some(shard.to_vec());
+        self.try_recover(key)
+    }
+
+    fn try_recover(&mut self, key: GroupKey) -> Vec<(Header, Packet)> {
+        let (session, first_seq) = key;
+        let group = match self.pending.get(&key) {
+            Some(group) => group,
+            None => return Vec::new(),
+        };
+        let (k, m, shard_len) = (group.k, group.parity.len(), group.shard_len);
+        let seqs: Vec<u32> = (0..k as u32).map(|i| first_seq.wrapping_add(i)).collect();
+        let present = seqs
+            .iter()
+            .filter(|&&seq| self.lookup(session, seq).is_some())
+            .count();
+        if present == k {
+            self.mark_done(key);
+            return Vec::new();
+        }
+        let parity_present = group.parity.iter().filter(|p| p.is_some()).count();
+        if present + parity_present < k {
+            return Vec::new();
+        }
+
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(k + m);
+        for &seq in &seqs {
+            match self.lookup(session, seq) {
+                Some(payload) if payload.len() + 2 <= shard_len => {
+                    shards.push(Some(to_shard(payload, shard_len)))
+                }
+                Some(_) => {
+                    // Lunghezze non coerenti con la parità: il gruppo non è ricostruibile
+                    self.mark_done(key);
+                    return Vec::new();
+                }
+                None => shards.push(None),
+            }
+        }
+        shards.extend(group.parity.iter().cloned());
+        let rs = self
+            .codecs
+            .entry((k, m))
+            .or_insert_with(|| ReedSolomon::new(k, m).unwrap());
+        let reconstructed = rs.reconstruct_data(&mut shards).is_ok();
+        self.mark_done(key);
+        if !reconstructed {
+            return Vec::new();
+        }
+
+        let mut recovered = Vec::new();
+        for (i, &seq) in seqs.iter().enumerate() {
+            if self.lookup(session, seq).is_some() {
+                continue;
+            }
+            let payload = match shards[i].take().and_then(from_shard) {
+                Some(payload) => payload,
+                None => continue,
+            };
+            self.store(session, seq, payload.clone());
+            let header = Header {
+                kind: FrameType::Data,
+                session,
+                seq,
+            };
+            recovered.push((header, payload));
+        }
+        recovered
+    }
+}
+
+pub struct Fec {
+    encoder: Mutex<Encoder>,
+    decoder: Mutex<Decoder>,
+    pub parity_sent: AtomicU64,
+    pub recovered: AtomicU64,
+}
+
+impl Fec {
+    pub fn new(cfg: &FecConfig) -> Self {
+        let (k, m) = (cfg.data_shards, cfg.parity_shards);
+        Fec {
+            encoder: Mutex::new(Encoder {
+                rs: ReedSolomon::new(k, m).unwrap(),
+                k,
+                m,
+                first_seq: 0,
+                packets: Vec::with_capacity(k),
+            }),
+            decoder: Mutex::new(Decoder::new()),
+            parity_sent: AtomicU64::new(0),
+            recovered: AtomicU64::new(0),
+        }
+    }
+
+    // Aggiunge un pacchetto inviato al gruppo corrente: quando il gruppo è
+    // completo restituisce i frame di parità da inviare
+    pub fn encode(&self, header: &Header, payload: &[u8]) -> Vec<Vec<u8>> {
+        let frames = self.encoder.lock().unwrap().push(header, payload);
+        self.parity_sent
+            .fetch_add(frames.len() as u64, Ordering::Relaxed);
+        frames
+    }
+
+    // Un pacchetto di dati ricevuto (e non duplicato): restituisce gli
+    // eventuali pacchetti ricostruiti grazie al suo arrivo
+    pub fn on_data(&self, header: &Header, payload: &Packet) -> Vec<(Header, Packet)> {
+        let recovered = self.decoder.lock().unwrap().on_data(header, payload);
+        self.recovered
+            .fetch_add(recovered.len() as u64, Ordering::Relaxed);
+        recovered
+    }
+
+    // Un frame di parità ricevuto: restituisce i pacchetti ricostruiti
+    pub fn on_parity(&self, header: &Header, body: &[u8]) -> Vec<(Header, Packet)> {
+        let recovered = self
+            .decoder
+            .lock()
+            .unwrap()
+            .on_parity(header, body, Instant::now());
+        self.recovered
+            .fetch_add(recovered.len() as u64, Ordering::Relaxed);
+        recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(data_shards: usize, parity_shards: usize) -> FecConfig {
+        FecConfig {
+            data_shards,
+            parity_shards,
+        }
+    }
+
+    fn header(kind: FrameType, seq: u32) -> Header {
+        Header {
+            kind,
+            session: 7,
+            seq,
+        }
+    }
+
+    fn payload(seq: u32) -> Vec<u8> {
+        // Lunghezze diverse, per provare il riempimento degli shard
+        (0..10 + seq as usize % 7)
+            .map(|i| (seq as usize + i) as u8)
+            .collect()
+    }
+
+    // Codifica i pacchetti first..first+count, restituisce i frame di parità
+    fn encode(fec: &Fec, first: u32, count: u32) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| first.wrapping_add(i))
+            .flat_map(|seq| fec.encode(&header(FrameType::Data, seq), &payload(seq)))
+            .collect()
+    }
+
+    fn receive_parity(fec: &Fec, frame: &[u8]) -> Vec<(Header, Packet)> {
+        let (header, body) = Header::parse(frame).unwrap();
+        assert_eq!(header.kind, FrameType::Parity);
+        fec.on_parity(&header, body)
+    }
+
+    fn receive_data(fec: &Fec, seq: u32) -> Vec<(Header, Packet)> {
+        let packet = Packet::from_vec(payload(seq));
+        fec.on_data(&header(FrameType::Data, seq), &packet)
+    }
+
+    fn seqs(recovered: &[(Header, Packet)]) -> Vec<u32> {
+        for (header, data) in recovered {
+            assert_eq!(header.kind, FrameType::Data);
+            assert_eq!(header.session, 7);
+            assert_eq!(**data, payload(header.seq));
+        }
+        recovered.iter().map(|(header, _)| header.seq).collect()
+    }
+
+    #[test]
+    fn parity_only_when_group_is_full() {
+        let sender = Fec::new(&config(4, 2));
+        assert!(encode(&sender, 0, 3).is_empty());
+        assert_eq!(encode(&sender, 3, 1).len(), 2);
+        assert_eq!(sender.parity_sent.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn recovers_lost_data() {
+        let sender = Fec::new(&config(4, 2));
+        let receiver = Fec::new(&config(4, 2));
+        let parity = encode(&sender, 100, 4);
+        for seq in [100, 102] {
+            assert!(receive_data(&receiver, seq).is_empty());
+        }
+        assert!(receive_parity(&receiver, &parity[0]).is_empty());
+        assert_eq!(seqs(&receive_parity(&receiver, &parity[1])), vec![101, 103]);
+        assert_eq!(receiver.recovered.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn nothing_to_recover_when_parity_is_lost() {
+        let sender = Fec::new(&config(3, 2));
+        let receiver = Fec::new(&config(3, 2));
+        let parity = encode(&sender, 0, 3);
+        for seq in 0..3 {
+            assert!(receive_data(&receiver, seq).is_empty());
+        }
+        // Tutti i dati sono arrivati: la parità persa (o arrivata dopo) non serve
+        assert!(receive_parity(&receiver, &parity[1]).is_empty());
+        assert_eq!(receiver.recovered.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn too_many_losses_are_not_recovered() {
+        let sender = Fec::new(&config(4, 1));
+        let receiver = Fec::new(&config(4, 1));
+        let parity = encode(&sender, 0, 4);
+        for seq in [0, 1] {
+            receive_data(&receiver, seq);
+        }
+        assert!(receive_parity(&receiver, &parity[0]).is_empty());
+        // Un pacchetto in ritardo lascia un solo buco, che la parità ricostruisce
+        assert_eq!(seqs(&receive_data(&receiver, 3)), vec![2]);
+    }
+
+    #[test]
+    fn lost_group_does_not_affect_the_next() {
+        let sender = Fec::new(&config(2, 1));
+        let receiver = Fec::new(&config(2, 1));
+        // Il primo gruppo va perso del tutto, parità compresa
+        encode(&sender, 0, 2);
+        let parity = encode(&sender, 2, 2);
+        receive_data(&receiver, 3);
+        assert_eq!(seqs(&receive_parity(&receiver, &parity[0])), vec![2]);
+    }
+
+    #[test]
+    fn out_of_order_arrival() {
+        let sender = Fec::new(&config(4, 2));
+        let receiver = Fec::new(&config(4, 2));
+        let parity = encode(&sender, u32::MAX - 1, 4);
+        // La parità arriva prima dei dati, e il gruppo attraversa il wrap-around
+        assert!(receive_parity(&receiver, &parity[1]).is_empty());
+        assert!(receive_data(&receiver, 1).is_empty());
+        assert!(receive_parity(&receiver, &parity[0]).is_empty());
+        assert_eq!(
+            seqs(&receive_data(&receiver, u32::MAX)),
+            vec![u32::MAX - 1, 0]
+        );
+        // Un duplicato o un pacchetto in ritardo non ricostruisce di nuovo il gruppo
+        assert!(receive_data(&receiver, 0).is_empty());
+        assert!(receive_parity(&receiver, &parity[1]).is_empty());
+    }
+
+    #[test]
+    fn groups_restart_on_sequence_gaps() {
+        let sender = Fec::new(&config(3, 1));
+        let receiver = Fec::new(&config(3, 1));
+        // Il pacchetto 11 non è passato dal mittente (es. un frame non di dati)
+        encode(&sender, 10, 1);
+        let parity = encode(&sender, 12, 3);
+        assert_eq!(parity.len(), 1);
+        for seq in [12, 14] {
+            receive_data(&receiver, seq);
+        }
+        assert_eq!(seqs(&receive_parity(&receiver, &parity[0])), vec![13]);
+    }
+
+    #[test]
+    fn malformed_parity_is_ignored() {
+        let receiver = Fec::new(&config(2, 1));
+        let header = header(FrameType::Parity, 0);
+        assert!(receiver.on_parity(&header, &[2, 1, 0]).is_empty());
+        assert!(receiver.on_parity(&header, &[0, 1, 0, 0, 0, 0]).is_empty());
+        assert!(receiver.on_parity(&header, &[2, 1, 1, 0, 0, 0]).is_empty());
+        assert!(receiver
+            .on_parity(&header, &[200, 100, 0, 0, 0, 0])
+            .is_empty());
+    }
+}
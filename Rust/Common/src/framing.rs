@@ -0,0 +1,375 @@
+//
+// Framing engarde (opzionale, deve essere abilitato sia sul client che sul server)
+//
+// Ogni datagram inizia con un header di HEADER_LEN byte:
+//
+//   0      magic (0xE6, mai usato come primo byte da Wireguard)
+//   1      tipo di frame
+//   2..4   id di sessione (casuale ad ogni avvio del mittente)
+//   4..8   numero di sequenza (u32 big endian, con wrap-around)
+//
+// Il numero di sequenza è lo stesso su tutti i path, così il ricevente può
+// scartare i duplicati prima di passarli a Wireguard.
+//
+// I frame di probe usano lo stesso header (senza payload) anche quando il
+// framing dei dati non è abilitato: il magic li distingue dai pacchetti Wireguard.
+//
+// I frame di parità della FEC (vedi fec.rs) hanno come numero di sequenza il
+// primo pacchetto del gruppo che proteggono.
+//
+// I frame MtuProbe sono riempiti fino alla dimensione da provare, il
+// MtuProbeReply contiene la dimensione ricevuta (u16 big endian).
+//
+// I frame di autenticazione con la psk sono descritti in auth.rs.
+//
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub const HEADER_LEN: usize = 8;
+pub const MAGIC: u8 = 0xE6;
+
+// Bit della finestra di de-duplicazione
+const WINDOW_BITS: u32 = 4096;
+const WINDOW_WORDS: usize = (WINDOW_BITS / 64) as usize;
+// Un pacchetto così "vecchio" può solo significare che il mittente è ripartito
+const RESET_DISTANCE: u32 = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Data,
+    Probe,
+    ProbeReply,
+    Parity,
+    MtuProbe,
+    MtuProbeReply,
+    AuthHello,
+    AuthChallenge,
+    AuthResponse,
+    AuthOk,
+}
+
+impl FrameType {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameType::Data => 0,
+            FrameType::Probe => 1,
+            FrameType::ProbeReply => 2,
+            FrameType::Parity => 3,
+            FrameType::MtuProbe => 4,
+            FrameType::MtuProbeReply => 5,
+            FrameType::AuthHello => 6,
+            FrameType::AuthChallenge => 7,
+            FrameType::AuthResponse => 8,
+            FrameType::AuthOk => 9,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<FrameType> {
+        match b {
+            0 => Some(FrameType::Data),
+            1 => Some(FrameType::Probe),
+            2 => Some(FrameType::ProbeReply),
+            3 => Some(FrameType::Parity),
+            4 => Some(FrameType::MtuProbe),
+            5 => Some(FrameType::MtuProbeReply),
+            6 => Some(FrameType::AuthHello),
+            7 => Some(FrameType::AuthChallenge),
+            8 => Some(FrameType::AuthResponse),
+            9 => Some(FrameType::AuthOk),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: FrameType,
+    pub session: u16,
+    pub seq: u32,
+}
+
+impl Header {
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[0] = MAGIC;
+        buf[1] = self.kind.to_byte();
+        buf[2..4].copy_from_slice(&self.session.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.seq.to_be_bytes());
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut buf = [0u8; HEADER_LEN];
+        self.write(&mut buf);
+        buf
+    }
+
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] != MAGIC {
+            return None;
+        }
+        let header = Header {
+            kind: FrameType::from_byte(packet[1])?,
+            session: u16::from_be_bytes([packet[2], packet[3]]),
+            seq: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        };
+        Some((header, &packet[HEADER_LEN..]))
+    }
+}
+
+// Finestra scorrevole dei numeri di sequenza già visti (stile anti-replay IPsec)
+struct ReplayWindow {
+    session: Option<u16>,
+    highest: u32,
+    bitmap: [u64; WINDOW_WORDS],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow {
+            session: None,
+            highest: 0,
+            bitmap: [0; WINDOW_WORDS],
+        }
+    }
+
+    fn bit(seq: u32) -> (usize, u64) {
+        let idx = seq % WINDOW_BITS;
+        ((idx / 64) as usize, 1u64 << (idx % 64))
+    }
+
+    fn reset(&mut self, session: u16, seq: u32) {
+        self.session = Some(session);
+        self.highest = seq;
+        self.bitmap = [0; WINDOW_WORDS];
+        let (w, m) = Self::bit(seq);
+        self.bitmap[w] |= m;
+    }
+
+    // true se il pacchetto non era ancora stato visto
+    fn accept(&mut self, session: u16, seq: u32) -> bool {
+        if self.session != Some(session) {
+            self.reset(session, seq);
+            return true;
+        }
+        let ahead = seq.wrapping_sub(self.highest);
+        if ahead != 0 && ahead < u32::MAX / 2 {
+            if ahead >= WINDOW_BITS {
+                self.bitmap = [0; WINDOW_WORDS];
+            } else {
+                for i in 1..ahead {
+                    let (w, m) = Self::bit(self.highest.wrapping_add(i));
+                    self.bitmap[w] &= !m;
+                }
+            }
+            self.highest = seq;
+            let (w, m) = Self::bit(seq);
+            self.bitmap[w] |= m;
+            return true;
+        }
+        let behind = self.highest.wrapping_sub(seq);
+        if behind >= RESET_DISTANCE {
+            self.reset(session, seq);
+            return true;
+        }
+        if behind >= WINDOW_BITS {
+            return false;
+        }
+        let (w, m) = Self::bit(seq);
+        if self.bitmap[w] & m != 0 {
+            false
+        } else {
+            self.bitmap[w] |= m;
+            true
+        }
+    }
+}
+
+// Stato del framing di un tunnel: sequenza in uscita e de-duplicazione in entrata
+pub struct Framing {
+    session: u16,
+    next_seq: AtomicU32,
+    window: Mutex<ReplayWindow>,
+    pub unique: AtomicU64,
+    pub duplicates: AtomicU64,
+    pub invalid: AtomicU64,
+}
+
+impl Framing {
+    pub fn new() -> Self {
+        Framing {
+            session: random_session(),
+            next_seq: AtomicU32::new(0),
+            window: Mutex::new(ReplayWindow::new()),
+            unique: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            invalid: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next_header(&self, kind: FrameType) -> Header {
+        Header {
+            kind,
+            session: self.session,
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    // true se il frame va inoltrato, false se è un duplicato
+    pub fn accept(&self, header: &Header) -> bool {
+        let fresh = self
+            .window
+            .lock()
+            .unwrap()
+            .accept(header.session, header.seq);
+        if fresh {
+            self.unique.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+        }
+        fresh
+    }
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// La sessione distingue i riavvii del mittente: due processi avviati nello
+// stesso istante devono avere sessioni diverse
+fn random_session() -> u16 {
+    let mut buf = [0u8; 2];
+    match getrandom::getrandom(&mut buf) {
+        Ok(()) => u16::from_be_bytes(buf),
+        // Senza sorgente casuale ci si accontenta dell'orologio
+        Err(_) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(session: u16, seqs: &[u32]) -> ReplayWindow {
+        let mut w = ReplayWindow::new();
+        for &seq in seqs {
+            assert!(w.accept(session, seq));
+        }
+        w
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let mut w = window(1, &[0, 1, 2]);
+        assert!(!w.accept(1, 2));
+        assert!(!w.accept(1, 0));
+        assert!(w.accept(1, 3));
+        assert!(!w.accept(1, 3));
+    }
+
+    #[test]
+    fn reordered_packets_are_accepted_once() {
+        let mut w = window(1, &[10, 14]);
+        for seq in [13, 11, 12] {
+            assert!(w.accept(1, seq));
+            assert!(!w.accept(1, seq));
+        }
+        // I numeri saltati restano liberi anche dopo un salto in avanti
+        assert!(w.accept(1, 100));
+        assert!(w.accept(1, 50));
+        assert!(!w.accept(1, 14));
+    }
+
+    #[test]
+    fn packets_older_than_the_window_are_rejected() {
+        let mut w = window(1, &[WINDOW_BITS + 10]);
+        assert!(w.accept(1, 11));
+        assert!(!w.accept(1, 10));
+        assert!(!w.accept(1, 0));
+    }
+
+    #[test]
+    fn jump_past_the_window_clears_it() {
+        let mut w = window(1, &[5]);
+        let far = 5 + 3 * WINDOW_BITS;
+        assert!(w.accept(1, far));
+        // Stesso bit di 5 nella bitmap, ma è un pacchetto diverso
+        assert!(w.accept(1, far - WINDOW_BITS + 1));
+        assert!(!w.accept(1, far));
+    }
+
+    #[test]
+    fn sequence_wraparound() {
+        let mut w = window(1, &[u32::MAX - 2, u32::MAX]);
+        assert!(w.accept(1, 0));
+        assert!(w.accept(1, 1));
+        // In ritardo da prima del wrap-around, ancora nella finestra
+        assert!(w.accept(1, u32::MAX - 1));
+        assert!(!w.accept(1, u32::MAX - 1));
+        assert!(!w.accept(1, u32::MAX));
+        assert!(!w.accept(1, 0));
+        assert!(w.accept(1, 2));
+    }
+
+    #[test]
+    fn resync_after_reset_distance() {
+        let mut w = window(1, &[RESET_DISTANCE + 100]);
+        // Appena sotto RESET_DISTANCE è solo un pacchetto vecchio
+        assert!(!w.accept(1, 101));
+        // Da RESET_DISTANCE in poi il mittente è ripartito da capo
+        assert!(w.accept(1, 100));
+        assert!(w.accept(1, 101));
+        assert!(!w.accept(1, 100));
+    }
+
+    #[test]
+    fn new_session_resets_the_window() {
+        let mut w = window(1, &[1000]);
+        assert!(w.accept(2, 0));
+        assert!(!w.accept(2, 0));
+        assert!(w.accept(2, 1));
+        // La vecchia sessione è dimenticata
+        assert!(w.accept(1, 1000));
+    }
+
+    #[test]
+    fn framing_counts_duplicates() {
+        let sender = Framing::new();
+        let receiver = Framing::new();
+        let first = sender.next_header(FrameType::Data);
+        let second = sender.next_header(FrameType::Data);
+        assert_eq!(second.seq, first.seq.wrapping_add(1));
+        assert!(receiver.accept(&second));
+        assert!(receiver.accept(&first));
+        assert!(!receiver.accept(&first));
+        assert_eq!(receiver.unique.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.duplicates.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            kind: FrameType::Parity,
+            session: 0xBEEF,
+            seq: 0x01020304,
+        };
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(b"payload");
+        let (parsed, payload) = Header::parse(&packet).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(payload, b"payload");
+        assert!(Header::parse(&packet[..HEADER_LEN - 1]).is_none());
+        assert!(Header::parse(&[0u8; HEADER_LEN]).is_none());
+    }
+
+    #[test]
+    fn senders_started_together_get_different_sessions() {
+        let sessions: std::collections::HashSet<u16> =
+            (0..16).map(|_| Framing::new().session).collect();
+        assert!(sessions.len() > 1);
+    }
+}
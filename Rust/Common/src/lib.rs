@@ -0,0 +1,16 @@
+//
+// Codice condiviso tra il client e il server engarde
+//
+
+pub mod auth;
+pub mod batch;
+pub mod dns;
+pub mod fec;
+pub mod framing;
+pub mod metrics;
+pub mod pool;
+pub mod probe;
+pub mod queue;
+pub mod scheduler;
+pub mod tcp;
+pub mod web;
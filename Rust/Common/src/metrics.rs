@@ -0,0 +1,154 @@
+//
+// Metriche in formato testo Prometheus
+//
+
+use crate::fec::Fec;
+use crate::framing::Framing;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Contatori di traffico di un path
+#[derive(Default)]
+pub struct PathCounters {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub send_errors: AtomicU64,
+    pub write_timeouts: AtomicU64,
+    pub truncated: AtomicU64,
+    pub queue_drops: AtomicU64,
+    pub rate_limit_drops: AtomicU64,
+}
+
+impl PathCounters {
+    pub fn add_rx(&self, bytes: usize) {
+        self.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_tx(&self, bytes: usize) {
+        self.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn values(&self) -> [u64; 9] {
+        [
+            self.rx_packets.load(Ordering::Relaxed),
+            self.rx_bytes.load(Ordering::Relaxed),
+            self.tx_packets.load(Ordering::Relaxed),
+            self.tx_bytes.load(Ordering::Relaxed),
+            self.send_errors.load(Ordering::Relaxed),
+            self.write_timeouts.load(Ordering::Relaxed),
+            self.truncated.load(Ordering::Relaxed),
+            self.queue_drops.load(Ordering::Relaxed),
+            self.rate_limit_drops.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+// Nome e descrizione dei contatori di PathCounters, nello stesso ordine di values()
+pub const PATH_COUNTERS: [(&str, &str); 9] = [
+    ("rx_packets_total", "Packets received on the path"),
+    ("rx_bytes_total", "Bytes received on the path"),
+    ("tx_packets_total", "Packets sent on the path"),
+    ("tx_bytes_total", "Bytes sent on the path"),
+    ("send_errors_total", "Send errors on the path"),
+    ("write_timeouts_total", "Sends that exceeded writeTimeout"),
+    (
+        "truncated_total",
+        "Datagrams dropped because larger than maxDatagramSize",
+    ),
+    (
+        "queue_drops_total",
+        "Packets dropped because the send queue was full",
+    ),
+    (
+        "rate_limit_drops_total",
+        "Packets dropped by the maxBps/maxPps limit of the interface",
+    ),
+];
+
+// Nome e descrizione dei contatori del framing, nello stesso ordine di framing_values()
+pub const FRAMING_COUNTERS: [(&str, &str); 3] = [
+    (
+        "engarde_frames_unique_total",
+        "Unique frames forwarded to WireGuard",
+    ),
+    ("engarde_frames_duplicate_total", "Duplicate frames dropped"),
+    (
+        "engarde_frames_invalid_total",
+        "Unframed or invalid packets dropped",
+    ),
+];
+
+pub fn framing_values(framing: &Framing) -> [u64; 3] {
+    [
+        framing.unique.load(Ordering::Relaxed),
+        framing.duplicates.load(Ordering::Relaxed),
+        framing.invalid.load(Ordering::Relaxed),
+    ]
+}
+
+pub const FEC_COUNTERS: [(&str, &str); 2] = [
+    ("engarde_fec_parity_sent_total", "FEC parity frames sent"),
+    (
+        "engarde_fec_recovered_total",
+        "Lost packets rebuilt from FEC parity frames",
+    ),
+];
+
+pub fn fec_values(fec: &Fec) -> [u64; 2] {
+    [
+        fec.parity_sent.load(Ordering::Relaxed),
+        fec.recovered.load(Ordering::Relaxed),
+    ]
+}
+
+pub struct MetricsWriter {
+    out: String,
+}
+
+impl MetricsWriter {
+    pub fn new() -> Self {
+        MetricsWriter { out: String::new() }
+    }
+
+    // Intestazione di una famiglia, da scrivere prima dei suoi campioni
+    pub fn family(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (key, val)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"{}\"", key, escape_label(val));
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+impl Default for MetricsWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
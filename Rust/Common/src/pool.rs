@@ -0,0 +1,148 @@
+//
+// Pool di buffer per i pacchetti
+//
+// Un datagram letto da Wireguard diventa un Packet, condiviso in sola lettura
+// da tutti i path su cui viene inviato: clonarlo incrementa un contatore invece
+// di copiare i byte.
+//
+// Il pool appartiene al task che legge e tiene un riferimento ai pacchetti
+// che ha consegnato. Quando è rimasto l'unico, tutti i path li hanno inviati:
+// il pacchetto torna libero con il suo buffer e viene riusato per le letture
+// successive, così il fan-out non alloca.
+//
+
+use std::collections::VecDeque;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+// Pacchetti consegnati seguiti al massimo, oltre si smette di seguire i più
+// vecchi (bloccati in coda) e il pool ne crea di nuovi. La FEC tiene gli
+// ultimi 1024 pacchetti ricevuti: il limite deve restare sopra.
+const MAX_IN_FLIGHT: usize = 4096;
+
+pub struct BufferPool {
+    buf_len: usize,
+    free: Vec<Arc<Slot>>,
+    in_flight: VecDeque<Arc<Slot>>,
+}
+
+struct Slot {
+    buf: Vec<u8>,
+}
+
+impl BufferPool {
+    pub fn new(buf_len: usize) -> Self {
+        BufferPool {
+            buf_len,
+            free: Vec::new(),
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    // Un buffer di buf_len byte
+    pub fn buffer(&self) -> Vec<u8> {
+        vec![0u8; self.buf_len]
+    }
+
+    // Trasforma in pacchetto i byte range di buf senza copiarli: buf viene
+    // scambiato con il buffer libero di un pacchetto del pool
+    pub fn wrap(&mut self, buf: &mut Vec<u8>, range: Range<usize>) -> Packet {
+        self.reclaim();
+        let mut slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => Arc::new(Slot { buf: self.buffer() }),
+        };
+        let inner = Arc::get_mut(&mut slot).expect("free packet is shared");
+        std::mem::swap(&mut inner.buf, buf);
+        if self.in_flight.len() == MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(slot.clone());
+        Packet { slot, range }
+    }
+
+    // I pacchetti sono inviati più o meno in ordine: basta guardare i più vecchi
+    fn reclaim(&mut self) {
+        // Nessuno crea Weak dei pacchetti: con un solo riferimento il
+        // pacchetto è tornato libero
+        while let Some(front) = self.in_flight.front() {
+            if Arc::strong_count(front) > 1 {
+                break;
+            }
+            let slot = self.in_flight.pop_front().unwrap();
+            self.free.push(slot);
+        }
+    }
+}
+
+// Pacchetto condiviso: più pacchetti possono essere parti dello stesso buffer
+#[derive(Clone)]
+pub struct Packet {
+    slot: Arc<Slot>,
+    range: Range<usize>,
+}
+
+impl Packet {
+    // Pacchetto fuori dal pool, ad esempio un frame di parità
+    pub fn from_vec(buf: Vec<u8>) -> Self {
+        let range = 0..buf.len();
+        Packet {
+            slot: Arc::new(Slot { buf }),
+            range,
+        }
+    }
+
+    // I byte range del pacchetto, senza copiarli
+    pub fn slice(&self, range: Range<usize>) -> Packet {
+        assert!(range.start <= range.end && range.end <= self.len());
+        Packet {
+            slot: self.slot.clone(),
+            range: self.range.start + range.start..self.range.start + range.end,
+        }
+    }
+}
+
+impl Deref for Packet {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.slot.buf[self.range.clone()]
+    }
+}
+
+impl AsRef<[u8]> for Packet {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slices_share_the_buffer() {
+        let packet = Packet::from_vec(b"headerpayload".to_vec());
+        let payload = packet.slice(6..13);
+        assert_eq!(&*payload, b"payload");
+        assert_eq!(&*payload.slice(0..3), b"pay");
+        assert!(Arc::ptr_eq(&packet.slot, &payload.slot));
+    }
+
+    #[test]
+    fn sent_packets_return_to_the_pool() {
+        let mut pool = BufferPool::new(8);
+        let mut buf = pool.buffer();
+        buf[0] = 1;
+        let first = pool.wrap(&mut buf, 0..1);
+        let part = first.slice(0..1);
+        drop(first);
+        // Una parte ancora in uso tiene occupato il buffer
+        let second = pool.wrap(&mut buf, 0..1);
+        assert_eq!(pool.free.len(), 0);
+        drop((part, second));
+        pool.reclaim();
+        assert_eq!(pool.free.len(), 2);
+        assert_eq!(pool.in_flight.len(), 0);
+    }
+}
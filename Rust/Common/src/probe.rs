@@ -0,0 +1,203 @@
+//
+// Probe per path: RTT, jitter e perdita
+//
+// Ogni probe è un header di framing di tipo Probe, con un numero di sequenza
+// proprio del path. Chi lo riceve risponde sullo stesso path con un ProbeReply
+// identico; il mittente associa la risposta alla richiesta tramite la sequenza.
+//
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::framing::{FrameType, Header, HEADER_LEN};
+
+// Numero di probe considerati per la perdita
+const PROBE_WINDOW: usize = 32;
+// Oltre questo tempo un probe senza risposta è considerato perso
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// Con i probe un path è sano se uno ha avuto risposta di recente, anche a
+// tunnel fermo; la finestra copre almeno PROBE_HEALTH_INTERVALS probe
+pub const PROBE_HEALTH_INTERVALS: u32 = 3;
+
+struct PendingProbe {
+    seq: u32,
+    sent_at: Instant,
+    answered: bool,
+}
+
+pub struct ProbeStats {
+    next_seq: u32,
+    pending: VecDeque<PendingProbe>,
+    // Media mobile dell'RTT (come SRTT di TCP) e jitter come in RFC 3550, in ms
+    rtt: Option<f64>,
+    jitter: Option<f64>,
+    last_sample: Option<f64>,
+    // Ultima risposta ricevuta, per sapere se il path è vivo anche senza traffico
+    last_reply: Option<Instant>,
+}
+
+impl ProbeStats {
+    pub fn new() -> Self {
+        ProbeStats {
+            next_seq: 0,
+            pending: VecDeque::with_capacity(PROBE_WINDOW),
+            rtt: None,
+            jitter: None,
+            last_sample: None,
+            last_reply: None,
+        }
+    }
+
+    // Registra un nuovo probe e restituisce il pacchetto da inviare
+    pub fn next_probe(&mut self, now: Instant) -> [u8; HEADER_LEN] {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        if self.pending.len() == PROBE_WINDOW {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(PendingProbe {
+            seq,
+            sent_at: now,
+            answered: false,
+        });
+        Header {
+            kind: FrameType::Probe,
+            session: 0,
+            seq,
+        }
+        .to_bytes()
+    }
+
+    // Aggiorna RTT e jitter con la risposta ricevuta, ignorando duplicati e sconosciute
+    pub fn on_reply(&mut self, header: &Header, now: Instant) {
+        let probe = match self
+            .pending
+            .iter_mut()
+            .find(|p| p.seq == header.seq && !p.answered)
+        {
+            Some(p) => p,
+            None => return,
+        };
+        probe.answered = true;
+        self.last_reply = Some(now);
+        let sample = now.duration_since(probe.sent_at).as_secs_f64() * 1000.0;
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt * 7.0 / 8.0 + sample / 8.0,
+            None => sample,
+        });
+        if let Some(last) = self.last_sample {
+            let d = (sample - last).abs();
+            let jitter = self.jitter.unwrap_or(0.0);
+            self.jitter = Some(jitter + (d - jitter) / 16.0);
+        }
+        self.last_sample = Some(sample);
+    }
+
+    // true se un probe ha avuto risposta negli ultimi window
+    pub fn replied_within(&self, now: Instant, window: Duration) -> bool {
+        self.last_reply
+            .is_some_and(|last| now.saturating_duration_since(last) < window)
+    }
+
+    pub fn rtt_ms(&self) -> Option<f64> {
+        self.rtt.map(round_tenth)
+    }
+
+    pub fn jitter_ms(&self) -> Option<f64> {
+        self.jitter.map(round_tenth)
+    }
+
+    // Percentuale di probe persi tra quelli con una risposta o ormai scaduti
+    pub fn loss(&self, now: Instant) -> Option<f64> {
+        let mut total = 0;
+        let mut lost = 0;
+        for p in &self.pending {
+            if p.answered {
+                total += 1;
+            } else if now.duration_since(p.sent_at) > REPLY_TIMEOUT {
+                total += 1;
+                lost += 1;
+            }
+        }
+        if total == 0 {
+            return None;
+        }
+        Some(round_tenth(lost as f64 * 100.0 / total as f64))
+    }
+}
+
+impl Default for ProbeStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Risposta a un probe ricevuto: stesso header con il tipo ProbeReply
+pub fn reply_for(header: &Header) -> [u8; HEADER_LEN] {
+    Header {
+        kind: FrameType::ProbeReply,
+        ..*header
+    }
+    .to_bytes()
+}
+
+// Dimensione della risposta a un probe di MTU, i probe più corti vanno scartati
+pub const MTU_REPLY_LEN: usize = HEADER_LEN + 2;
+
+// Risposta a un probe di MTU di size byte: piccola, così misura solo l'andata
+pub fn mtu_reply_for(header: &Header, size: usize) -> [u8; MTU_REPLY_LEN] {
+    let mut reply = [0u8; MTU_REPLY_LEN];
+    Header {
+        kind: FrameType::MtuProbeReply,
+        ..*header
+    }
+    .write(&mut reply);
+    reply[HEADER_LEN..].copy_from_slice(&(size.min(u16::MAX as usize) as u16).to_be_bytes());
+    reply
+}
+
+fn round_tenth(v: f64) -> f64 {
+    (v * 10.0).round() / 10.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(seq: u32) -> Header {
+        Header {
+            kind: FrameType::ProbeReply,
+            session: 0,
+            seq,
+        }
+    }
+
+    #[test]
+    fn liveness_follows_replies() {
+        let start = Instant::now();
+        let window = Duration::from_secs(1);
+        let mut stats = ProbeStats::new();
+        stats.next_probe(start);
+        assert!(!stats.replied_within(start, window));
+        // Una risposta a un probe mai inviato non conta
+        stats.on_reply(&reply(7), start);
+        assert!(!stats.replied_within(start, window));
+        let answered = start + Duration::from_millis(50);
+        stats.on_reply(&reply(0), answered);
+        assert!(stats.replied_within(answered + Duration::from_millis(900), window));
+        assert!(!stats.replied_within(answered + window, window));
+        assert_eq!(stats.rtt_ms(), Some(50.0));
+    }
+
+    #[test]
+    fn loss_counts_expired_probes() {
+        let start = Instant::now();
+        let mut stats = ProbeStats::new();
+        for _ in 0..4 {
+            stats.next_probe(start);
+        }
+        stats.on_reply(&reply(1), start);
+        assert_eq!(stats.loss(start), Some(0.0));
+        assert_eq!(stats.loss(start + REPLY_TIMEOUT * 2), Some(75.0));
+    }
+}
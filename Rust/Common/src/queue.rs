@@ -0,0 +1,164 @@
+//
+// Coda di invio di un path
+//
+// Il task che legge da Wireguard mette i pacchetti nella coda di ogni path
+// scelto e passa subito al successivo, un task per path li invia. Così un link
+// lento o bloccato non ferma gli altri: quando la sua coda è piena i pacchetti
+// vengono scartati secondo la policy.
+//
+//   drop-newest  scarta il pacchetto appena arrivato
+//   drop-oldest  scarta il pacchetto più vecchio in coda
+//
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::pool::Packet;
+
+// Pacchetti in coda per path se sendQueueSize non è impostato
+pub const DEFAULT_QUEUE_SIZE: usize = 256;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum DropPolicy {
+    #[default]
+    DropNewest,
+    DropOldest,
+}
+
+pub struct SendQueue {
+    capacity: usize,
+    packets: Mutex<VecDeque<Packet>>,
+    notify: Notify,
+    closed: AtomicBool,
+}
+
+impl SendQueue {
+    pub fn new(capacity: usize) -> Self {
+        SendQueue {
+            capacity,
+            packets: Mutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    // Accoda il pacchetto, false se con la coda piena è stato scartato un pacchetto
+    pub fn push(&self, packet: Packet, policy: DropPolicy) -> bool {
+        let mut packets = self.packets.lock().unwrap();
+        let full = packets.len() >= self.capacity;
+        if full {
+            match policy {
+                DropPolicy::DropNewest => return false,
+                DropPolicy::DropOldest => {
+                    packets.pop_front();
+                }
+            }
+        }
+        packets.push_back(packet);
+        drop(packets);
+        self.notify.notify_one();
+        !full
+    }
+
+    // Aspetta almeno un pacchetto e sposta in out quelli in coda, al massimo max.
+    // false quando la coda è stata chiusa.
+    pub async fn pop(&self, out: &mut Vec<Packet>, max: usize) -> bool {
+        loop {
+            if self.closed.load(Ordering::Acquire) {
+                return false;
+            }
+            {
+                let mut packets = self.packets.lock().unwrap();
+                if !packets.is_empty() {
+                    let n = packets.len().min(max);
+                    out.extend(packets.drain(..n));
+                    return true;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.packets.lock().unwrap().len()
+    }
+
+    // Fa terminare il task che invia e libera i pacchetti in coda
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.packets.lock().unwrap().clear();
+        self.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(id: u8) -> Packet {
+        Packet::from_vec(vec![id])
+    }
+
+    fn contents(queue: &SendQueue) -> Vec<u8> {
+        queue.packets.lock().unwrap().iter().map(|p| p[0]).collect()
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_packets() {
+        let queue = SendQueue::new(2);
+        assert!(queue.push(packet(1), DropPolicy::DropNewest));
+        assert!(queue.push(packet(2), DropPolicy::DropNewest));
+        assert!(!queue.push(packet(3), DropPolicy::DropNewest));
+        assert_eq!(contents(&queue), [1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_the_new_packet() {
+        let queue = SendQueue::new(2);
+        assert!(queue.push(packet(1), DropPolicy::DropOldest));
+        assert!(queue.push(packet(2), DropPolicy::DropOldest));
+        assert!(!queue.push(packet(3), DropPolicy::DropOldest));
+        assert!(!queue.push(packet(4), DropPolicy::DropOldest));
+        assert_eq!(contents(&queue), [3, 4]);
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[test]
+    fn policy_names() {
+        let policy: DropPolicy = serde_yaml::from_str("drop-oldest").unwrap();
+        assert_eq!(policy, DropPolicy::DropOldest);
+        assert_eq!(DropPolicy::default(), DropPolicy::DropNewest);
+    }
+
+    #[tokio::test]
+    async fn pop_takes_at_most_max_in_order() {
+        let queue = SendQueue::new(8);
+        for id in 1..=5 {
+            queue.push(packet(id), DropPolicy::DropNewest);
+        }
+        let mut out = Vec::new();
+        assert!(queue.pop(&mut out, 3).await);
+        assert_eq!(out.iter().map(|p| p[0]).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(queue.depth(), 2);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_the_sender_and_frees_the_queue() {
+        let queue = std::sync::Arc::new(SendQueue::new(8));
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop(&mut Vec::new(), 8).await }
+        });
+        tokio::task::yield_now().await;
+        queue.push(packet(1), DropPolicy::DropNewest);
+        queue.close();
+        // Il task può aver preso il pacchetto prima della chiusura o no, ma termina
+        let _ = waiting.await.unwrap();
+        assert_eq!(queue.depth(), 0);
+        assert!(!queue.pop(&mut Vec::new(), 8).await);
+    }
+}
@@ -0,0 +1,343 @@
+//
+// Scelta dei path su cui inviare ogni pacchetto
+//
+//   redundant  ogni pacchetto su tutti i path (come nella versione Go)
+//   balance    ogni pacchetto su un solo path, ripartiti in base al peso
+//              (round robin pesato "smooth", come nginx)
+//   failover   solo sul path sano con la priorità migliore (valore più basso)
+//
+// Un path è sano se ha ricevuto qualcosa di recente: se nessun path è sano,
+// balance usa tutti i path e failover invia su tutti finché uno non risponde.
+//
+// Con select_with un path può rifiutare il pacchetto (es. per il limite di
+// banda): in balance e failover il pacchetto passa al path successivo.
+//
+// Lo stato di ogni path segue il suo indice in paths, così la scelta di un
+// pacchetto non alloca: set_paths lo riallinea quando i path cambiano.
+//
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Redundant,
+    Balance,
+    Failover,
+}
+
+pub struct PathInfo<'a> {
+    pub key: &'a str,
+    pub weight: u32,
+    pub priority: u32,
+    pub healthy: bool,
+}
+
+pub struct Scheduler {
+    // Chiavi dei path dell'ultimo set_paths
+    keys: Vec<String>,
+    // Peso accumulato di ogni path per il round robin pesato
+    current: Vec<i64>,
+    // Path scelto dall'ultimo invio in failover
+    active: Option<usize>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            keys: Vec::new(),
+            current: Vec::new(),
+            active: None,
+        }
+    }
+
+    // Indice in paths del path attivo in failover
+    pub fn active(&self) -> Option<usize> {
+        self.active
+    }
+
+    // Da chiamare quando si ricostruiscono i path, prima di select: i path
+    // che restano mantengono il loro stato anche se cambia l'indice
+    pub fn set_paths(&mut self, paths: &[PathInfo]) {
+        let same = self.keys.len() == paths.len()
+            && self
+                .keys
+                .iter()
+                .zip(paths)
+                .all(|(key, path)| key == path.key);
+        if same {
+            return;
+        }
+        let index = |key: &str| self.keys.iter().position(|k| k == key);
+        self.current = paths
+            .iter()
+            .map(|path| index(path.key).map_or(0, |i| self.current[i]))
+            .collect();
+        self.active = self
+            .active
+            .and_then(|a| paths.iter().position(|path| path.key == self.keys[a]));
+        self.keys = paths.iter().map(|path| path.key.to_string()).collect();
+    }
+
+    // Restituisce gli indici (in paths) dei path su cui inviare il pacchetto
+    pub fn select(&mut self, mode: Mode, paths: &[PathInfo]) -> Vec<usize> {
+        self.select_with(mode, paths, |_| true)
+    }
+
+    // Come select, ma un path viene usato solo se admit lo accetta
+    pub fn select_with(
+        &mut self,
+        mode: Mode,
+        paths: &[PathInfo],
+        mut admit: impl FnMut(usize) -> bool,
+    ) -> Vec<usize> {
+        // Senza set_paths se ne accorge almeno quando cambia il numero dei path
+        if self.current.len() != paths.len() {
+            self.set_paths(paths);
+        }
+        let mut healthy: Vec<usize> = (0..paths.len()).filter(|&i| paths[i].healthy).collect();
+        match mode {
+            Mode::Redundant => (0..paths.len()).filter(|&i| admit(i)).collect(),
+            Mode::Balance => {
+                let candidates = if healthy.is_empty() {
+                    (0..paths.len()).collect()
+                } else {
+                    healthy
+                };
+                self.weighted_pick(paths, &candidates, admit)
+                    .into_iter()
+                    .collect()
+            }
+            Mode::Failover => {
+                healthy.sort_by(|&a, &b| {
+                    (paths[a].priority, paths[a].key).cmp(&(paths[b].priority, paths[b].key))
+                });
+                match healthy.first() {
+                    Some(&best) => {
+                        // Il path attivo resta quello migliore anche quando
+                        // qualche pacchetto passa al successivo
+                        self.active = Some(best);
+                        healthy
+                            .into_iter()
+                            .find(|&i| admit(i))
+                            .into_iter()
+                            .collect()
+                    }
+                    None => {
+                        self.active = None;
+                        (0..paths.len()).filter(|&i| admit(i)).collect()
+                    }
+                }
+            }
+        }
+    }
+
+    fn weighted_pick(
+        &mut self,
+        paths: &[PathInfo],
+        candidates: &[usize],
+        mut admit: impl FnMut(usize) -> bool,
+    ) -> Option<usize> {
+        // Con tutti i pesi a zero ogni path vale 1
+        let all_zero = candidates.iter().all(|&i| paths[i].weight == 0);
+        let weight = |i: usize| {
+            if all_zero {
+                1
+            } else {
+                paths[i].weight as i64
+            }
+        };
+        let total: i64 = candidates.iter().map(|&i| weight(i)).sum();
+        let mut order: Vec<(usize, i64)> = Vec::with_capacity(candidates.len());
+        for &i in candidates {
+            if weight(i) == 0 {
+                continue;
+            }
+            self.current[i] += weight(i);
+            order.push((i, self.current[i]));
+        }
+        // Il peso accumulato più alto vince, a parità il primo. Se il path
+        // rifiuta il pacchetto si prova il successivo in quest'ordine.
+        order.sort_by_key(|&(_, cw)| std::cmp::Reverse(cw));
+        let mut charge = total;
+        for (i, _) in order {
+            if admit(i) {
+                self.current[i] -= charge;
+                return Some(i);
+            }
+            // Un path che rifiuta non accumula peso in questo giro, altrimenti
+            // appena torna libero riceverebbe tutti i pacchetti arretrati
+            self.current[i] -= weight(i);
+            charge -= weight(i);
+        }
+        None
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(key: &str, weight: u32, priority: u32, healthy: bool) -> PathInfo<'_> {
+        PathInfo {
+            key,
+            weight,
+            priority,
+            healthy,
+        }
+    }
+
+    fn picks(s: &mut Scheduler, mode: Mode, paths: &[PathInfo], n: usize) -> Vec<usize> {
+        (0..n).flat_map(|_| s.select(mode, paths)).collect()
+    }
+
+    #[test]
+    fn smooth_weighted_round_robin() {
+        let paths = [
+            path("a", 5, 0, true),
+            path("b", 1, 0, true),
+            path("c", 1, 0, true),
+        ];
+        let mut s = Scheduler::new();
+        // La sequenza di nginx per i pesi 5, 1, 1: mai tutti i pacchetti di
+        // "a" di seguito
+        let expected = [0, 0, 1, 0, 2, 0, 0];
+        assert_eq!(
+            picks(&mut s, Mode::Balance, &paths, 14),
+            [expected, expected].concat()
+        );
+    }
+
+    #[test]
+    fn balance_follows_weights() {
+        let paths = [
+            path("a", 3, 0, true),
+            path("b", 2, 0, true),
+            path("c", 0, 0, true),
+        ];
+        let mut s = Scheduler::new();
+        let sent = picks(&mut s, Mode::Balance, &paths, 500);
+        let count = |i| sent.iter().filter(|&&p| p == i).count();
+        assert_eq!((count(0), count(1), count(2)), (300, 200, 0));
+    }
+
+    #[test]
+    fn balance_uses_healthy_paths_only() {
+        let paths = [
+            path("a", 1, 0, false),
+            path("b", 1, 0, true),
+            path("c", 1, 0, true),
+        ];
+        let mut s = Scheduler::new();
+        let sent = picks(&mut s, Mode::Balance, &paths, 10);
+        assert!(!sent.contains(&0));
+        assert_eq!(sent.iter().filter(|&&p| p == 1).count(), 5);
+        // Nessun path sano: si usano tutti
+        let down = [path("a", 1, 0, false), path("b", 1, 0, false)];
+        let sent = picks(&mut s, Mode::Balance, &down, 4);
+        assert_eq!(sent.iter().filter(|&&p| p == 0).count(), 2);
+    }
+
+    #[test]
+    fn balance_with_all_weights_zero() {
+        // Come i path di riserva: tutti a zero valgono 1
+        let paths = [path("a", 0, 0, true), path("b", 0, 0, true)];
+        let mut s = Scheduler::new();
+        assert_eq!(picks(&mut s, Mode::Balance, &paths, 4), vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn failover_order() {
+        let mut paths = [
+            path("c", 1, 2, true),
+            path("b", 1, 1, true),
+            path("a", 1, 1, true),
+        ];
+        let mut s = Scheduler::new();
+        // Priorità più bassa, a parità il nome
+        assert_eq!(s.select(Mode::Failover, &paths), vec![2]);
+        assert_eq!(s.active(), Some(2));
+        paths[2].healthy = false;
+        assert_eq!(s.select(Mode::Failover, &paths), vec![1]);
+        assert_eq!(s.active(), Some(1));
+        paths[1].healthy = false;
+        assert_eq!(s.select(Mode::Failover, &paths), vec![0]);
+        // Il path migliore torna sano
+        paths[2].healthy = true;
+        assert_eq!(s.select(Mode::Failover, &paths), vec![2]);
+        // Nessun path sano: si invia su tutti finché uno non risponde
+        for p in paths.iter_mut() {
+            p.healthy = false;
+        }
+        assert_eq!(s.select(Mode::Failover, &paths), vec![0, 1, 2]);
+        assert_eq!(s.active(), None);
+    }
+
+    #[test]
+    fn balance_skips_refused_path() {
+        let paths = [path("a", 1, 0, true), path("b", 1, 0, true)];
+        let mut s = Scheduler::new();
+        // "a" è oltre il limite: tutti i pacchetti vanno su "b"
+        for _ in 0..4 {
+            assert_eq!(s.select_with(Mode::Balance, &paths, |i| i != 0), vec![1]);
+        }
+        // Nessun path accetta: il pacchetto è perso
+        assert!(s.select_with(Mode::Balance, &paths, |_| false).is_empty());
+        // Tolto il limite i due path si alternano di nuovo
+        let sent = picks(&mut s, Mode::Balance, &paths, 4);
+        assert_eq!(sent.iter().filter(|&&i| i == 0).count(), 2);
+    }
+
+    #[test]
+    fn failover_overflows_to_next_priority() {
+        let paths = [
+            path("a", 1, 20, true),
+            path("b", 1, 10, true),
+            path("c", 1, 30, true),
+        ];
+        let mut s = Scheduler::new();
+        assert_eq!(s.select_with(Mode::Failover, &paths, |i| i != 1), vec![0]);
+        // Il path attivo resta quello con la priorità migliore
+        assert_eq!(s.active(), Some(1));
+        assert_eq!(s.select_with(Mode::Failover, &paths, |i| i == 2), vec![2]);
+        assert!(s.select_with(Mode::Failover, &paths, |_| false).is_empty());
+    }
+
+    #[test]
+    fn redundant_drops_only_refused_paths() {
+        let paths = [path("a", 1, 0, true), path("b", 1, 0, false)];
+        let mut s = Scheduler::new();
+        assert_eq!(s.select_with(Mode::Redundant, &paths, |i| i == 1), vec![1]);
+        assert_eq!(s.select(Mode::Redundant, &paths), vec![0, 1]);
+    }
+
+    #[test]
+    fn state_follows_the_path_when_indexes_change() {
+        let mut s = Scheduler::new();
+        let paths = [path("b", 2, 0, true), path("c", 1, 0, true)];
+        s.set_paths(&paths);
+        assert_eq!(picks(&mut s, Mode::Balance, &paths, 2), vec![0, 1]);
+        // Arriva "a": "b" e "c" cambiano indice ma non perdono il peso accumulato
+        let more = [
+            path("a", 0, 0, true),
+            path("b", 2, 0, true),
+            path("c", 1, 0, true),
+        ];
+        s.set_paths(&more);
+        assert_eq!(picks(&mut s, Mode::Balance, &more, 4), vec![1, 1, 2, 1]);
+
+        assert_eq!(s.select(Mode::Failover, &more), vec![0]);
+        let fewer = [path("b", 1, 0, true), path("a", 1, 1, true)];
+        s.set_paths(&fewer);
+        assert_eq!(s.active(), Some(1));
+        s.set_paths(&paths);
+        assert_eq!(s.active(), None);
+    }
+}
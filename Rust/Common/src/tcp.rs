@@ -0,0 +1,155 @@
+//
+// Trasporto TCP
+//
+// Sulle reti che bloccano o rallentano UDP un path può passare da una
+// connessione TCP. Ogni datagram è preceduto dalla sua lunghezza su 2 byte
+// (big endian). La scrittura la fa un task dedicato: chi invia non aspetta mai
+// la rete, e un invio annullato (ad esempio per writeTimeout) non può lasciare
+// sulla connessione un datagram scritto a metà.
+//
+
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+
+// Blocchi di datagram in attesa di essere scritti, oltre si scartano
+const WRITE_QUEUE: usize = 256;
+
+pub struct TcpLink {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl TcpLink {
+    // Il task di scrittura termina quando il TcpLink viene rilasciato o la
+    // scrittura fallisce, e chiude la connessione
+    pub fn new(mut writer: OwnedWriteHalf) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITE_QUEUE);
+        tokio::spawn(async move {
+            while let Some(buf) = rx.recv().await {
+                if writer.write_all(&buf).await.is_err() {
+                    break;
+                }
+            }
+            let _ = writer.shutdown().await;
+        });
+        TcpLink { tx }
+    }
+
+    // Accoda i datagram. Errore se la connessione è chiusa o la coda è piena.
+    pub fn send<P: AsRef<[u8]>>(&self, packets: &[P]) -> io::Result<()> {
+        let len: usize = packets.iter().map(|p| p.as_ref().len() + 2).sum();
+        let mut buf = Vec::with_capacity(len);
+        for packet in packets {
+            let packet = packet.as_ref();
+            let n = u16::try_from(packet.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "datagram too large"))?;
+            buf.extend_from_slice(&n.to_be_bytes());
+            buf.extend_from_slice(packet);
+        }
+        self.tx.try_send(buf).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => io::Error::from(io::ErrorKind::WouldBlock),
+            mpsc::error::TrySendError::Closed(_) => io::Error::from(io::ErrorKind::BrokenPipe),
+        })
+    }
+}
+
+// Legge un datagram in buf e ne restituisce la lunghezza. Un datagram più
+// grande di buf è un errore: la connessione non è più utilizzabile.
+pub async fn read_datagram<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let len = u16::from_be_bytes(len) as usize;
+    if len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("datagram of {} bytes, larger than maxDatagramSize", len),
+        ));
+    }
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // Restituisce i dati al massimo chunk byte per lettura, come una
+    // connessione lenta
+    struct Chunked {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let end = (self.pos + self.chunk)
+                .min(self.data.len())
+                .min(self.pos + buf.remaining());
+            buf.put_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn framed(datagrams: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for d in datagrams {
+            data.extend_from_slice(&(d.len() as u16).to_be_bytes());
+            data.extend_from_slice(d);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn datagrams_survive_partial_reads() {
+        let mut reader = Chunked {
+            data: framed(&[b"first", b"", b"third datagram"]),
+            pos: 0,
+            chunk: 1,
+        };
+        let mut buf = [0u8; 64];
+        let n = read_datagram(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"first");
+        assert_eq!(read_datagram(&mut reader, &mut buf).await.unwrap(), 0);
+        let n = read_datagram(&mut reader, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"third datagram");
+        // Fine della connessione tra due datagram
+        let e = read_datagram(&mut reader, &mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn length_larger_than_the_buffer_is_an_error() {
+        let mut reader = &framed(&[&[7u8; 65]])[..];
+        let mut buf = [0u8; 64];
+        let e = read_datagram(&mut reader, &mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // Esattamente la dimensione del buffer va bene
+        let mut reader = &framed(&[&[7u8; 64]])[..];
+        assert_eq!(read_datagram(&mut reader, &mut buf).await.unwrap(), 64);
+    }
+
+    #[tokio::test]
+    async fn eof_in_the_middle_of_a_datagram_is_an_error() {
+        let data = framed(&[b"truncated"]);
+        let mut buf = [0u8; 64];
+        // Nel mezzo del corpo e nel mezzo della lunghezza
+        for cut in [data.len() - 3, 1] {
+            let mut reader = &data[..cut];
+            let e = read_datagram(&mut reader, &mut buf).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
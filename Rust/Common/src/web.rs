@@ -0,0 +1,109 @@
+//
+// Autenticazione HTTP Basic dei web manager
+//
+
+use base64::Engine;
+use warp::http::{Response, StatusCode};
+use warp::Filter;
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    // Scorre sempre la stringa più lunga, così il tempo non dipende
+    // dalla posizione del primo byte diverso.
+    let len = a.len().max(b.len());
+    let mut diff = a.len() ^ b.len();
+    for i in 0..len {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+pub fn check_basic_auth(header: Option<&str>, username: &str, password: &str) -> bool {
+    let encoded = match header.and_then(|h| h.strip_prefix("Basic ")) {
+        Some(e) => e.trim(),
+        None => return false,
+    };
+    let decoded = match base64::engine::general_purpose::STANDARD.decode(encoded) {
+        Ok(d) => d,
+        Err(_) => return false,
+    };
+    let (user, pass) = match decoded.iter().position(|&b| b == b':') {
+        Some(idx) => (&decoded[..idx], &decoded[idx + 1..]),
+        None => return false,
+    };
+    // Entrambi i confronti vengono sempre eseguiti
+    let user_ok = constant_time_eq(user, username.as_bytes());
+    let pass_ok = constant_time_eq(pass, password.as_bytes());
+    user_ok & pass_ok
+}
+
+// credentials restituisce utente e password correnti (None se il web manager
+// non è configurato): vengono letti ad ogni richiesta, così valgono subito
+// dopo un reload
+pub fn with_auth<F>(credentials: F) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone
+where
+    F: Fn() -> Option<(String, String)> + Clone + Send + Sync + 'static,
+{
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let ok = credentials()
+                .is_some_and(|(user, pass)| check_basic_auth(header.as_deref(), &user, &pass));
+            async move {
+                if ok {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn handle_rejection(err: warp::Rejection) -> Result<impl warp::Reply, warp::Rejection> {
+    if err.find::<Unauthorized>().is_some() {
+        Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("WWW-Authenticate", "Basic realm=\"engarde\"")
+            .body(String::new()))
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(credentials: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn compare() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"a"));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let ok = |h: &str| check_basic_auth(Some(h), "admin", "pa:ss");
+        assert!(ok(&header("admin:pa:ss")));
+        assert!(!ok(&header("admin:pa")));
+        assert!(!ok(&header("root:pa:ss")));
+        assert!(!ok(&header("adminpass")));
+        assert!(!ok("Basic !!!"));
+        assert!(!ok(&header("admin:pa:ss").replace("Basic", "Bearer")));
+        assert!(!check_basic_auth(None, "admin", "pa:ss"));
+    }
+}
@@ -1,13 +1,33 @@
+use engarde_common::auth::{self, Verifier, COOKIE_LEN};
+use engarde_common::batch::{Receiver, Sender, BATCH_SIZE};
+use engarde_common::dns;
+use engarde_common::fec::{Fec, FecConfig, PARITY_OVERHEAD};
+use engarde_common::framing::{FrameType, Framing, Header, HEADER_LEN};
+use engarde_common::metrics::{
+    fec_values, framing_values, MetricsWriter, PathCounters, FEC_COUNTERS, FRAMING_COUNTERS,
+    PATH_COUNTERS,
+};
+use engarde_common::pool::{BufferPool, Packet};
+use engarde_common::probe::{
+    mtu_reply_for, reply_for, ProbeStats, MTU_REPLY_LEN, PROBE_HEALTH_INTERVALS,
+};
+use engarde_common::queue::{DropPolicy, SendQueue, DEFAULT_QUEUE_SIZE};
+use engarde_common::scheduler::{Mode, PathInfo, Scheduler};
+use engarde_common::tcp::{read_datagram, TcpLink};
+use engarde_common::web;
 use rust_embed::RustEmbed;
-use warp::http::Response;
-use warp::Filter;
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::{net::UdpSocket, task};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use warp::http::Response;
+use warp::Filter;
 
 use serde::Deserialize;
 
@@ -20,25 +40,408 @@ struct Config {
     server: ServerConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct ServerConfig {
     description: Option<String>,
-    listenAddr: String,
-    dstAddr: String,
+    // Se presente crea il gruppo "default"
+    #[serde(rename = "listenAddr", default)]
+    listen_addr: String,
+    // Altri indirizzi del gruppo "default", ad esempio uno per ogni uplink del server
+    #[serde(rename = "listenAddrs", default)]
+    listen_addrs: Vec<String>,
+    // Porta TCP del gruppo "default" per i client su reti che bloccano UDP
+    #[serde(rename = "tcpListenAddr")]
+    tcp_listen_addr: Option<String>,
+    #[serde(rename = "dstAddr", default)]
+    dst_addr: String,
+    // Ogni quanti secondi risolvere di nuovo un dstAddr con un nome, se
+    // assente si segue il TTL della risposta
+    #[serde(rename = "dnsRefresh")]
+    dns_refresh: Option<u64>,
+    // Chiave condivisa con i client: un path viene aggiunto solo dopo aver
+    // dimostrato di conoscerla (vedi auth.rs)
+    psk: Option<String>,
+    // Con la psk, millisecondi concessi a una connessione TCP per autenticarsi
+    #[serde(rename = "tcpAuthTimeout")]
+    tcp_auth_timeout: Option<u64>,
+    // Connessioni TCP contemporanee di ogni gruppo, per indirizzo sorgente e in
+    // totale: oltre le nuove vengono chiuse subito
+    #[serde(rename = "tcpMaxPerSource")]
+    tcp_max_per_source: Option<usize>,
+    #[serde(rename = "tcpMaxConnections")]
+    tcp_max_connections: Option<usize>,
+    // Secondi senza datagram prima di chiudere una connessione TCP, se assente
+    // clientTimeout
+    #[serde(rename = "tcpIdleTimeout")]
+    tcp_idle_timeout: Option<u64>,
     // in millisecondi
-    writeTimeout: Option<u64>,
+    #[serde(rename = "writeTimeout")]
+    write_timeout: Option<u64>,
     // in secondi
-    clientTimeout: Option<u64>,
-    webManager: Option<WebManagerConfig>,
+    #[serde(rename = "clientTimeout")]
+    client_timeout: Option<u64>,
+    #[serde(rename = "webManager")]
+    web_manager: Option<WebManagerConfig>,
+    // Gruppi di client aggiuntivi, ognuno con la sua porta e il suo peer Wireguard
+    #[serde(default)]
+    groups: Vec<ClientGroupConfig>,
+    // Header engarde con numero di sequenza, va abilitato anche sui client
+    #[serde(default)]
+    framing: bool,
+    // Intervallo dei probe di RTT/jitter/perdita verso ogni path, in millisecondi
+    #[serde(rename = "probeInterval")]
+    probe_interval: Option<u64>,
+    // Come distribuire i pacchetti verso i client: redundant, balance o failover
+    #[serde(default)]
+    mode: Mode,
+    // Un path è sano se ha ricevuto qualcosa negli ultimi healthTimeout millisecondi
+    #[serde(rename = "healthTimeout")]
+    health_timeout: Option<u64>,
+    // Smette di inviare a un path che non riceve nulla (o, con probeInterval,
+    // non risponde ai probe) da pruneTimeout millisecondi, finché c'è un altro
+    // path vivo. L'invio riprende appena il path riceve di nuovo.
+    #[serde(rename = "pruneTimeout")]
+    prune_timeout: Option<u64>,
+    // Frame di parità Reed-Solomon per ricostruire i pacchetti persi, richiede il framing
+    fec: Option<FecConfig>,
+    // Dimensione massima di un pacchetto Wireguard, i più grandi vengono scartati
+    #[serde(rename = "maxDatagramSize")]
+    max_datagram_size: Option<usize>,
+    // Su Linux legge e scrive più datagram per chiamata (recvmmsg/sendmmsg, GSO/GRO)
+    #[serde(rename = "batchIo", default)]
+    batch_io: bool,
+    // Pacchetti in coda per ogni client e cosa scartare quando la coda è piena
+    #[serde(rename = "sendQueueSize")]
+    send_queue_size: Option<usize>,
+    #[serde(rename = "dropPolicy", default)]
+    drop_policy: DropPolicy,
+    // Quando rimuovere un client che non riesce a ricevere
+    #[serde(rename = "sendFailures", default)]
+    send_failures: SendFailureConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+struct SendFailureConfig {
+    // Errori consecutivi prima di rimuovere il client: temporanei (ENOBUFS,
+    // writeTimeout scaduto, ...) e di destinazione irraggiungibile
+    transient: Option<u32>,
+    unreachable: Option<u32>,
+    // Attesa massima prima di riprovare dopo un errore, in millisecondi
+    #[serde(rename = "maxBackoff")]
+    max_backoff: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+struct ClientGroupConfig {
+    name: String,
+    #[serde(rename = "listenAddr")]
+    listen_addr: String,
+    // Altri indirizzi su cui il gruppo riceve i client
+    #[serde(rename = "listenAddrs", default)]
+    listen_addrs: Vec<String>,
+    // Indirizzo TCP su cui il gruppo riceve i client, se assente solo UDP
+    #[serde(rename = "tcpListenAddr")]
+    tcp_listen_addr: Option<String>,
+    // Se assente usa il dstAddr del server
+    #[serde(rename = "dstAddr")]
+    dst_addr: Option<String>,
+    // Se assente usa l'impostazione del server
+    framing: Option<bool>,
+    // Se assente usa il mode del server
+    mode: Option<Mode>,
+    // Se assente usa la psk del server
+    psk: Option<String>,
+    // Se assente usa la fec del server
+    fec: Option<FecConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 struct WebManagerConfig {
-    listenAddr: String,
+    #[serde(rename = "listenAddr")]
+    listen_addr: String,
     username: String,
     password: String,
 }
 
+// Configurazione corrente, sostituita quando viene ricaricata con SIGHUP
+type SharedConfig = Arc<Mutex<ServerConfig>>;
+
+fn load_config(path: &str) -> Result<ServerConfig, String> {
+    let config_str =
+        std::fs::read_to_string(path).map_err(|e| format!("Errore leggendo {}: {}", path, e))?;
+    let config: Config =
+        serde_yaml::from_str(&config_str).map_err(|e| format!("Errore parseando config: {}", e))?;
+    let server = config.server;
+    if let Some(size) = server.max_datagram_size {
+        if !(MIN_DATAGRAM_SIZE..=MAX_DATAGRAM_SIZE).contains(&size) {
+            return Err(format!(
+                "maxDatagramSize {} non valido, deve essere tra {} e {}",
+                size, MIN_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE
+            ));
+        }
+    }
+    if server.send_queue_size == Some(0) {
+        return Err("sendQueueSize 0 non valido, deve essere almeno 1".to_string());
+    }
+    let failures = &server.send_failures;
+    if failures.transient == Some(0) || failures.unreachable == Some(0) {
+        return Err("sendFailures non valido, le soglie devono essere almeno 1".to_string());
+    }
+    if server.prune_timeout == Some(0) {
+        return Err("pruneTimeout 0 non valido, deve essere almeno 1".to_string());
+    }
+    if server.dns_refresh == Some(0) {
+        return Err("dnsRefresh 0 non valido, deve essere almeno 1".to_string());
+    }
+    if server.tcp_max_per_source == Some(0) || server.tcp_max_connections == Some(0) {
+        return Err(
+            "tcpMaxPerSource e tcpMaxConnections non validi, devono essere almeno 1".to_string(),
+        );
+    }
+    if server.tcp_idle_timeout == Some(0) {
+        return Err("tcpIdleTimeout 0 non valido, deve essere almeno 1".to_string());
+    }
+    if server.tcp_auth_timeout == Some(0) {
+        return Err("tcpAuthTimeout 0 non valido, deve essere almeno 1".to_string());
+    }
+    // Verifica gruppi e indirizzi senza tenere i gruppi creati
+    build_client_groups(&server).map_err(|e| format!("Errore nella config: {}", e))?;
+    if let Some(web) = &server.web_manager {
+        web.listen_addr.parse::<SocketAddr>().map_err(|e| {
+            format!(
+                "listenAddr {} del webManager non valido: {}",
+                web.listen_addr, e
+            )
+        })?;
+    }
+    Ok(server)
+}
+
+//
+// Ricaricamento della configurazione
+//
+
+// Annota un'impostazione cambiata che viene applicata subito
+fn note_applied<T: PartialEq>(
+    name: &'static str,
+    current: &T,
+    new: &T,
+    applied: &mut Vec<&'static str>,
+) {
+    if current != new {
+        applied.push(name);
+    }
+}
+
+// Un'impostazione che richiede il riavvio mantiene il valore corrente
+fn keep_current<T: PartialEq + Clone>(
+    name: &'static str,
+    current: &T,
+    new: &mut T,
+    restart: &mut Vec<&'static str>,
+) {
+    if current != new {
+        restart.push(name);
+        *new = current.clone();
+    }
+}
+
+// Restituisce la configurazione da usare, le impostazioni applicate e quelle
+// che richiedono il riavvio
+fn merge_reload(
+    current: &ServerConfig,
+    mut new: ServerConfig,
+) -> (ServerConfig, Vec<&'static str>, Vec<&'static str>) {
+    let mut applied = Vec::new();
+    let mut restart = Vec::new();
+    note_applied(
+        "description",
+        &current.description,
+        &new.description,
+        &mut applied,
+    );
+    note_applied(
+        "writeTimeout",
+        &current.write_timeout,
+        &new.write_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "clientTimeout",
+        &current.client_timeout,
+        &new.client_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "dropPolicy",
+        &current.drop_policy,
+        &new.drop_policy,
+        &mut applied,
+    );
+    note_applied(
+        "tcpMaxPerSource",
+        &current.tcp_max_per_source,
+        &new.tcp_max_per_source,
+        &mut applied,
+    );
+    note_applied(
+        "tcpMaxConnections",
+        &current.tcp_max_connections,
+        &new.tcp_max_connections,
+        &mut applied,
+    );
+    note_applied(
+        "tcpIdleTimeout",
+        &current.tcp_idle_timeout,
+        &new.tcp_idle_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "tcpAuthTimeout",
+        &current.tcp_auth_timeout,
+        &new.tcp_auth_timeout,
+        &mut applied,
+    );
+    keep_current(
+        "listenAddr",
+        &current.listen_addr,
+        &mut new.listen_addr,
+        &mut restart,
+    );
+    keep_current(
+        "listenAddrs",
+        &current.listen_addrs,
+        &mut new.listen_addrs,
+        &mut restart,
+    );
+    keep_current(
+        "tcpListenAddr",
+        &current.tcp_listen_addr,
+        &mut new.tcp_listen_addr,
+        &mut restart,
+    );
+    keep_current(
+        "dstAddr",
+        &current.dst_addr,
+        &mut new.dst_addr,
+        &mut restart,
+    );
+    keep_current("groups", &current.groups, &mut new.groups, &mut restart);
+    keep_current("framing", &current.framing, &mut new.framing, &mut restart);
+    keep_current("psk", &current.psk, &mut new.psk, &mut restart);
+    keep_current("fec", &current.fec, &mut new.fec, &mut restart);
+    keep_current(
+        "maxDatagramSize",
+        &current.max_datagram_size,
+        &mut new.max_datagram_size,
+        &mut restart,
+    );
+    keep_current(
+        "batchIo",
+        &current.batch_io,
+        &mut new.batch_io,
+        &mut restart,
+    );
+    keep_current(
+        "sendQueueSize",
+        &current.send_queue_size,
+        &mut new.send_queue_size,
+        &mut restart,
+    );
+    note_applied(
+        "healthTimeout",
+        &current.health_timeout,
+        &new.health_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "sendFailures",
+        &current.send_failures,
+        &new.send_failures,
+        &mut applied,
+    );
+    note_applied(
+        "pruneTimeout",
+        &current.prune_timeout,
+        &new.prune_timeout,
+        &mut applied,
+    );
+    note_applied(
+        "dnsRefresh",
+        &current.dns_refresh,
+        &new.dns_refresh,
+        &mut applied,
+    );
+    // I gruppi senza mode passano al nuovo dal prossimo pacchetto. Con la fec
+    // mode: redundant non è valido e load_config rifiuta la configurazione
+    note_applied("mode", &current.mode, &new.mode, &mut applied);
+    keep_current(
+        "probeInterval",
+        &current.probe_interval,
+        &mut new.probe_interval,
+        &mut restart,
+    );
+    match (&current.web_manager, &mut new.web_manager) {
+        (Some(cur), Some(web)) => {
+            if cur.username != web.username || cur.password != web.password {
+                applied.push("credenziali webManager");
+            }
+            keep_current(
+                "webManager.listenAddr",
+                &cur.listen_addr,
+                &mut web.listen_addr,
+                &mut restart,
+            );
+        }
+        (None, None) => {}
+        _ => keep_current(
+            "webManager",
+            &current.web_manager,
+            &mut new.web_manager,
+            &mut restart,
+        ),
+    }
+    (new, applied, restart)
+}
+
+async fn reload_on_sighup(config_path: String, config: SharedConfig) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::warn!("Impossibile installare il gestore di SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        log::info!("SIGHUP ricevuto, ricarico {}", config_path);
+        let new = match load_config(&config_path) {
+            Ok(server) => server,
+            Err(e) => {
+                log::warn!("Configurazione non ricaricata: {}", e);
+                continue;
+            }
+        };
+        let mut current = config.lock().unwrap();
+        let (merged, applied, restart) = merge_reload(&current, new);
+        *current = merged;
+        if applied.is_empty() {
+            log::info!("Configurazione ricaricata, nessuna modifica applicata");
+        } else {
+            log::info!(
+                "Configurazione ricaricata, applicato: {}",
+                applied.join(", ")
+            );
+        }
+        if !restart.is_empty() {
+            log::warn!(
+                "Modifiche che richiedono il riavvio (ignorate): {}",
+                restart.join(", ")
+            );
+        }
+    }
+}
+
 //
 // Stato dei client
 //
@@ -47,10 +450,451 @@ struct WebManagerConfig {
 struct ConnectedClient {
     addr: SocketAddr,
     last: Instant,
+    // Ultimo pacchetto di dati (non probe): in failover si risponde sul path
+    // che il client sta usando
+    last_data: Instant,
+    // Ultima risposta a un probe del server
+    last_reply: Instant,
+    // Frame ricevuti dal path e, all'ultima lettura della web API,
+    // (frame del path, frame unici del gruppo) per calcolare la perdita
+    frames: u64,
+    loss_check: (u64, u64),
+    probe: Arc<Mutex<ProbeStats>>,
+    counters: Arc<PathCounters>,
+    // Pacchetti da inviare al client, svuotata dal task send_to_client
+    queue: Arc<SendQueue>,
+    // Errori di invio consecutivi: se più di zero il path è degradato
+    send_failures: Arc<AtomicU32>,
+    // Da dove arrivano i pacchetti del client: le risposte partono
+    // dall'indirizzo a cui il client invia, anche se cambia (ad esempio quando
+    // il client risolve il nome del server in un altro indirizzo)
+    link: Arc<Mutex<ClientLink>>,
+}
+
+impl ConnectedClient {
+    fn link(&self) -> ClientLink {
+        self.link.lock().unwrap().clone()
+    }
+
+    // Path giudicato morto: non riceve nulla da prune_timeout oppure, se il
+    // server invia probe, non risponde da prune_timeout più un intervallo
+    // (ad esempio se si è interrotta solo la direzione verso il client)
+    fn is_dead(
+        &self,
+        now: Instant,
+        prune_timeout: Duration,
+        probe_interval: Option<Duration>,
+    ) -> bool {
+        if now.duration_since(self.last) > prune_timeout {
+            return true;
+        }
+        probe_interval
+            .is_some_and(|interval| now.duration_since(self.last_reply) > prune_timeout + interval)
+    }
+
+    // Come nel client: se il server invia probe conta l'ultima risposta,
+    // altrimenti l'ultimo pacchetto ricevuto dal path
+    fn is_healthy(
+        &self,
+        now: Instant,
+        health_timeout: Duration,
+        probe_interval: Option<Duration>,
+    ) -> bool {
+        match probe_interval {
+            Some(interval) => {
+                let window = health_timeout.max(interval * PROBE_HEALTH_INTERVALS);
+                now.duration_since(self.last_reply) < window
+            }
+            None => now.duration_since(self.last) < health_timeout,
+        }
+    }
+}
+
+// Path su cui sospendere l'invio; senza pruneTimeout nessuno
+fn dead_paths(
+    clients: &[(String, ConnectedClient)],
+    now: Instant,
+    prune: Option<(Duration, Option<Duration>)>,
+) -> HashSet<String> {
+    match prune {
+        Some((prune_timeout, probe_interval)) => clients
+            .iter()
+            .filter(|(_, client)| client.is_dead(now, prune_timeout, probe_interval))
+            .map(|(key, _)| key.clone())
+            .collect(),
+        None => HashSet::new(),
+    }
 }
 
 type Clients = Arc<Mutex<HashMap<String, ConnectedClient>>>;
 
+// Come si raggiunge un client: dal socket UDP del server su cui arrivano i suoi
+// pacchetti o dalla sua connessione TCP
+#[derive(Clone)]
+enum ClientLink {
+    Udp(Arc<UdpSocket>),
+    Tcp {
+        link: Arc<TcpLink>,
+        local: SocketAddr,
+    },
+}
+
+impl ClientLink {
+    async fn send<P: AsRef<[u8]>>(
+        &self,
+        sender: &Sender,
+        packets: &[P],
+        addr: SocketAddr,
+    ) -> std::io::Result<()> {
+        match self {
+            ClientLink::Udp(socket) => sender.send(socket, packets, addr).await,
+            ClientLink::Tcp { link, .. } => link.send(packets),
+        }
+    }
+
+    async fn send_to(&self, packet: &[u8], addr: SocketAddr) -> std::io::Result<()> {
+        match self {
+            ClientLink::Udp(socket) => socket.send_to(packet, addr).await.map(|_| ()),
+            ClientLink::Tcp { link, .. } => link.send(&[packet]),
+        }
+    }
+
+    fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ClientLink::Udp(socket) => socket.local_addr().ok(),
+            ClientLink::Tcp { local, .. } => Some(*local),
+        }
+    }
+
+    fn transport(&self) -> &'static str {
+        match self {
+            ClientLink::Udp(_) => "udp",
+            ClientLink::Tcp { .. } => "tcp",
+        }
+    }
+}
+
+// Ogni gruppo ha i suoi socket e i suoi path: i pacchetti di Wireguard
+// ricevuti sul socket di un gruppo vengono inviati solo ai client di quel gruppo.
+#[derive(Clone)]
+struct ClientGroup {
+    name: String,
+    // Un socket per ogni indirizzo, il primo è listenAddr
+    listen_addrs: Vec<String>,
+    tcp_listen_addr: Option<String>,
+    // dstAddr configurato (anche un nome) e indirizzo in uso, None finché il
+    // nome non è risolto
+    dst: String,
+    dst_addr: Arc<Mutex<Option<SocketAddr>>>,
+    clients: Clients,
+    framing: Option<Arc<Framing>>,
+    fec: Option<Arc<Fec>>,
+    // Autenticazione dei path, se il gruppo ha una psk
+    auth: Option<Arc<GroupAuth>>,
+    // mode del gruppo; senza si usa quello generale, che si può ricaricare
+    mode: Option<Mode>,
+    // Dimensione massima dei pacchetti di Wireguard (maxDatagramSize)
+    max_datagram: usize,
+    batch_io: bool,
+    // Invio sul socket dei client, condiviso dai task dei client
+    sender: Arc<Sender>,
+    // Dimensione della coda di invio di ogni client (sendQueueSize)
+    send_queue: usize,
+    stats: Arc<GroupStats>,
+    // Connessioni TCP aperte, per sorgente e in totale
+    tcp_conns: Arc<Mutex<TcpConnections>>,
+}
+
+impl ClientGroup {
+    // Byte aggiunti da engarde al pacchetto di Wireguard nel caso peggiore
+    fn overhead(&self) -> usize {
+        let framing = if self.framing.is_some() {
+            HEADER_LEN
+        } else {
+            0
+        };
+        let fec = if self.fec.is_some() {
+            PARITY_OVERHEAD
+        } else {
+            0
+        };
+        framing + fec
+    }
+
+    fn dst_addr(&self) -> Option<SocketAddr> {
+        *self.dst_addr.lock().unwrap()
+    }
+
+    // Posto per una nuova connessione TCP da ip, None oltre i limiti
+    fn tcp_slot(&self, ip: IpAddr, max_per_source: usize, max_total: usize) -> Option<TcpSlot> {
+        let mut conns = self.tcp_conns.lock().unwrap();
+        if conns.total >= max_total {
+            return None;
+        }
+        let from_source = conns.per_source.entry(ip).or_insert(0);
+        if *from_source >= max_per_source {
+            return None;
+        }
+        *from_source += 1;
+        conns.total += 1;
+        Some(TcpSlot {
+            conns: self.tcp_conns.clone(),
+            ip,
+        })
+    }
+}
+
+// Default di tcpMaxPerSource e tcpMaxConnections
+const DEFAULT_TCP_MAX_PER_SOURCE: usize = 8;
+const DEFAULT_TCP_MAX_CONNECTIONS: usize = 256;
+
+#[derive(Default)]
+struct TcpConnections {
+    per_source: HashMap<IpAddr, usize>,
+    total: usize,
+}
+
+// Una connessione TCP accettata, libera il suo posto quando viene rilasciata
+struct TcpSlot {
+    conns: Arc<Mutex<TcpConnections>>,
+    ip: IpAddr,
+}
+
+impl Drop for TcpSlot {
+    fn drop(&mut self) {
+        let mut conns = self.conns.lock().unwrap();
+        conns.total -= 1;
+        if let Some(from_source) = conns.per_source.get_mut(&self.ip) {
+            *from_source -= 1;
+            if *from_source == 0 {
+                conns.per_source.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Contatori del gruppo per /metrics: path registrati, rimossi e sospesi,
+// datagram troncati ricevuti dai client e da Wireguard, pacchetti scartati
+// perché il mittente non è autenticato, risposte di autenticazione non valide e
+// connessioni TCP rifiutate per i limiti
+#[derive(Default)]
+struct GroupStats {
+    ups: AtomicU64,
+    downs: AtomicU64,
+    prunes: AtomicU64,
+    truncated: AtomicU64,
+    wg_truncated: AtomicU64,
+    unauthenticated: AtomicU64,
+    auth_failures: AtomicU64,
+    tcp_rejected: AtomicU64,
+    evictions: Mutex<Evictions>,
+}
+
+// Client rimossi di recente con il motivo, per la web API, e rimozioni per motivo
+#[derive(Default)]
+struct Evictions {
+    recent: VecDeque<Eviction>,
+    totals: BTreeMap<&'static str, u64>,
+}
+
+struct Eviction {
+    address: String,
+    reason: &'static str,
+    detail: String,
+    at: Instant,
+}
+
+const MAX_EVICTIONS: usize = 20;
+
+impl GroupStats {
+    // Registra la rimozione di un client dal gruppo
+    fn evicted(&self, address: &str, reason: &'static str, detail: String) {
+        self.downs.fetch_add(1, Ordering::Relaxed);
+        let mut evictions = self.evictions.lock().unwrap();
+        *evictions.totals.entry(reason).or_insert(0) += 1;
+        if evictions.recent.len() == MAX_EVICTIONS {
+            evictions.recent.pop_front();
+        }
+        evictions.recent.push_back(Eviction {
+            address: address.to_string(),
+            reason,
+            detail,
+            at: Instant::now(),
+        });
+    }
+}
+
+// Al massimo un avviso per le risposte di autenticazione non valide in questo
+// intervallo, per non riempire il log
+const AUTH_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+// Autenticazione dei path di un gruppo con la psk (vedi auth.rs)
+struct GroupAuth {
+    verifier: Verifier,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl GroupAuth {
+    fn new(psk: &str) -> Result<Self, String> {
+        let verifier = Verifier::new(psk.as_bytes())
+            .map_err(|e| format!("impossibile generare il segreto dei cookie: {}", e))?;
+        Ok(GroupAuth {
+            verifier,
+            last_warning: Mutex::new(None),
+        })
+    }
+
+    // true se è passato abbastanza tempo dall'ultimo avviso
+    fn should_warn(&self, now: Instant) -> bool {
+        let mut last = self.last_warning.lock().unwrap();
+        if last.is_some_and(|at| now.duration_since(at) < AUTH_WARNING_INTERVAL) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+}
+
+// Motivi di rimozione di un client
+const EVICT_TIMEOUT: &str = "client-timeout";
+const EVICT_TRANSIENT: &str = "send-failures";
+const EVICT_UNREACHABLE: &str = "unreachable";
+const EVICT_TCP_CLOSED: &str = "tcp-closed";
+
+// Soglie di default di sendFailures e prima attesa dopo un errore
+const DEFAULT_TRANSIENT_FAILURES: u32 = 50;
+const DEFAULT_UNREACHABLE_FAILURES: u32 = 3;
+const DEFAULT_MAX_BACKOFF: u64 = 1000;
+const MIN_BACKOFF: Duration = Duration::from_millis(1);
+
+// Limiti di maxDatagramSize: il minimo di IPv4 e il massimo payload UDP
+const DEFAULT_DATAGRAM_SIZE: usize = 1500;
+const MIN_DATAGRAM_SIZE: usize = 576;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+type ClientGroups = Arc<Vec<ClientGroup>>;
+
+fn build_client_groups(server: &ServerConfig) -> Result<Vec<ClientGroup>, String> {
+    let mut configs = Vec::new();
+    if !server.listen_addr.is_empty() {
+        configs.push(ClientGroupConfig {
+            name: "default".to_string(),
+            listen_addr: server.listen_addr.clone(),
+            listen_addrs: server.listen_addrs.clone(),
+            tcp_listen_addr: server.tcp_listen_addr.clone(),
+            dst_addr: None,
+            framing: None,
+            mode: None,
+            psk: None,
+            fec: None,
+        });
+    }
+    configs.extend(server.groups.iter().cloned());
+    if configs.is_empty() {
+        return Err("nessun listenAddr o gruppo configurato".to_string());
+    }
+
+    let mut groups: Vec<ClientGroup> = Vec::new();
+    for conf in configs {
+        if groups.iter().any(|g| g.name == conf.name) {
+            return Err(format!("gruppo {} duplicato", conf.name));
+        }
+        let mut listen_addrs = vec![conf.listen_addr];
+        listen_addrs.extend(conf.listen_addrs);
+        for (i, addr) in listen_addrs.iter().enumerate() {
+            addr.parse::<SocketAddr>().map_err(|e| {
+                format!(
+                    "listenAddr {} del gruppo {} non valido: {}",
+                    addr, conf.name, e
+                )
+            })?;
+            if groups.iter().any(|g| g.listen_addrs.contains(addr))
+                || listen_addrs[..i].contains(addr)
+            {
+                return Err(format!("listenAddr {} usato più volte", addr));
+            }
+        }
+        if let Some(addr) = &conf.tcp_listen_addr {
+            addr.parse::<SocketAddr>().map_err(|e| {
+                format!(
+                    "tcpListenAddr {} del gruppo {} non valido: {}",
+                    addr, conf.name, e
+                )
+            })?;
+            if groups
+                .iter()
+                .any(|g| g.tcp_listen_addr.as_ref() == Some(addr))
+            {
+                return Err(format!("tcpListenAddr {} usato più volte", addr));
+            }
+        }
+        let dst = conf.dst_addr.unwrap_or_else(|| server.dst_addr.clone());
+        // Un nome viene risolto all'avvio del gruppo
+        let dst_addr = dst.parse::<SocketAddr>().ok();
+        if dst_addr.is_none() && dns::split_host_port(&dst).is_none() {
+            return Err(format!(
+                "dstAddr {:?} del gruppo {} non valido, deve essere ip:porta o nome:porta",
+                dst, conf.name
+            ));
+        }
+        let framing = if conf.framing.unwrap_or(server.framing) {
+            Some(Arc::new(Framing::new()))
+        } else {
+            None
+        };
+        let mode = conf.mode.unwrap_or(server.mode);
+        let fec = match conf.fec.as_ref().or(server.fec.as_ref()) {
+            Some(_) if framing.is_none() => {
+                return Err(format!(
+                    "la fec del gruppo {} richiede il framing",
+                    conf.name
+                ))
+            }
+            Some(_) if mode == Mode::Redundant => {
+                // La parità andrebbe ad aggiungersi alla copia su tutti i path
+                return Err(format!(
+                    "la fec del gruppo {} non si può usare con mode: redundant, usa balance o failover",
+                    conf.name
+                ));
+            }
+            Some(fec) if !fec.is_valid() => {
+                return Err(format!(
+                    "fec del gruppo {} non valida: dataShards {} parityShards {}",
+                    conf.name, fec.data_shards, fec.parity_shards
+                ))
+            }
+            Some(fec) => Some(Arc::new(Fec::new(fec))),
+            None => None,
+        };
+        let auth = match conf.psk.as_ref().or(server.psk.as_ref()) {
+            Some(psk) if psk.is_empty() => {
+                return Err(format!("psk del gruppo {} vuota", conf.name))
+            }
+            Some(psk) => Some(Arc::new(GroupAuth::new(psk)?)),
+            None => None,
+        };
+        groups.push(ClientGroup {
+            name: conf.name,
+            listen_addrs,
+            tcp_listen_addr: conf.tcp_listen_addr,
+            dst,
+            dst_addr: Arc::new(Mutex::new(dst_addr)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            framing,
+            fec,
+            auth,
+            mode: conf.mode,
+            max_datagram: server.max_datagram_size.unwrap_or(DEFAULT_DATAGRAM_SIZE),
+            batch_io: server.batch_io,
+            sender: Arc::new(Sender::new(server.batch_io)),
+            send_queue: server.send_queue_size.unwrap_or(DEFAULT_QUEUE_SIZE),
+            stats: Arc::new(GroupStats::default()),
+            tcp_conns: Arc::new(Mutex::new(TcpConnections::default())),
+        });
+    }
+    Ok(groups)
+}
+
 //
 // Embedding dei file statici
 //
@@ -81,198 +925,2041 @@ async fn serve_embedded_file(path: warp::path::Tail) -> Result<impl warp::Reply,
 // Webserver
 //
 
-async fn run_webserver(web_conf: WebManagerConfig, clients: Clients) {
+async fn run_webserver(listen_addr: String, config: SharedConfig, groups: ClientGroups) {
     // Route per i file statici embedded:
     let static_route = warp::path::tail().and_then(serve_embedded_file);
 
     // Route per l'API get-list:
-    let clients_filter = warp::any().map(move || clients.clone());
+    let clients_filter = warp::any().map(move || groups.clone());
+    let config_filter = {
+        let config = config.clone();
+        warp::any().map(move || config.clone())
+    };
     let get_list = warp::path!("api" / "v1" / "get-list")
-        .and(clients_filter)
+        .and(clients_filter.clone())
+        .and(config_filter)
         .and_then(handle_get_list);
 
-    let routes = static_route.or(get_list);
+    // Route per le metriche Prometheus:
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(clients_filter)
+        .and_then(handle_metrics);
+
+    // Tutte le route (API, metriche e file statici) richiedono le credenziali
+    // Le credenziali vengono lette ad ogni richiesta, così valgono subito dopo un reload
+    let credentials = move || {
+        let config = config.lock().unwrap();
+        let web_conf = config.web_manager.as_ref()?;
+        Some((web_conf.username.clone(), web_conf.password.clone()))
+    };
+    let routes = web::with_auth(credentials)
+        .and(get_list.or(metrics).or(static_route))
+        .recover(web::handle_rejection);
 
-    log::info!("Webserver in ascolto su {}", web_conf.listenAddr);
+    log::info!("Webserver in ascolto su {}", listen_addr);
     warp::serve(routes)
-        .run(web_conf.listenAddr.parse::<SocketAddr>().unwrap())
+        .run(listen_addr.parse::<SocketAddr>().unwrap())
         .await;
 }
 
-async fn handle_get_list(clients: Clients) -> Result<impl warp::Reply, warp::Rejection> {
+async fn handle_get_list(
+    groups: ClientGroups,
+    config: SharedConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
     let now = Instant::now();
-    let clients_guard = clients.lock().unwrap();
+    let (prune, default_mode, description, listen_addr, dst_addr) = {
+        let server = config.lock().unwrap();
+        let prune = server.prune_timeout.map(|ms| {
+            (
+                Duration::from_millis(ms),
+                server.probe_interval.map(Duration::from_millis),
+            )
+        });
+        (
+            prune,
+            server.mode,
+            server.description.clone(),
+            server.listen_addr.clone(),
+            server.dst_addr.clone(),
+        )
+    };
     let mut sockets = Vec::new();
-    for (key, client) in clients_guard.iter() {
-        let elapsed = now.duration_since(client.last).as_secs();
-        sockets.push(serde_json::json!({
-            "address": key,
-            "last": elapsed,
+    let mut group_list = Vec::new();
+    for group in groups.iter() {
+        let mut clients_guard = group.clients.lock().unwrap();
+        let unique = group
+            .framing
+            .as_ref()
+            .map(|f| f.unique.load(Ordering::Relaxed));
+        for (key, client) in clients_guard.iter_mut() {
+            let elapsed = now.duration_since(client.last).as_secs();
+            // Percentuale di pacchetti persi sul path, solo con il framing abilitato
+            let loss = unique.and_then(|unique| {
+                let d_frames = client.frames - client.loss_check.0;
+                let d_unique = unique - client.loss_check.1;
+                client.loss_check = (client.frames, unique);
+                if d_unique == 0 {
+                    return None;
+                }
+                let received = (d_frames as f64 / d_unique as f64).min(1.0);
+                Some(((1.0 - received) * 1000.0).round() / 10.0)
+            });
+            // Con pruneTimeout, se il path è giudicato morto
+            let pruned = prune.map(|(prune_timeout, probe_interval)| {
+                client.is_dead(now, prune_timeout, probe_interval)
+            });
+            let send_failures = client.send_failures.load(Ordering::Relaxed);
+            let link = client.link();
+            let probe = client.probe.lock().unwrap();
+            sockets.push(serde_json::json!({
+                "address": key,
+                "last": elapsed,
+                "group": group.name,
+                "loss": loss,
+                "rttMs": probe.rtt_ms(),
+                "jitterMs": probe.jitter_ms(),
+                "probeLoss": probe.loss(now),
+                "queueDepth": client.queue.depth(),
+                "queueDrops": client.counters.queue_drops.load(Ordering::Relaxed),
+                "pruned": pruned,
+                // Indirizzo del server a cui il path invia
+                "serverAddress": link.local_addr().map(|a| a.to_string()),
+                "transport": link.transport(),
+                // Degradato se gli ultimi invii sono falliti
+                "state": if send_failures > 0 { "degraded" } else { "active" },
+                "sendFailures": send_failures,
+            }));
+        }
+        // Client rimossi di recente, dal più recente
+        let evictions: Vec<serde_json::Value> = group
+            .stats
+            .evictions
+            .lock()
+            .unwrap()
+            .recent
+            .iter()
+            .rev()
+            .map(|e| {
+                serde_json::json!({
+                    "address": e.address,
+                    "reason": e.reason,
+                    "detail": e.detail,
+                    "ago": now.duration_since(e.at).as_secs(),
+                })
+            })
+            .collect();
+        group_list.push(serde_json::json!({
+            "name": group.name,
+            "listenAddress": group.listen_addrs.join(", "),
+            "dstAddress": match group.dst_addr() {
+                Some(addr) if addr.to_string() != group.dst => format!("{} ({})", group.dst, addr),
+                _ => group.dst.clone(),
+            },
+            "sockets": clients_guard.len(),
+            "framing": group.framing.is_some(),
+            "mode": group.mode.unwrap_or(default_mode),
+            "duplicatesDropped": group.framing.as_ref().map(|f| f.duplicates.load(Ordering::Relaxed)),
+            "fecRecovered": group.fec.as_ref().map(|f| f.recovered.load(Ordering::Relaxed)),
+            "authentication": group.auth.is_some(),
+            "unauthenticated": group.stats.unauthenticated.load(Ordering::Relaxed),
+            "authFailures": group.stats.auth_failures.load(Ordering::Relaxed),
+            "tcpConnections": group.tcp_conns.lock().unwrap().total,
+            "tcpRejected": group.stats.tcp_rejected.load(Ordering::Relaxed),
+            "evictions": evictions,
         }));
     }
     let reply = serde_json::json!({
         "type": "server",
         "version": env!("CARGO_PKG_VERSION"),
-        "description": "Engarde Server in Rust",
-        "listenAddress": "", // Puoi inserire qui il valore se necessario
-        "dstAddress": "",    // Puoi inserire qui il valore se necessario
-        "sockets": sockets
+        "description": description,
+        // Del gruppo default; quelli degli altri gruppi sono in groups
+        "listenAddress": listen_addr,
+        "dstAddress": dst_addr,
+        "sockets": sockets,
+        "groups": group_list
     });
     Ok(warp::reply::json(&reply))
 }
 
+// Metriche in formato testo Prometheus, una serie per ogni client connesso
+async fn handle_metrics(groups: ClientGroups) -> Result<impl warp::Reply, warp::Rejection> {
+    let now = Instant::now();
+    let mut clients = Vec::new();
+    for group in groups.iter() {
+        let guard = group.clients.lock().unwrap();
+        let mut keys: Vec<&String> = guard.keys().collect();
+        keys.sort();
+        for key in keys {
+            let client = &guard[key];
+            let age = now.duration_since(client.last).as_secs_f64();
+            clients.push((
+                group.name.clone(),
+                key.clone(),
+                age,
+                client.queue.depth(),
+                client.counters.values(),
+            ));
+        }
+    }
+
+    let mut w = MetricsWriter::new();
+    w.family(
+        "engarde_client_last_receive_seconds",
+        "Seconds since the last packet received from the client",
+        "gauge",
+    );
+    for (group, key, age, _, _) in &clients {
+        let labels = [("group", group.as_str()), ("client", key.as_str())];
+        w.sample("engarde_client_last_receive_seconds", &labels, *age);
+    }
+    w.family(
+        "engarde_client_queue_depth",
+        "Packets waiting in the send queue of the client",
+        "gauge",
+    );
+    for (group, key, _, depth, _) in &clients {
+        let labels = [("group", group.as_str()), ("client", key.as_str())];
+        w.sample("engarde_client_queue_depth", &labels, *depth as f64);
+    }
+    for (i, (suffix, help)) in PATH_COUNTERS.iter().enumerate() {
+        let name = format!("engarde_client_{}", suffix);
+        w.family(&name, help, "counter");
+        for (group, key, _, _, values) in &clients {
+            let labels = [("group", group.as_str()), ("client", key.as_str())];
+            w.sample(&name, &labels, values[i] as f64);
+        }
+    }
+    w.family(
+        "engarde_group_clients",
+        "Clients connected to the group",
+        "gauge",
+    );
+    for group in groups.iter() {
+        let count = group.clients.lock().unwrap().len();
+        w.sample(
+            "engarde_group_clients",
+            &[("group", &group.name)],
+            count as f64,
+        );
+    }
+    w.family(
+        "engarde_group_path_up_transitions_total",
+        "Clients registered in the group",
+        "counter",
+    );
+    for group in groups.iter() {
+        let ups = group.stats.ups.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_path_up_transitions_total",
+            &[("group", &group.name)],
+            ups as f64,
+        );
+    }
+    w.family(
+        "engarde_group_path_down_transitions_total",
+        "Clients removed from the group",
+        "counter",
+    );
+    for group in groups.iter() {
+        let downs = group.stats.downs.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_path_down_transitions_total",
+            &[("group", &group.name)],
+            downs as f64,
+        );
+    }
+    w.family(
+        "engarde_group_evictions_total",
+        "Clients removed from the group, by reason",
+        "counter",
+    );
+    for group in groups.iter() {
+        let evictions = group.stats.evictions.lock().unwrap();
+        for (reason, count) in &evictions.totals {
+            w.sample(
+                "engarde_group_evictions_total",
+                &[("group", &group.name), ("reason", reason)],
+                *count as f64,
+            );
+        }
+    }
+    w.family(
+        "engarde_group_path_prunes_total",
+        "Times a client path was judged dead and sending to it was suspended",
+        "counter",
+    );
+    for group in groups.iter() {
+        let prunes = group.stats.prunes.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_path_prunes_total",
+            &[("group", &group.name)],
+            prunes as f64,
+        );
+    }
+    w.family(
+        "engarde_group_truncated_total",
+        "Datagrams from clients dropped because larger than maxDatagramSize",
+        "counter",
+    );
+    for group in groups.iter() {
+        let truncated = group.stats.truncated.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_truncated_total",
+            &[("group", &group.name)],
+            truncated as f64,
+        );
+    }
+    w.family(
+        "engarde_group_unauthenticated_total",
+        "Datagrams dropped because the sender has not authenticated with the psk",
+        "counter",
+    );
+    for group in groups.iter() {
+        let dropped = group.stats.unauthenticated.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_unauthenticated_total",
+            &[("group", &group.name)],
+            dropped as f64,
+        );
+    }
+    w.family(
+        "engarde_group_auth_failures_total",
+        "Authentication responses with an invalid cookie or psk",
+        "counter",
+    );
+    for group in groups.iter() {
+        let failures = group.stats.auth_failures.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_auth_failures_total",
+            &[("group", &group.name)],
+            failures as f64,
+        );
+    }
+    w.family(
+        "engarde_group_tcp_rejected_total",
+        "TCP connections closed because of the per-source or total limit",
+        "counter",
+    );
+    for group in groups.iter() {
+        let rejected = group.stats.tcp_rejected.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_group_tcp_rejected_total",
+            &[("group", &group.name)],
+            rejected as f64,
+        );
+    }
+    w.family(
+        "engarde_wireguard_truncated_total",
+        "WireGuard packets dropped because larger than maxDatagramSize",
+        "counter",
+    );
+    for group in groups.iter() {
+        let truncated = group.stats.wg_truncated.load(Ordering::Relaxed);
+        w.sample(
+            "engarde_wireguard_truncated_total",
+            &[("group", &group.name)],
+            truncated as f64,
+        );
+    }
+    let framing_values: Vec<(&str, [u64; 3])> = groups
+        .iter()
+        .filter_map(|g| {
+            g.framing
+                .as_ref()
+                .map(|f| (g.name.as_str(), framing_values(f)))
+        })
+        .collect();
+    for (i, (name, help)) in FRAMING_COUNTERS.iter().enumerate() {
+        w.family(name, help, "counter");
+        for (group, values) in &framing_values {
+            w.sample(name, &[("group", group)], values[i] as f64);
+        }
+    }
+    let fec_values: Vec<(&str, [u64; 2])> = groups
+        .iter()
+        .filter_map(|g| g.fec.as_ref().map(|f| (g.name.as_str(), fec_values(f))))
+        .collect();
+    for (i, (name, help)) in FEC_COUNTERS.iter().enumerate() {
+        w.family(name, help, "counter");
+        for (group, values) in &fec_values {
+            w.sample(name, &[("group", group)], values[i] as f64);
+        }
+    }
+    Ok(warp::reply::with_header(
+        w.finish(),
+        "Content-Type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
 //
 // UDP Server per la comunicazione
 //
 
 async fn receive_from_wireguard(
+    group: ClientGroup,
     wg_socket: Arc<UdpSocket>,
-    client_socket: Arc<UdpSocket>,
-    wg_addr: SocketAddr,
-    clients: Clients,
-    client_timeout: Duration,
-    write_timeout: Duration,
+    config: SharedConfig,
 ) {
-    let mut buf = vec![0u8; 1500];
+    let group_name = group.name;
+    let clients = group.clients;
+    // Con il framing il pacchetto di Wireguard viene letto dopo lo spazio per l'header
+    let offset = if group.framing.is_some() {
+        HEADER_LEN
+    } else {
+        0
+    };
+    let mut receiver = Receiver::new(group.max_datagram, offset, group.batch_io);
+    // I pacchetti letti sono condivisi tra i client senza copiarli
+    let mut pool = BufferPool::new(receiver.buf_len());
+    let mut scheduler = Scheduler::new();
+    // Path su cui l'invio è sospeso (pruneTimeout)
+    let mut pruned: HashSet<String> = HashSet::new();
     loop {
-        match wg_socket.recv_from(&mut buf).await {
-            Ok((n, _)) => {
-                let now = Instant::now();
-                let mut to_remove = Vec::new();
-                // Creiamo una snapshot dei client per non tenere il lock durante gli await
-                let clients_snapshot = {
-                    let guard = clients.lock().unwrap();
-                    guard
-                        .iter()
-                        .map(|(k, v)| (k.clone(), v.clone()))
-                        .collect::<Vec<_>>()
-                };
+        if let Err(e) = receiver.recv(&wg_socket).await {
+            log::warn!("[{}] Errore in recv_from Wireguard: {}", group_name, e);
+            continue;
+        }
+        let (mode, client_timeout, health_timeout, probe_interval, drop_policy, prune) = {
+            let server = config.lock().unwrap();
+            let probe_interval = server.probe_interval.map(Duration::from_millis);
+            (
+                group.mode.unwrap_or(server.mode),
+                Duration::from_secs(server.client_timeout.unwrap_or(30)),
+                Duration::from_millis(server.health_timeout.unwrap_or(1000)),
+                probe_interval,
+                server.drop_policy,
+                server
+                    .prune_timeout
+                    .map(|ms| (Duration::from_millis(ms), probe_interval)),
+            )
+        };
+        let now = Instant::now();
+        let mut to_remove = Vec::new();
+        // Creiamo una snapshot dei client per non tenere il lock durante gli await
+        let mut clients_snapshot = {
+            let guard = clients.lock().unwrap();
+            guard
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
+        };
+        clients_snapshot.retain(|(key, client)| {
+            let alive = now.duration_since(client.last) < client_timeout;
+            if !alive {
+                to_remove.push((key.clone(), client.queue.clone()));
+            }
+            alive
+        });
 
-                let sends = clients_snapshot.into_iter().map(|(key, client)| {
-                    let socket = client_socket.clone();
-                    let addr = client.addr;
-                    let alive = now.duration_since(client.last) < client_timeout;
-                    let data = buf[..n].to_vec();
-                    async move {
-                        let send_fut = socket.send_to(&data, addr);
-                        (key, alive, tokio::time::timeout(write_timeout, send_fut).await)
-                    }
-                });
+        let dead = dead_paths(&clients_snapshot, now, prune);
+        for (key, _) in &clients_snapshot {
+            match (dead.contains(key), pruned.contains(key)) {
+                (true, false) => {
+                    group.stats.prunes.fetch_add(1, Ordering::Relaxed);
+                    log::info!("[{}] Path {} non risponde, invio sospeso", group_name, key);
+                }
+                (false, true) => log::info!("[{}] Path {} di nuovo attivo", group_name, key),
+                _ => {}
+            }
+        }
+        pruned = dead;
+        // Se tutti i path sono morti si continua a inviare su tutti
+        if pruned.len() < clients_snapshot.len() {
+            clients_snapshot.retain(|(key, _)| !pruned.contains(key));
+        }
+
+        clients_snapshot.sort_by(|a, b| a.0.cmp(&b.0));
+        // Il server non conosce le interfacce del client: in balance i path
+        // hanno lo stesso peso, in failover il preferito è quello da cui sono
+        // arrivati gli ultimi dati
+        let latest = clients_snapshot
+            .iter()
+            .max_by_key(|(_, client)| client.last_data)
+            .map(|(key, _)| key.clone());
+        let paths: Vec<PathInfo> = clients_snapshot
+            .iter()
+            .map(|(key, client)| PathInfo {
+                key,
+                weight: 1,
+                priority: if Some(key) == latest.as_ref() { 0 } else { 1 },
+                // Un path degradato riceve pacchetti solo se nessun altro è sano
+                healthy: client.is_healthy(now, health_timeout, probe_interval)
+                    && client.send_failures.load(Ordering::Relaxed) == 0,
+            })
+            .collect();
+        scheduler.set_paths(&paths);
 
-                let results = futures::future::join_all(sends).await;
-
-                for (key, still_valid, result) in results {
-                    if still_valid {
-                        match result {
-                            Ok(Ok(_)) => {}
-                            Ok(Err(e)) => {
-                                log::warn!("Errore scrivendo al client {}: {}", key, e);
-                                to_remove.push(key);
-                            }
-                            Err(_) => {
-                                log::warn!("Timeout scrivendo al client {}", key);
-                                to_remove.push(key);
-                            }
+        for i in 0..receiver.count() {
+            if receiver.get(i).2 {
+                group.stats.wg_truncated.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "[{}] Scarto pacchetto troncato da Wireguard, più grande di maxDatagramSize {}",
+                    group_name,
+                    group.max_datagram
+                );
+                continue;
+            }
+            let packet = receiver.with_headroom_mut(i);
+            let mut parity = Vec::new();
+            if let Some(framing) = &group.framing {
+                let header = framing.next_header(FrameType::Data);
+                header.write(packet);
+                // Quando un gruppo è completo la FEC aggiunge i suoi frame di parità
+                if let Some(fec) = &group.fec {
+                    parity = fec.encode(&header, &packet[HEADER_LEN..]);
+                }
+            }
+            let packet = receiver.take(i, &mut pool);
+            // I frame di parità sono distribuiti sui path come i pacchetti di dati
+            let parity = parity.into_iter().map(Packet::from_vec);
+            for datagram in std::iter::once(packet).chain(parity) {
+                let previous = scheduler.active();
+                let selected = scheduler.select(mode, &paths);
+                if mode == Mode::Failover && scheduler.active() != previous {
+                    match scheduler.active() {
+                        Some(p) => {
+                            log::info!("[{}] Failover: invio su {}", group_name, paths[p].key)
                         }
-                    } else {
-                        log::info!("Client {} timed out", key);
-                        to_remove.push(key);
+                        None => log::warn!(
+                            "[{}] Failover: nessun path sano, invio su tutti",
+                            group_name
+                        ),
                     }
                 }
+                fan_out(&clients_snapshot, &selected, datagram, drop_policy);
+            }
+        }
 
-                if !to_remove.is_empty() {
-                    let mut guard = clients.lock().unwrap();
-                    for key in to_remove {
-                        guard.remove(&key);
-                    }
+        if !to_remove.is_empty() {
+            let mut guard = clients.lock().unwrap();
+            for (key, queue) in to_remove {
+                // Solo se nella mappa c'è ancora questo client, senza pacchetti
+                // arrivati dopo la snapshot, e non uno registrato dopo
+                let expired = guard.get(&key).is_some_and(|c| {
+                    Arc::ptr_eq(&c.queue, &queue)
+                        && Instant::now().duration_since(c.last) >= client_timeout
+                });
+                if !expired {
+                    continue;
+                }
+                log::info!("[{}] Client {} timed out", group_name, key);
+                if let Some(client) = guard.remove(&key) {
+                    client.queue.close();
+                    let detail = format!("nessun pacchetto da {}s", client_timeout.as_secs());
+                    group.stats.evicted(&key, EVICT_TIMEOUT, detail);
                 }
-            }
-            Err(e) => {
-                log::warn!("Errore in recv_from Wireguard: {}", e);
             }
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+// Mette il pacchetto nelle code dei client selezionati. L'ultimo lo riceve
+// senza clonarlo, così con un solo path il pacchetto torna subito al pool.
+fn fan_out(
+    clients: &[(String, ConnectedClient)],
+    selected: &[usize],
+    packet: Packet,
+    policy: DropPolicy,
+) {
+    if let Some((&last, others)) = selected.split_last() {
+        for &c in others {
+            enqueue(&clients[c].1, packet.clone(), policy);
+        }
+        enqueue(&clients[last].1, packet, policy);
+    }
+}
 
-    // Legge il file di configurazione (default "engarde.yml")
-    let config_path = std::env::args().nth(1).unwrap_or_else(|| "engarde.yml".to_string());
-    let config_str = std::fs::read_to_string(&config_path)
-        .unwrap_or_else(|e| panic!("Errore leggendo {}: {}", config_path, e));
-    let config: Config = serde_yaml::from_str(&config_str)
-        .unwrap_or_else(|e| panic!("Errore parseando config: {}", e));
+fn enqueue(client: &ConnectedClient, packet: Packet, policy: DropPolicy) {
+    if !client.queue.push(packet, policy) {
+        client.counters.queue_drops.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
-    let server = config.server;
-    log::info!("Server: {:?}", server.description);
+// Classe di un errore di invio
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SendFailure {
+    // Il kernel o il link sono momentaneamente saturi (ENOBUFS, writeTimeout, ...)
+    // oppure il pacchetto è stato scartato da netfilter (EPERM, EACCES)
+    Transient,
+    // Il client non è raggiungibile (ENETUNREACH, ICMP port unreachable, ...)
+    Unreachable,
+}
 
-    let client_timeout = Duration::from_secs(server.clientTimeout.unwrap_or(30));
-    let write_timeout = Duration::from_millis(server.writeTimeout.unwrap_or(10));
+fn classify_send_error(e: &std::io::Error) -> SendFailure {
+    match e.raw_os_error() {
+        Some(
+            libc::ENETUNREACH
+            | libc::EHOSTUNREACH
+            | libc::ENETDOWN
+            | libc::EADDRNOTAVAIL
+            | libc::ECONNREFUSED,
+        ) => SendFailure::Unreachable,
+        _ => SendFailure::Transient,
+    }
+}
 
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+// Errori di invio consecutivi di un client, per classe
+struct SendErrors {
+    transient: u32,
+    unreachable: u32,
+    backoff: Duration,
+}
 
-    // Socket UDP per i client
-    let client_socket = Arc::new(
-        UdpSocket::bind(&server.listenAddr)
-            .await
-            .unwrap_or_else(|e| panic!("Errore bind client socket: {}", e)),
+// Cosa fare dopo un errore di invio
+#[derive(Debug, PartialEq)]
+enum AfterFailure {
+    // Riprovare dopo backoff: è l'errore count di limit
+    Retry {
+        count: u32,
+        limit: u32,
+        backoff: Duration,
+    },
+    // Raggiunto sendFailures: rimuovere il client
+    Evict {
+        count: u32,
+        reason: &'static str,
+    },
+}
+
+impl SendErrors {
+    fn new() -> Self {
+        SendErrors {
+            transient: 0,
+            unreachable: 0,
+            backoff: MIN_BACKOFF,
+        }
+    }
+
+    fn total(&self) -> u32 {
+        self.transient + self.unreachable
+    }
+
+    // Un invio riuscito azzera gli errori; true se il path era degradato
+    fn succeeded(&mut self) -> bool {
+        let degraded = self.total() > 0;
+        *self = SendErrors::new();
+        degraded
+    }
+
+    fn failed(&mut self, class: SendFailure, failures: &SendFailureConfig) -> AfterFailure {
+        let (count, limit, reason) = match class {
+            SendFailure::Transient => {
+                self.transient += 1;
+                let limit = failures.transient.unwrap_or(DEFAULT_TRANSIENT_FAILURES);
+                (self.transient, limit, EVICT_TRANSIENT)
+            }
+            SendFailure::Unreachable => {
+                self.unreachable += 1;
+                let limit = failures.unreachable.unwrap_or(DEFAULT_UNREACHABLE_FAILURES);
+                (self.unreachable, limit, EVICT_UNREACHABLE)
+            }
+        };
+        if count >= limit {
+            return AfterFailure::Evict { count, reason };
+        }
+        let backoff = self.backoff;
+        let max_backoff =
+            Duration::from_millis(failures.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF));
+        self.backoff = (backoff * 2).min(max_backoff);
+        AfterFailure::Retry {
+            count,
+            limit,
+            backoff,
+        }
+    }
+}
+
+// Invia al client i pacchetti in coda, finché il client non viene rimosso.
+// Dopo un errore il path è degradato: si riprova dopo un'attesa che raddoppia
+// a ogni errore, e il client viene rimosso solo dopo sendFailures errori
+// consecutivi della stessa classe. Viene registrato di nuovo al prossimo
+// pacchetto ricevuto.
+async fn send_to_client(
+    group: ClientGroup,
+    config: SharedConfig,
+    key: String,
+    client: ConnectedClient,
+) {
+    let mut packets = Vec::with_capacity(BATCH_SIZE);
+    let mut errors = SendErrors::new();
+    while client.queue.pop(&mut packets, BATCH_SIZE).await {
+        let (write_timeout, failures) = {
+            let server = config.lock().unwrap();
+            (
+                Duration::from_millis(server.write_timeout.unwrap_or(10)),
+                server.send_failures.clone(),
+            )
+        };
+        let link = client.link();
+        let fut = link.send(&group.sender, &packets, client.addr);
+        let counters = &client.counters;
+        let (class, error) = match tokio::time::timeout(write_timeout, fut).await {
+            Ok(Ok(_)) => {
+                for packet in &packets {
+                    counters.add_tx(packet.len());
+                }
+                packets.clear();
+                if errors.succeeded() {
+                    log::info!("[{}] Client {} di nuovo raggiungibile", group.name, key);
+                    client.send_failures.store(0, Ordering::Relaxed);
+                }
+                continue;
+            }
+            Ok(Err(e)) => {
+                counters.send_errors.fetch_add(1, Ordering::Relaxed);
+                (classify_send_error(&e), e.to_string())
+            }
+            Err(_) => {
+                counters.write_timeouts.fetch_add(1, Ordering::Relaxed);
+                (SendFailure::Transient, "timeout".to_string())
+            }
+        };
+        packets.clear();
+        let after = errors.failed(class, &failures);
+        client
+            .send_failures
+            .store(errors.total(), Ordering::Relaxed);
+        let (count, reason) = match after {
+            AfterFailure::Retry {
+                count,
+                limit,
+                backoff,
+            } => {
+                log::warn!(
+                    "[{}] Errore scrivendo al client {}: {} ({} di {}), riprovo tra {:?}",
+                    group.name,
+                    key,
+                    error,
+                    count,
+                    limit,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+            AfterFailure::Evict { count, reason } => (count, reason),
+        };
+        let detail = format!("{} errori consecutivi, ultimo: {}", count, error);
+        log::warn!(
+            "[{}] Client {} rimosso ({}): {}",
+            group.name,
+            key,
+            reason,
+            detail
+        );
+        // Solo se nella mappa c'è ancora questo client e non uno registrato dopo
+        let mut guard = group.clients.lock().unwrap();
+        if guard
+            .get(&key)
+            .is_some_and(|c| Arc::ptr_eq(&c.queue, &client.queue))
+        {
+            guard.remove(&key);
+            group.stats.evicted(&key, reason, detail);
+        }
+        client.queue.close();
+        return;
+    }
+}
+
+async fn receive_from_clients(
+    group: ClientGroup,
+    wg_socket: Arc<UdpSocket>,
+    client_socket: Arc<UdpSocket>,
+    config: SharedConfig,
+) {
+    let max_len = group.max_datagram + group.overhead();
+    let mut receiver = Receiver::new(max_len, 0, group.batch_io);
+    // La FEC tiene gli ultimi pacchetti ricevuti: con GRO ognuno terrebbe
+    // occupato un buffer da 64KB
+    if group.fec.is_none() {
+        receiver.enable_gro(&client_socket);
+    }
+    // I datagram letti passano a Wireguard e alla FEC senza copiarli
+    let mut pool = BufferPool::new(receiver.buf_len());
+    let wg_sender = Sender::new(group.batch_io);
+    let link = ClientLink::Udp(client_socket.clone());
+    loop {
+        if let Err(e) = receiver.recv(&client_socket).await {
+            log::warn!("[{}] Errore in recv_from client: {}", group.name, e);
+            continue;
+        }
+        let mut out = ToWireguard::default();
+        for (packet, src_addr, truncated) in receiver.take_all(&mut pool) {
+            handle_client_packet(
+                &group, &config, &link, src_addr, packet, truncated, &mut out,
+            )
+            .await;
+        }
+        out.flush(&group, &wg_socket, &wg_sender).await;
+    }
+}
+
+// Chiave di un client nella mappa del gruppo
+fn client_key(link: &ClientLink, src_addr: SocketAddr) -> String {
+    // Sul socket dual-stack i client IPv4 arrivano come ::ffff:a.b.c.d
+    let addr = SocketAddr::new(src_addr.ip().to_canonical(), src_addr.port());
+    match link {
+        ClientLink::Udp(_) => addr.to_string(),
+        ClientLink::Tcp { .. } => format!("tcp/{}", addr),
+    }
+}
+
+// Pacchetti ricevuti dai client da inoltrare a Wireguard tutti insieme: i dati
+// e quelli ricostruiti dalla FEC
+#[derive(Default)]
+struct ToWireguard {
+    packets: Vec<Packet>,
+    recovered: Vec<(Header, Packet)>,
+}
+
+impl ToWireguard {
+    async fn flush(self, group: &ClientGroup, wg_socket: &UdpSocket, wg_sender: &Sender) {
+        write_to_wireguard(group, wg_socket, wg_sender, &self.packets).await;
+        forward_recovered(group, wg_socket, wg_sender, self.recovered).await;
+    }
+}
+
+// Con la psk del gruppo un path viene registrato solo dopo l'autenticazione.
+// false se il pacchetto si ferma qui: le richieste di autenticazione, le
+// risposte non valide e i pacchetti dei mittenti non autenticati, che vengono
+// contati e scartati.
+async fn check_auth(
+    group: &ClientGroup,
+    link: &ClientLink,
+    key: &str,
+    src_addr: SocketAddr,
+    parsed: Option<(Header, &[u8])>,
+) -> bool {
+    let kind = parsed.map(|(header, _)| header.kind);
+    let auth = match &group.auth {
+        Some(auth) => auth,
+        // Senza psk i frame di autenticazione vengono ignorati
+        None => return !matches!(kind, Some(FrameType::AuthHello | FrameType::AuthResponse)),
+    };
+    match parsed {
+        Some((header, body)) if header.kind == FrameType::AuthHello => {
+            // La sfida non deve essere più grande della richiesta
+            if body.len() >= COOKIE_LEN {
+                let cookie = auth
+                    .verifier
+                    .cookie(key, Verifier::epoch(SystemTime::now()));
+                let challenge = auth::frame(FrameType::AuthChallenge, &cookie);
+                if let Err(e) = link.send_to(&challenge, src_addr).await {
+                    log::warn!(
+                        "[{}] Errore inviando la sfida di autenticazione a {}: {}",
+                        group.name,
+                        src_addr,
+                        e
+                    );
+                }
+            }
+            false
+        }
+        Some((header, body)) if header.kind == FrameType::AuthResponse => {
+            if auth
+                .verifier
+                .verify(key, body, Verifier::epoch(SystemTime::now()))
+            {
+                if !group.clients.lock().unwrap().contains_key(key) {
+                    log::info!("[{}] Client autenticato: {}", group.name, key);
+                }
+                return true;
+            }
+            group.stats.auth_failures.fetch_add(1, Ordering::Relaxed);
+            if auth.should_warn(Instant::now()) {
+                log::warn!(
+                    "[{}] Autenticazione non valida da {}, la psk è la stessa sul client?",
+                    group.name,
+                    src_addr
+                );
+            }
+            false
+        }
+        _ => {
+            if group.clients.lock().unwrap().contains_key(key) {
+                return true;
+            }
+            group.stats.unauthenticated.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+}
+
+// Un datagram ricevuto da un client, via UDP o TCP: registra il path, risponde
+// ai probe e mette i dati in out
+async fn handle_client_packet(
+    group: &ClientGroup,
+    config: &SharedConfig,
+    link: &ClientLink,
+    src_addr: SocketAddr,
+    packet: Packet,
+    truncated: bool,
+    out: &mut ToWireguard,
+) {
+    let max_len = group.max_datagram + group.overhead();
+    let n = packet.len();
+    let key = client_key(link, src_addr);
+    let parsed = Header::parse(&packet);
+    // I probe di MTU arrivano da un socket dedicato del client: si
+    // risponde senza registrarlo come path
+    if let Some((header, body)) = parsed {
+        if header.kind == FrameType::MtuProbe {
+            // La risposta non deve essere più grande del probe e, con la psk,
+            // il probe deve iniziare con il suo tag
+            let authorized = group
+                .auth
+                .as_ref()
+                .is_none_or(|auth| auth.verifier.verify_probe(&header, body));
+            if n < MTU_REPLY_LEN || !authorized {
+                group.stats.unauthenticated.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            // Se il probe è stato troncato non è arrivato per intero
+            let received = if truncated { max_len } else { n };
+            let reply = mtu_reply_for(&header, received);
+            if let Err(e) = link.send_to(&reply, src_addr).await {
+                log::warn!(
+                    "[{}] Errore rispondendo al probe di MTU di {}: {}",
+                    group.name,
+                    src_addr,
+                    e
+                );
+            }
+            return;
+        }
+        if header.kind == FrameType::MtuProbeReply {
+            return;
+        }
+    }
+    if !check_auth(group, link, &key, src_addr, parsed).await {
+        return;
+    }
+    if truncated {
+        group.stats.truncated.fetch_add(1, Ordering::Relaxed);
+        if let Some(client) = group.clients.lock().unwrap().get(&key) {
+            client.counters.truncated.fetch_add(1, Ordering::Relaxed);
+        }
+        log::warn!(
+            "[{}] Scarto datagram troncato da {}, più grande di maxDatagramSize {}",
+            group.name,
+            src_addr,
+            group.max_datagram
+        );
+        return;
+    }
+    let kind = parsed.map(|(header, _)| header.kind);
+    // Frame tra client e server, non sono dati
+    let is_probe = matches!(
+        kind,
+        Some(FrameType::Probe | FrameType::ProbeReply | FrameType::AuthResponse)
     );
-    log::info!("Listening on {}", server.listenAddr);
+    // Con il framing i pacchetti senza header non registrano il path
+    if let (Some(framing), None) = (&group.framing, parsed) {
+        framing.invalid.fetch_add(1, Ordering::Relaxed);
+        log::warn!(
+            "[{}] Scarto pacchetto senza framing da {}, il framing è abilitato sul client?",
+            group.name,
+            src_addr
+        );
+        return;
+    }
+    let now = Instant::now();
+    let (probe, counters, new_client) = {
+        let mut map = group.clients.lock().unwrap();
+        let unique = group
+            .framing
+            .as_ref()
+            .map(|f| f.unique.load(Ordering::Relaxed))
+            .unwrap_or(0);
+        let is_new = !map.contains_key(&key);
+        let client = map.entry(key.clone()).or_insert_with(|| {
+            group.stats.ups.fetch_add(1, Ordering::Relaxed);
+            ConnectedClient {
+                addr: src_addr,
+                last: now,
+                last_data: now,
+                last_reply: now,
+                frames: 0,
+                loss_check: (0, unique),
+                probe: Arc::new(Mutex::new(ProbeStats::new())),
+                counters: Arc::new(PathCounters::default()),
+                queue: Arc::new(SendQueue::new(group.send_queue)),
+                send_failures: Arc::new(AtomicU32::new(0)),
+                link: Arc::new(Mutex::new(link.clone())),
+            }
+        });
+        client.addr = src_addr;
+        *client.link.lock().unwrap() = link.clone();
+        client.last = now;
+        if !is_probe {
+            // La perdita del path si calcola solo sui pacchetti di dati
+            if kind != Some(FrameType::Parity) {
+                client.frames += 1;
+            }
+            client.last_data = now;
+        } else if kind == Some(FrameType::ProbeReply) {
+            client.last_reply = now;
+        }
+        let new_client = is_new.then(|| client.clone());
+        (client.probe.clone(), client.counters.clone(), new_client)
+    };
+    if let Some(client) = new_client {
+        tokio::spawn(send_to_client(group.clone(), config.clone(), key, client));
+    }
+    counters.add_rx(n);
+    // I probe restano tra client e server, non vanno a Wireguard
+    let payload = match parsed {
+        Some((header, _)) if header.kind == FrameType::Probe => {
+            let reply = reply_for(&header);
+            if let Err(e) = link.send_to(&reply, src_addr).await {
+                log::warn!(
+                    "[{}] Errore rispondendo al probe di {}: {}",
+                    group.name,
+                    src_addr,
+                    e
+                );
+            }
+            return;
+        }
+        Some((header, _)) if header.kind == FrameType::ProbeReply => {
+            probe.lock().unwrap().on_reply(&header, now);
+            return;
+        }
+        // Il path è stato autenticato da check_auth
+        Some((header, _)) if header.kind == FrameType::AuthResponse => {
+            let ok = auth::frame(FrameType::AuthOk, &[]);
+            if let Err(e) = link.send_to(&ok, src_addr).await {
+                log::warn!(
+                    "[{}] Errore confermando l'autenticazione di {}: {}",
+                    group.name,
+                    src_addr,
+                    e
+                );
+            }
+            return;
+        }
+        Some((header, body)) if header.kind == FrameType::Parity => {
+            match &group.fec {
+                Some(fec) => out.recovered.extend(fec.on_parity(&header, body)),
+                None => {
+                    if let Some(framing) = &group.framing {
+                        framing.invalid.fetch_add(1, Ordering::Relaxed);
+                    }
+                    log::warn!(
+                        "[{}] Scarto frame di parità FEC da {}, la fec è abilitata sul server?",
+                        group.name,
+                        src_addr
+                    );
+                }
+            }
+            return;
+        }
+        Some((header, _)) => match &group.framing {
+            Some(framing) => {
+                if !framing.accept(&header) {
+                    return;
+                }
+                let payload = packet.slice(HEADER_LEN..n);
+                if let Some(fec) = &group.fec {
+                    out.recovered.extend(fec.on_data(&header, &payload));
+                }
+                payload
+            }
+            None => packet,
+        },
+        None => packet,
+    };
+    out.packets.push(payload);
+}
+
+// Accetta le connessioni dei client TCP del gruppo
+async fn accept_tcp_clients(
+    group: ClientGroup,
+    wg_socket: Arc<UdpSocket>,
+    listener: TcpListener,
+    config: SharedConfig,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let (max_per_source, max_total) = {
+                    let server = config.lock().unwrap();
+                    (
+                        server
+                            .tcp_max_per_source
+                            .unwrap_or(DEFAULT_TCP_MAX_PER_SOURCE),
+                        server
+                            .tcp_max_connections
+                            .unwrap_or(DEFAULT_TCP_MAX_CONNECTIONS),
+                    )
+                };
+                let Some(slot) = group.tcp_slot(peer.ip(), max_per_source, max_total) else {
+                    group.stats.tcp_rejected.fetch_add(1, Ordering::Relaxed);
+                    log::debug!(
+                        "[{}] Connessione TCP da {} rifiutata, troppe connessioni aperte",
+                        group.name,
+                        peer
+                    );
+                    continue;
+                };
+                tokio::spawn(receive_from_tcp_client(
+                    group.clone(),
+                    wg_socket.clone(),
+                    stream,
+                    peer,
+                    config.clone(),
+                    slot,
+                ));
+            }
+            Err(e) => {
+                log::warn!("[{}] Errore accettando un client TCP: {}", group.name, e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// Tempo concesso a una connessione TCP per autenticarsi, se il gruppo ha una
+// psk e tcpAuthTimeout non è impostato
+const DEFAULT_TCP_AUTH_TIMEOUT: u64 = 5000;
+
+// Legge i datagram di un client TCP finché la connessione resta aperta, poi
+// rimuove il suo path. Una connessione da cui non arriva nulla per
+// tcpIdleTimeout (o clientTimeout) viene chiusa.
+async fn receive_from_tcp_client(
+    group: ClientGroup,
+    wg_socket: Arc<UdpSocket>,
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: SharedConfig,
+    _slot: TcpSlot,
+) {
+    let local = match stream.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::warn!(
+                "[{}] Connessione TCP da {} non valida: {}",
+                group.name,
+                peer,
+                e
+            );
+            return;
+        }
+    };
+    if let Err(e) = stream.set_nodelay(true) {
+        log::warn!(
+            "[{}] Impossibile impostare TCP_NODELAY per {}: {}",
+            group.name,
+            peer,
+            e
+        );
+    }
+    let (reader, writer) = stream.into_split();
+    let link = ClientLink::Tcp {
+        link: Arc::new(TcpLink::new(writer)),
+        local,
+    };
+    let key = client_key(&link, peer);
+    log::info!("[{}] Client TCP connesso: {}", group.name, key);
+    let mut reader = tokio::io::BufReader::new(reader);
+    // Come per UDP i datagram letti passano a Wireguard e alla FEC senza copiarli
+    let mut pool = BufferPool::new(group.max_datagram + group.overhead());
+    let mut buf = pool.buffer();
+    let wg_sender = Sender::new(false);
+    // Con la psk la connessione deve autenticarsi entro tcpAuthTimeout
+    let auth_timeout = Duration::from_millis(
+        config
+            .lock()
+            .unwrap()
+            .tcp_auth_timeout
+            .unwrap_or(DEFAULT_TCP_AUTH_TIMEOUT),
+    );
+    let mut auth_deadline = group.auth.as_ref().map(|_| Instant::now() + auth_timeout);
+    let error = loop {
+        let idle = {
+            let server = config.lock().unwrap();
+            Duration::from_secs(
+                server
+                    .tcp_idle_timeout
+                    .or(server.client_timeout)
+                    .unwrap_or(30),
+            )
+        };
+        let mut wait = idle;
+        if let Some(deadline) = auth_deadline {
+            if group.clients.lock().unwrap().contains_key(&key) {
+                auth_deadline = None;
+            } else {
+                wait = wait.min(deadline.saturating_duration_since(Instant::now()));
+            }
+        }
+        // Una lettura interrotta lascia la connessione a metà di un datagram,
+        // per cui dopo una scadenza si chiude sempre
+        let n = match tokio::time::timeout(wait, read_datagram(&mut reader, &mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => break e,
+            Err(_) if wait < idle => {
+                break std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!("non autenticato entro {}ms", auth_timeout.as_millis()),
+                )
+            }
+            Err(_) => {
+                break std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("nessun pacchetto da {}s", idle.as_secs()),
+                )
+            }
+        };
+        let packet = pool.wrap(&mut buf, 0..n);
+        let mut out = ToWireguard::default();
+        handle_client_packet(&group, &config, &link, peer, packet, false, &mut out).await;
+        out.flush(&group, &wg_socket, &wg_sender).await;
+    };
+    log::info!("[{}] Client TCP {} disconnesso: {}", group.name, key, error);
+    let reason = if error.kind() == std::io::ErrorKind::TimedOut {
+        EVICT_TIMEOUT
+    } else {
+        EVICT_TCP_CLOSED
+    };
+    if let Some(client) = group.clients.lock().unwrap().remove(&key) {
+        client.queue.close();
+        group.stats.evicted(&key, reason, error.to_string());
+    }
+}
+
+async fn write_to_wireguard(
+    group: &ClientGroup,
+    wg_socket: &UdpSocket,
+    wg_sender: &Sender,
+    packets: &[Packet],
+) {
+    if packets.is_empty() {
+        return;
+    }
+    let Some(dst_addr) = group.dst_addr() else {
+        return;
+    };
+    if let Err(e) = wg_sender.send(wg_socket, packets, dst_addr).await {
+        log::warn!("[{}] Errore inoltrando a Wireguard: {}", group.name, e);
+    }
+}
+
+// Inoltra a Wireguard i pacchetti ricostruiti dalla FEC che non sono già arrivati
+async fn forward_recovered(
+    group: &ClientGroup,
+    wg_socket: &UdpSocket,
+    wg_sender: &Sender,
+    recovered: Vec<(Header, Packet)>,
+) {
+    let framing = match &group.framing {
+        Some(framing) => framing,
+        None => return,
+    };
+    let packets: Vec<Packet> = recovered
+        .into_iter()
+        .filter(|(header, _)| framing.accept(header))
+        .map(|(_, payload)| payload)
+        .collect();
+    write_to_wireguard(group, wg_socket, wg_sender, &packets).await;
+}
+
+async fn probe_clients(group: ClientGroup, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let targets: Vec<ConnectedClient> =
+            group.clients.lock().unwrap().values().cloned().collect();
+        for client in targets {
+            let addr = client.addr;
+            let packet = client.probe.lock().unwrap().next_probe(Instant::now());
+            if let Err(e) = client.link().send_to(&packet, addr).await {
+                log::warn!("[{}] Errore inviando probe a {}: {}", group.name, addr, e);
+            }
+        }
+    }
+}
+
+//
+// dstAddr con un nome
+//
+
+// Senza dnsRefresh si segue il TTL, ma non più spesso di così
+const MIN_DNS_REFRESH: Duration = Duration::from_secs(5);
+// Senza TTL (resolver di sistema)
+const DEFAULT_DNS_REFRESH: Duration = Duration::from_secs(60);
+// Nuovo tentativo dopo una risoluzione fallita
+const DNS_RETRY: Duration = Duration::from_secs(5);
+
+// Risolve il dstAddr del gruppo, della stessa famiglia dell'indirizzo in uso se
+// c'è. Restituisce l'indirizzo e fra quanto risolverlo di nuovo.
+async fn lookup_group_dst(
+    group: &ClientGroup,
+    config: &SharedConfig,
+) -> Option<(SocketAddr, Duration)> {
+    let (host, port) = dns::split_host_port(&group.dst)?;
+    let current = group.dst_addr();
+    for ipv6 in [false, true] {
+        if current.is_some_and(|addr| addr.is_ipv6() != ipv6) {
+            continue;
+        }
+        let Some(answer) = dns::resolve(host, ipv6, None).await else {
+            continue;
+        };
+        // Con più indirizzi si resta su quello in uso, se c'è ancora
+        let addr = current
+            .filter(|addr| answer.addrs.contains(&addr.ip()))
+            .unwrap_or(SocketAddr::new(answer.addrs[0], port));
+        let refresh = match config.lock().unwrap().dns_refresh {
+            Some(secs) => Duration::from_secs(secs),
+            None => answer
+                .ttl
+                .map_or(DEFAULT_DNS_REFRESH, |ttl| ttl.max(MIN_DNS_REFRESH)),
+        };
+        return Some((addr, refresh));
+    }
+    None
+}
 
-    // Socket UDP per Wireguard (bind su "0.0.0.0:0")
+// Risolve di nuovo il dstAddr del gruppo e, se cambia, i pacchetti per
+// Wireguard vanno al nuovo indirizzo
+async fn refresh_group_dst(group: ClientGroup, config: SharedConfig, mut next: Duration) {
+    loop {
+        tokio::time::sleep(next).await;
+        match lookup_group_dst(&group, &config).await {
+            Some((addr, refresh)) => {
+                let mut current = group.dst_addr.lock().unwrap();
+                if *current != Some(addr) {
+                    log::info!("[{}] {} ora risolve in {}", group.name, group.dst, addr);
+                    *current = Some(addr);
+                }
+                next = refresh;
+            }
+            None => {
+                log::warn!("[{}] Impossibile risolvere {}", group.name, group.dst);
+                next = DNS_RETRY;
+            }
+        }
+    }
+}
+
+// Su un indirizzo IPv6 non specificato ([::]:porta) il socket accetta anche IPv4
+fn bind_client_socket(listen_addr: &str) -> std::io::Result<UdpSocket> {
+    let addr: SocketAddr = listen_addr
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn start_group(
+    group: &ClientGroup,
+    config: SharedConfig,
+    probe_interval: Option<Duration>,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    // Socket UDP per i client, uno per indirizzo
+    let client_sockets: Vec<Arc<UdpSocket>> = group
+        .listen_addrs
+        .iter()
+        .map(|addr| {
+            let socket = bind_client_socket(addr).unwrap_or_else(|e| {
+                panic!("Errore bind client socket {} {}: {}", group.name, addr, e)
+            });
+            log::info!("[{}] Listening on {}", group.name, addr);
+            Arc::new(socket)
+        })
+        .collect();
+    if group.framing.is_some() {
+        log::info!("[{}] Framing engarde abilitato", group.name);
+    }
+    if group.fec.is_some() {
+        log::info!("[{}] FEC abilitata", group.name);
+    }
+
+    // Con un nome come dstAddr si aspetta la prima risoluzione: la famiglia
+    // dell'indirizzo serve per il socket di Wireguard
+    let mut dns_refresh = None;
+    let dst_addr = loop {
+        if let Some(addr) = group.dst_addr() {
+            break addr;
+        }
+        match lookup_group_dst(group, &config).await {
+            Some((addr, refresh)) => {
+                log::info!("[{}] {} risolto in {}", group.name, group.dst, addr);
+                *group.dst_addr.lock().unwrap() = Some(addr);
+                dns_refresh = Some(refresh);
+            }
+            None => {
+                log::warn!(
+                    "[{}] Impossibile risolvere {}, riprovo tra {:?}",
+                    group.name,
+                    group.dst,
+                    DNS_RETRY
+                );
+                tokio::time::sleep(DNS_RETRY).await;
+            }
+        }
+    };
+
+    // Socket UDP per Wireguard (bind su "0.0.0.0:0" o "[::]:0"): una porta sorgente
+    // diversa per ogni gruppo, così Wireguard vede ogni gruppo come un endpoint distinto
+    let wg_bind_ip = if dst_addr.is_ipv6() {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    };
     let wg_socket = Arc::new(
-        UdpSocket::bind("0.0.0.0:0")
+        UdpSocket::bind(SocketAddr::new(wg_bind_ip, 0))
             .await
-            .unwrap_or_else(|e| panic!("Errore bind Wireguard socket: {}", e)),
+            .unwrap_or_else(|e| panic!("Errore bind Wireguard socket {}: {}", group.name, e)),
     );
-    let wg_addr: SocketAddr = server.dstAddr.parse().expect("Invalid dstAddr");
 
     // Avvia task: ricezione da Wireguard
-    {
-        let clients = clients.clone();
-        let client_socket = client_socket.clone();
-        let wg_socket = wg_socket.clone();
-        tokio::spawn(async move {
-            receive_from_wireguard(
-                wg_socket,
-                client_socket,
-                wg_addr,
-                clients,
-                client_timeout,
-                write_timeout,
-            )
-            .await;
-        });
+    let from_wg = tokio::spawn(receive_from_wireguard(
+        group.clone(),
+        wg_socket.clone(),
+        config.clone(),
+    ));
+
+    let mut tasks = vec![from_wg];
+    // Avvia task: ricezione dai client e inoltro a Wireguard
+    for client_socket in client_sockets {
+        tasks.push(tokio::spawn(receive_from_clients(
+            group.clone(),
+            wg_socket.clone(),
+            client_socket,
+            config.clone(),
+        )));
+    }
+    if let Some(addr) = &group.tcp_listen_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .unwrap_or_else(|e| panic!("Errore bind TCP {} {}: {}", group.name, addr, e));
+        log::info!("[{}] Listening on {} (TCP)", group.name, addr);
+        tasks.push(tokio::spawn(accept_tcp_clients(
+            group.clone(),
+            wg_socket.clone(),
+            listener,
+            config.clone(),
+        )));
+    }
+    if let Some(interval) = probe_interval {
+        tasks.push(tokio::spawn(probe_clients(group.clone(), interval)));
+    }
+    if let Some(refresh) = dns_refresh {
+        tasks.push(tokio::spawn(refresh_group_dst(
+            group.clone(),
+            config.clone(),
+            refresh,
+        )));
+    }
+    tasks
+}
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    // Legge il file di configurazione (default "engarde.yml")
+    let config_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "engarde.yml".to_string());
+    let server = load_config(&config_path).unwrap_or_else(|e| panic!("{}", e));
+    log::info!("Server: {:?}", server.description);
+    if server.batch_io {
+        if cfg!(target_os = "linux") {
+            log::info!("I/O a blocchi abilitato");
+        } else {
+            log::warn!("batchIo è supportato solo su Linux, uso l'I/O normale");
+        }
     }
 
+    let probe_interval = server
+        .probe_interval
+        .map(|ms| Duration::from_millis(ms.max(10)));
+
+    let groups: ClientGroups = Arc::new(
+        build_client_groups(&server).unwrap_or_else(|e| panic!("Errore nella config: {}", e)),
+    );
+    let config: SharedConfig = Arc::new(Mutex::new(server.clone()));
+
+    // I gruppi partono insieme: uno che aspetta la risoluzione di dstAddr non
+    // blocca gli altri
+    let started = futures::future::join_all(
+        groups
+            .iter()
+            .map(|group| start_group(group, config.clone(), probe_interval)),
+    )
+    .await;
+    let tasks: Vec<_> = started.into_iter().flatten().collect();
+
     // Avvia il webserver se configurato
-    if let Some(web_conf) = server.webManager {
-        let clients_web = clients.clone();
+    if let Some(web_conf) = server.web_manager {
+        let config_web = config.clone();
+        let groups_web = groups.clone();
         tokio::spawn(async move {
-            run_webserver(web_conf, clients_web).await;
+            run_webserver(web_conf.listen_addr, config_web, groups_web).await;
         });
     }
 
-    // Loop principale: ricezione dai client e inoltro a Wireguard
-    let mut buf = vec![0u8; 1500];
-    loop {
-        match client_socket.recv_from(&mut buf).await {
-            Ok((n, src_addr)) => {
-                let key = src_addr.to_string();
-                let now = Instant::now();
-                {
-                    let mut map = clients.lock().unwrap();
-                    map.insert(key.clone(), ConnectedClient { addr: src_addr, last: now });
-                }
-                if let Err(e) = wg_socket.send_to(&buf[..n], &wg_addr).await {
-                    log::warn!("Errore inoltrando a Wireguard: {}", e);
-                }
+    tokio::spawn(reload_on_sighup(config_path, config));
+
+    futures::future::join_all(tasks).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_config(yaml: &str) -> ServerConfig {
+        serde_yaml::from_str::<Config>(yaml).unwrap().server
+    }
+
+    const PSK_CONFIG: &str = r#"
+server:
+  listenAddr: "127.0.0.1:0"
+  dstAddr: "127.0.0.1:51820"
+  psk: "secret"
+"#;
+
+    // Un gruppo con la psk, il socket del server e quello di un client
+    async fn psk_group() -> (ClientGroup, SharedConfig, ClientLink, UdpSocket) {
+        let server = server_config(PSK_CONFIG);
+        let group = build_client_groups(&server).unwrap().remove(0);
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (
+            group,
+            Arc::new(Mutex::new(server)),
+            ClientLink::Udp(Arc::new(socket)),
+            client,
+        )
+    }
+
+    async fn handle(
+        group: &ClientGroup,
+        config: &SharedConfig,
+        link: &ClientLink,
+        client: &UdpSocket,
+        packet: &[u8],
+    ) {
+        let src = client.local_addr().unwrap();
+        let mut out = ToWireguard::default();
+        let packet = Packet::from_vec(packet.to_vec());
+        handle_client_packet(group, config, link, src, packet, false, &mut out).await;
+        assert!(out.packets.is_empty());
+    }
+
+    // Il frame che il client riceve dal server, se arriva
+    async fn reply(client: &UdpSocket) -> Option<(FrameType, Vec<u8>)> {
+        let mut buf = [0u8; 2048];
+        let n = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        let (header, body) = Header::parse(&buf[..n])?;
+        Some((header.kind, body.to_vec()))
+    }
+
+    fn response(psk: &[u8], cookie: &[u8]) -> Vec<u8> {
+        let body = [cookie, &auth::tag(psk, cookie)].concat();
+        auth::frame(FrameType::AuthResponse, &body)
+    }
+
+    fn key(link: &ClientLink, client: &UdpSocket) -> String {
+        client_key(link, client.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn challenge_response_registers_the_path() {
+        let (group, config, link, client) = psk_group().await;
+        let hello = auth::frame(FrameType::AuthHello, &[0; COOKIE_LEN]);
+        handle(&group, &config, &link, &client, &hello).await;
+        let (kind, cookie) = reply(&client).await.unwrap();
+        assert_eq!(kind, FrameType::AuthChallenge);
+        assert!(group.clients.lock().unwrap().is_empty());
+
+        handle(
+            &group,
+            &config,
+            &link,
+            &client,
+            &response(b"secret", &cookie),
+        )
+        .await;
+        assert_eq!(reply(&client).await.unwrap().0, FrameType::AuthOk);
+        assert!(group
+            .clients
+            .lock()
+            .unwrap()
+            .contains_key(&key(&link, &client)));
+        assert_eq!(group.stats.auth_failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn hello_smaller_than_the_challenge_gets_no_answer() {
+        let (group, config, link, client) = psk_group().await;
+        let hello = auth::frame(FrameType::AuthHello, &[0; COOKIE_LEN - 1]);
+        handle(&group, &config, &link, &client, &hello).await;
+        assert!(reply(&client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stale_or_replayed_cookie_is_rejected() {
+        let (group, config, link, client) = psk_group().await;
+        let auth = group.auth.clone().unwrap();
+        let epoch = Verifier::epoch(SystemTime::now());
+        let stale = auth.verifier.cookie(&key(&link, &client), epoch - 2);
+        handle(
+            &group,
+            &config,
+            &link,
+            &client,
+            &response(b"secret", &stale),
+        )
+        .await;
+        assert!(reply(&client).await.is_none());
+
+        // Risposta valida ripetuta da un altro indirizzo
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cookie = auth.verifier.cookie(&key(&link, &other), epoch);
+        handle(
+            &group,
+            &config,
+            &link,
+            &client,
+            &response(b"secret", &cookie),
+        )
+        .await;
+        assert!(reply(&client).await.is_none());
+
+        assert!(group.clients.lock().unwrap().is_empty());
+        assert_eq!(group.stats.auth_failures.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn wrong_psk_is_rejected() {
+        let (group, config, link, client) = psk_group().await;
+        let auth = group.auth.clone().unwrap();
+        let epoch = Verifier::epoch(SystemTime::now());
+        let cookie = auth.verifier.cookie(&key(&link, &client), epoch);
+        handle(
+            &group,
+            &config,
+            &link,
+            &client,
+            &response(b"other", &cookie),
+        )
+        .await;
+        assert!(reply(&client).await.is_none());
+        assert!(group.clients.lock().unwrap().is_empty());
+        assert_eq!(group.stats.auth_failures.load(Ordering::Relaxed), 1);
+
+        // I dati di un mittente non autenticato vengono scartati
+        handle(&group, &config, &link, &client, b"wireguard").await;
+        assert_eq!(group.stats.unauthenticated.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn mtu_probe_needs_a_valid_signature() {
+        let (group, config, link, client) = psk_group().await;
+        let header = Header {
+            kind: FrameType::MtuProbe,
+            session: 0,
+            seq: 1,
+        };
+        let mut probe = vec![0u8; 600];
+        header.write(&mut probe);
+        handle(&group, &config, &link, &client, &probe).await;
+        assert!(reply(&client).await.is_none());
+
+        probe[HEADER_LEN..HEADER_LEN + auth::TAG_LEN]
+            .copy_from_slice(&auth::probe_tag(b"secret", &header));
+        handle(&group, &config, &link, &client, &probe).await;
+        let (kind, body) = reply(&client).await.unwrap();
+        assert_eq!(kind, FrameType::MtuProbeReply);
+        assert_eq!(u16::from_be_bytes([body[0], body[1]]), 600);
+
+        // Più corto della risposta: mai una risposta più grande del probe
+        handle(&group, &config, &link, &client, &probe[..MTU_REPLY_LEN - 1]).await;
+        assert!(reply(&client).await.is_none());
+        assert!(group.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tcp_slots_are_limited_per_source_and_in_total() {
+        let server = server_config(PSK_CONFIG);
+        let group = build_client_groups(&server).unwrap().remove(0);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let first = group.tcp_slot(a, 2, 3).unwrap();
+        let _second = group.tcp_slot(a, 2, 3).unwrap();
+        assert!(group.tcp_slot(a, 2, 3).is_none());
+        let _third = group.tcp_slot(b, 2, 3).unwrap();
+        assert!(group.tcp_slot(b, 2, 3).is_none());
+        // Una connessione chiusa libera il suo posto
+        drop(first);
+        let _again = group.tcp_slot(a, 2, 3).unwrap();
+        assert_eq!(group.tcp_conns.lock().unwrap().total, 3);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_are_closed() {
+        use tokio::io::AsyncReadExt;
+        let server = server_config(
+            r#"
+server:
+  listenAddr: "127.0.0.1:0"
+  dstAddr: "127.0.0.1:51820"
+  tcpMaxPerSource: 2
+"#,
+        );
+        let group = build_client_groups(&server).unwrap().remove(0);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let wg_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = Arc::new(Mutex::new(server));
+        tokio::spawn(accept_tcp_clients(
+            group.clone(),
+            wg_socket,
+            listener,
+            config,
+        ));
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            streams.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let mut buf = [0u8; 1];
+        let closed = tokio::time::timeout(Duration::from_secs(1), streams[2].read(&mut buf)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+        for stream in &mut streams[..2] {
+            let open =
+                tokio::time::timeout(Duration::from_millis(100), stream.read(&mut buf)).await;
+            assert!(open.is_err());
+        }
+        assert_eq!(group.stats.tcp_rejected.load(Ordering::Relaxed), 1);
+        assert_eq!(group.tcp_conns.lock().unwrap().total, 2);
+    }
+
+    // Un path che ha ricevuto l'ultimo pacchetto e l'ultima risposta a now
+    async fn connected_client(now: Instant) -> ConnectedClient {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        ConnectedClient {
+            addr: socket.local_addr().unwrap(),
+            last: now,
+            last_data: now,
+            last_reply: now,
+            frames: 0,
+            loss_check: (0, 0),
+            probe: Arc::new(Mutex::new(ProbeStats::new())),
+            counters: Arc::new(PathCounters::default()),
+            queue: Arc::new(SendQueue::new(DEFAULT_QUEUE_SIZE)),
+            send_failures: Arc::new(AtomicU32::new(0)),
+            link: Arc::new(Mutex::new(ClientLink::Udp(Arc::new(socket)))),
+        }
+    }
+
+    #[tokio::test]
+    async fn health_follows_probe_replies_when_probing() {
+        let start = Instant::now();
+        let health_timeout = Duration::from_millis(1000);
+        let interval = Some(Duration::from_millis(500));
+        let mut client = connected_client(start).await;
+        // Il path riceve traffico ma i probe non hanno più risposta
+        client.last = start + Duration::from_secs(5);
+        let now = start + Duration::from_secs(5);
+        assert!(client.is_healthy(now, health_timeout, None));
+        assert!(!client.is_healthy(now, health_timeout, interval));
+
+        // Senza traffico basta una risposta recente; la finestra è di almeno
+        // PROBE_HEALTH_INTERVALS intervalli
+        client.last = start;
+        client.last_reply = now - Duration::from_millis(1400);
+        assert!(!client.is_healthy(now, health_timeout, None));
+        assert!(client.is_healthy(now, health_timeout, interval));
+        client.last_reply = now - Duration::from_millis(1500);
+        assert!(!client.is_healthy(now, health_timeout, interval));
+    }
+
+    fn os_error(code: i32) -> std::io::Error {
+        std::io::Error::from_raw_os_error(code)
+    }
+
+    #[test]
+    fn send_errors_are_classified() {
+        for code in [
+            libc::ENETUNREACH,
+            libc::EHOSTUNREACH,
+            libc::ENETDOWN,
+            libc::EADDRNOTAVAIL,
+            libc::ECONNREFUSED,
+        ] {
+            assert_eq!(
+                classify_send_error(&os_error(code)),
+                SendFailure::Unreachable
+            );
+        }
+        for code in [libc::ENOBUFS, libc::EAGAIN, libc::EPERM, libc::EACCES] {
+            assert_eq!(classify_send_error(&os_error(code)), SendFailure::Transient);
+        }
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout");
+        assert_eq!(classify_send_error(&timeout), SendFailure::Transient);
+    }
+
+    #[test]
+    fn client_is_evicted_after_send_failures_errors_of_a_class() {
+        let failures = SendFailureConfig {
+            transient: Some(3),
+            unreachable: Some(2),
+            max_backoff: Some(3),
+        };
+        let mut errors = SendErrors::new();
+        let retry = |count, limit, ms| AfterFailure::Retry {
+            count,
+            limit,
+            backoff: Duration::from_millis(ms),
+        };
+        assert_eq!(
+            errors.failed(SendFailure::Transient, &failures),
+            retry(1, 3, 1)
+        );
+        assert_eq!(
+            errors.failed(SendFailure::Unreachable, &failures),
+            retry(1, 2, 2)
+        );
+        // Il backoff raddoppia fino a maxBackoff
+        assert_eq!(
+            errors.failed(SendFailure::Transient, &failures),
+            retry(2, 3, 3)
+        );
+        assert_eq!(errors.total(), 3);
+        assert_eq!(
+            errors.failed(SendFailure::Unreachable, &failures),
+            AfterFailure::Evict {
+                count: 2,
+                reason: EVICT_UNREACHABLE
             }
-            Err(e) => {
-                log::warn!("Errore in recv_from client: {}", e);
+        );
+    }
+
+    #[test]
+    fn a_successful_send_resets_the_errors() {
+        let failures = SendFailureConfig {
+            transient: Some(2),
+            ..Default::default()
+        };
+        let mut errors = SendErrors::new();
+        assert!(!errors.succeeded());
+        errors.failed(SendFailure::Transient, &failures);
+        errors.failed(SendFailure::Unreachable, &failures);
+        assert!(errors.succeeded());
+        assert_eq!(errors.total(), 0);
+        // Dopo l'azzeramento serve di nuovo la soglia intera, con il backoff minimo
+        assert_eq!(
+            errors.failed(SendFailure::Transient, &failures),
+            AfterFailure::Retry {
+                count: 1,
+                limit: 2,
+                backoff: MIN_BACKOFF
             }
+        );
+        assert_eq!(
+            errors.failed(SendFailure::Transient, &failures),
+            AfterFailure::Evict {
+                count: 2,
+                reason: EVICT_TRANSIENT
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn path_is_dead_after_prune_timeout() {
+        let start = Instant::now();
+        let prune_timeout = Duration::from_millis(2000);
+        let client = connected_client(start).await;
+        let at = |ms| start + Duration::from_millis(ms);
+        assert!(!client.is_dead(at(2000), prune_timeout, None));
+        assert!(client.is_dead(at(2001), prune_timeout, None));
+    }
+
+    #[tokio::test]
+    async fn path_without_probe_replies_is_dead() {
+        let start = Instant::now();
+        let prune_timeout = Duration::from_millis(2000);
+        let interval = Some(Duration::from_millis(500));
+        let mut client = connected_client(start).await;
+        let at = |ms| start + Duration::from_millis(ms);
+        // Riceve ancora ma i probe non hanno risposta: si aspetta un intervallo in più
+        client.last = at(2500);
+        assert!(!client.is_dead(at(2500), prune_timeout, interval));
+        assert!(client.is_dead(at(2501), prune_timeout, interval));
+        assert!(!client.is_dead(at(2501), prune_timeout, None));
+    }
+
+    #[tokio::test]
+    async fn without_prune_timeout_paths_are_never_suspended() {
+        let now = Instant::now();
+        let old = now - Duration::from_secs(3600);
+        let clients = vec![("path".to_string(), connected_client(old).await)];
+        let interval = Some(Duration::from_millis(500));
+        assert!(dead_paths(&clients, now, None).is_empty());
+        let prune = Some((Duration::from_secs(60), interval));
+        assert!(dead_paths(&clients, now, prune).contains("path"));
+    }
+
+    fn build_error(yaml: &str) -> String {
+        match build_client_groups(&server_config(yaml)) {
+            Ok(_) => panic!("configurazione accettata"),
+            Err(e) => e,
         }
     }
+
+    #[test]
+    fn duplicate_group_names_are_rejected() {
+        let e = build_error(
+            r#"
+server:
+  listenAddr: "127.0.0.1:0"
+  dstAddr: "127.0.0.1:51820"
+  groups:
+    - name: "default"
+      listenAddr: "127.0.0.1:59411"
+"#,
+        );
+        assert_eq!(e, "gruppo default duplicato");
+    }
+
+    #[test]
+    fn listen_addresses_are_used_once() {
+        let e = build_error(
+            r#"
+server:
+  listenAddr: "127.0.0.1:59410"
+  dstAddr: "127.0.0.1:51820"
+  groups:
+    - name: "site-b"
+      listenAddr: "127.0.0.1:59411"
+      listenAddrs: ["127.0.0.1:59410"]
+"#,
+        );
+        assert_eq!(e, "listenAddr 127.0.0.1:59410 usato più volte");
+        let e = build_error(
+            r#"
+server:
+  listenAddr: "127.0.0.1:59410"
+  listenAddrs: ["127.0.0.1:59410"]
+  dstAddr: "127.0.0.1:51820"
+"#,
+        );
+        assert_eq!(e, "listenAddr 127.0.0.1:59410 usato più volte");
+        let e = build_error(
+            r#"
+server:
+  listenAddr: "127.0.0.1:59410"
+  tcpListenAddr: "127.0.0.1:59412"
+  dstAddr: "127.0.0.1:51820"
+  groups:
+    - name: "site-b"
+      listenAddr: "127.0.0.1:59411"
+      tcpListenAddr: "127.0.0.1:59412"
+"#,
+        );
+        assert_eq!(e, "tcpListenAddr 127.0.0.1:59412 usato più volte");
+    }
+
+    #[test]
+    fn groups_inherit_the_top_level_psk_and_fec() {
+        let server = server_config(
+            r#"
+server:
+  listenAddr: "127.0.0.1:59410"
+  dstAddr: "127.0.0.1:51820"
+  framing: true
+  mode: balance
+  psk: "top"
+  fec:
+    dataShards: 4
+    parityShards: 1
+  groups:
+    - name: "inherits"
+      listenAddr: "127.0.0.1:59411"
+    - name: "overrides"
+      listenAddr: "127.0.0.1:59412"
+      psk: "own"
+      framing: false
+"#,
+        );
+        let e = build_client_groups(&server).err().unwrap();
+        assert_eq!(e, "la fec del gruppo overrides richiede il framing");
+
+        let mut server = server;
+        server.groups[1].framing = None;
+        server.groups[1].fec = Some(FecConfig {
+            data_shards: 0,
+            parity_shards: 1,
+        });
+        let e = build_client_groups(&server).err().unwrap();
+        assert!(e.starts_with("fec del gruppo overrides non valida"));
+
+        server.groups[1].fec = None;
+        let groups = build_client_groups(&server).unwrap();
+        assert!(groups.iter().all(|g| g.fec.is_some()));
+        let epoch = Verifier::epoch(SystemTime::now());
+        let accepts = |group: &ClientGroup, psk: &[u8]| {
+            let verifier = &group.auth.as_ref().unwrap().verifier;
+            let cookie = verifier.cookie("path", epoch);
+            let body = [&cookie[..], &auth::tag(psk, &cookie)].concat();
+            verifier.verify("path", &body, epoch)
+        };
+        assert!(accepts(&groups[0], b"top") && accepts(&groups[1], b"top"));
+        assert!(accepts(&groups[2], b"own") && !accepts(&groups[2], b"top"));
+    }
+
+    #[tokio::test]
+    async fn get_list_shows_the_configured_server() {
+        use warp::Reply;
+        let server = server_config(
+            r#"
+server:
+  description: "vps"
+  listenAddr: "127.0.0.1:59410"
+  dstAddr: "127.0.0.1:51820"
+"#,
+        );
+        let groups = Arc::new(build_client_groups(&server).unwrap());
+        let config = Arc::new(Mutex::new(server));
+        let reply = handle_get_list(groups, config).await.unwrap();
+        let body = warp::hyper::body::to_bytes(reply.into_response().into_body())
+            .await
+            .unwrap();
+        let list: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(list["description"], "vps");
+        assert_eq!(list["listenAddress"], "127.0.0.1:59410");
+        assert_eq!(list["dstAddress"], "127.0.0.1:51820");
+    }
+
+    #[test]
+    fn mode_is_applied_on_reload() {
+        let config = |mode| {
+            server_config(&format!(
+                "server:\n  listenAddr: \"127.0.0.1:59410\"\n  dstAddr: \"127.0.0.1:51820\"\n  mode: {}\n",
+                mode
+            ))
+        };
+        let (merged, applied, restart) = merge_reload(&config("balance"), config("failover"));
+        assert_eq!(merged.mode, Mode::Failover);
+        assert_eq!(applied, ["mode"]);
+        assert!(restart.is_empty());
+    }
 }